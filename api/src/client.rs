@@ -0,0 +1,757 @@
+use crate::server::{
+    ApiPaths, DiscoveryDocument, ErrorCode, ErrorResponse, FinishLoginRequest, FinishLoginResponse,
+    LogoutRequest, LogoutResponse, RefreshSessionRequest, RefreshSessionResponse,
+    StartLoginRequest, StartLoginResponse, DISCOVERY_PATH, PROTOCOL_HEADER, PROTOCOL_VERSION,
+    SIGNATURE_HEADER,
+};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use url::Url;
+
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The server responded with a structured error
+    #[error("{message}")]
+    Server {
+        status: u16,
+        code: ErrorCode,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Incompatible server: {0}")]
+    Incompatible(String),
+    /// The server did not prove it holds a pinned key
+    #[error("Server key mismatch: {0}")]
+    KeyMismatch(String),
+    /// A key given to the client builder is malformed
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+}
+
+impl ClientError {
+    /// The error code returned by the server, if the server responded with one
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Server { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request might succeed when sent again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Server { code, .. } => code.is_retryable(),
+            Self::Http(err) => err.is_connect() || err.is_timeout(),
+            Self::Url(_)
+            | Self::Json(_)
+            | Self::Incompatible(_)
+            | Self::KeyMismatch(_)
+            | Self::InvalidKey(_) => false,
+        }
+    }
+}
+
+/// How idempotent requests failing with a retryable error are retried, the delay
+/// is doubled after every retry and never waits longer than `max_delay`.
+/// Requests creating or refreshing a session are never retried, since the server
+/// may have handled them before failing.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+pub struct ClientBuilder {
+    base_url: Url,
+    user_agent: String,
+    root_certificates: Vec<reqwest::Certificate>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    pinned_keys: PinnedKeys,
+}
+
+/// Keys the server is expected to have, checked on every response carrying a server configuration
+#[derive(Debug, Clone, Default)]
+struct PinnedKeys {
+    /// Base64 WireGuard public key of the server
+    server_public_key: Option<String>,
+    /// Base64 Ed25519 public key responses are signed with
+    signing_key: Option<Vec<u8>>,
+}
+
+impl ClientBuilder {
+    /// Refuses server configurations with a WireGuard public key other than `public_key`
+    pub fn pin_server_public_key(mut self, public_key: impl Into<String>) -> Self {
+        self.pinned_keys.server_public_key = Some(public_key.into());
+        self
+    }
+
+    /// Refuses server configurations not signed with the base64 Ed25519 `public_key`
+    pub fn pin_signing_key(mut self, public_key: &str) -> Result<Self, ClientError> {
+        let public_key = base64::decode(public_key).map_err(|err| {
+            ClientError::InvalidKey(format!("Pinned signing key is not base64: {}", err))
+        })?;
+        if public_key.len() != ED25519_PUBLIC_KEY_LEN {
+            return Err(ClientError::InvalidKey(format!(
+                "Pinned signing key is {} bytes instead of {}",
+                public_key.len(),
+                ED25519_PUBLIC_KEY_LEN
+            )));
+        }
+        self.pinned_keys.signing_key = Some(public_key);
+        Ok(self)
+    }
+
+    /// Trusts the CA certificate in `pem` in addition to the system's root certificates
+    pub fn add_root_certificate_pem(mut self, pem: &[u8]) -> Result<Self, ClientError> {
+        self.root_certificates
+            .push(reqwest::Certificate::from_pem(pem)?);
+        Ok(self)
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Timeout for each request, from connecting until the whole response is read
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, ClientError> {
+        let mut http = reqwest::ClientBuilder::new().user_agent(self.user_agent);
+        for certificate in self.root_certificates {
+            http = http.add_root_certificate(certificate);
+        }
+        if let Some(timeout) = self.timeout {
+            http = http.timeout(timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            http = http.connect_timeout(connect_timeout);
+        }
+        Ok(Client {
+            base_url: self.base_url,
+            http: http.build()?,
+            retry_policy: self.retry_policy,
+            paths: Default::default(),
+            protocol_version: PROTOCOL_VERSION,
+            pinned_keys: self.pinned_keys,
+        })
+    }
+}
+
+/// Client for the REST API of a cablescout server
+#[derive(Clone)]
+pub struct Client {
+    base_url: Url,
+    http: reqwest::Client,
+    retry_policy: RetryPolicy,
+    paths: ApiPaths,
+    /// Protocol version requests are sent with, the newest version both sides speak
+    protocol_version: u32,
+    pinned_keys: PinnedKeys,
+}
+
+impl Client {
+    pub fn builder(base_url: Url) -> ClientBuilder {
+        ClientBuilder {
+            base_url,
+            user_agent: USER_AGENT.to_owned(),
+            root_certificates: vec![],
+            timeout: None,
+            connect_timeout: None,
+            retry_policy: Default::default(),
+            pinned_keys: Default::default(),
+        }
+    }
+
+    pub fn new(base_url: Url) -> Result<Self, ClientError> {
+        Self::builder(base_url).build()
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Fetches the discovery document of the server, `None` for servers too old to publish one
+    pub async fn discover(&self) -> Result<Option<DiscoveryDocument>, ClientError> {
+        self.with_retries(|| self.discover_once()).await
+    }
+
+    async fn discover_once(&self) -> Result<Option<DiscoveryDocument>, ClientError> {
+        let res = self
+            .http
+            .get(self.base_url.join(DISCOVERY_PATH)?)
+            .send()
+            .await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(res.error_for_status()?.json().await?))
+    }
+
+    /// Checks that the server is compatible, and uses the API paths and protocol version it publishes
+    pub fn use_discovery(&mut self, discovery: &DiscoveryDocument) -> Result<(), ClientError> {
+        discovery
+            .check_compatible()
+            .map_err(ClientError::Incompatible)?;
+        if let Some(signing_key) = self.pinned_keys.signing_key.as_ref() {
+            let published = discovery
+                .signing_public_key
+                .as_ref()
+                .and_then(|key| base64::decode(key).ok());
+            if published.as_ref() != Some(signing_key) {
+                return Err(ClientError::KeyMismatch(
+                    "Server publishes a signing key other than the pinned one".to_owned(),
+                ));
+            }
+        }
+        self.paths = discovery.paths.clone();
+        self.protocol_version = discovery.protocol_version.min(PROTOCOL_VERSION);
+        Ok(())
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// The page the OIDC server redirects users to after they login
+    pub fn finish_page_url(&self) -> Result<Url, ClientError> {
+        Ok(self.base_url.join(&self.paths.finish_page)?)
+    }
+
+    pub async fn start_login(
+        &self,
+        req: &StartLoginRequest,
+    ) -> Result<StartLoginResponse, ClientError> {
+        self.with_retries(|| self.post(&self.paths.start_login, req, false))
+            .await
+    }
+
+    pub async fn finish_login(
+        &self,
+        req: &FinishLoginRequest,
+    ) -> Result<FinishLoginResponse, ClientError> {
+        let res: FinishLoginResponse = self.post(&self.paths.finish_login, req, true).await?;
+        self.check_server_public_key(&res.peer.public_key)?;
+        Ok(res)
+    }
+
+    pub async fn refresh_session(
+        &self,
+        req: &RefreshSessionRequest,
+    ) -> Result<RefreshSessionResponse, ClientError> {
+        let res: RefreshSessionResponse = self.post(&self.paths.refresh_session, req, true).await?;
+        self.check_server_public_key(&res.peer.public_key)?;
+        Ok(res)
+    }
+
+    fn check_server_public_key(&self, public_key: &str) -> Result<(), ClientError> {
+        match self.pinned_keys.server_public_key.as_ref() {
+            Some(pinned) if pinned != public_key => Err(ClientError::KeyMismatch(format!(
+                "Server WireGuard public key is {}, expected {}",
+                public_key, pinned
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `body` was signed with the pinned signing key, if there is one
+    fn verify_signature(&self, body: &[u8], signature: Option<&str>) -> Result<(), ClientError> {
+        let signing_key = match self.pinned_keys.signing_key.as_ref() {
+            None => return Ok(()),
+            Some(signing_key) => signing_key,
+        };
+        let signature = signature
+            .and_then(|signature| base64::decode(signature).ok())
+            .ok_or_else(|| ClientError::KeyMismatch("Response is not signed".to_owned()))?;
+        UnparsedPublicKey::new(&ED25519, signing_key)
+            .verify(body, &signature)
+            .map_err(|_| {
+                ClientError::KeyMismatch(
+                    "Response signature does not match the pinned signing key".to_owned(),
+                )
+            })
+    }
+
+    pub async fn logout(&self, req: &LogoutRequest) -> Result<LogoutResponse, ClientError> {
+        self.with_retries(|| self.post(&self.paths.logout, req, false))
+            .await
+    }
+
+    /// Whether the server is alive
+    pub async fn is_healthy(&self) -> Result<bool, ClientError> {
+        self.probe("/healthz").await
+    }
+
+    /// Whether the server is ready to accept logins
+    pub async fn is_ready(&self) -> Result<bool, ClientError> {
+        self.probe("/readyz").await
+    }
+
+    async fn probe(&self, path: &str) -> Result<bool, ClientError> {
+        let res = self.http.get(self.base_url.join(path)?).send().await?;
+        Ok(res.status().is_success())
+    }
+
+    /// Calls `send` until it succeeds, fails with an error that isn't retryable or
+    /// the retry policy gives up. Only use for requests that are safe to send twice.
+    async fn with_retries<T, F, Fut>(&self, mut send: F) -> Result<T, ClientError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut delay = self.retry_policy.initial_delay;
+        let mut attempt = 0;
+        loop {
+            match send().await {
+                Err(err) if err.is_retryable() && attempt < self.retry_policy.max_retries => {
+                    let retry_after = match &err {
+                        ClientError::Server {
+                            retry_after: Some(retry_after),
+                            ..
+                        } => delay.max(*retry_after),
+                        _ => delay,
+                    };
+                    tokio::time::sleep(retry_after.min(self.retry_policy.max_delay)).await;
+                    delay = (delay * 2).min(self.retry_policy.max_delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Posts `req` to `path` once, verifying the response signature when `signed`
+    async fn post<Req, Res>(&self, path: &str, req: &Req, signed: bool) -> Result<Res, ClientError>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let url = self.base_url.join(path)?;
+        let res = self
+            .http
+            .post(url)
+            .header(PROTOCOL_HEADER, self.protocol_version)
+            .json(req)
+            .send()
+            .await?;
+        let server_version: Option<u32> = res
+            .headers()
+            .get(PROTOCOL_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        if res.status().is_success() {
+            let signature = res
+                .headers()
+                .get(SIGNATURE_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+            let body = res.bytes().await?;
+            if signed {
+                self.verify_signature(&body, signature.as_deref())?;
+            }
+            return match serde_json::from_slice(&body) {
+                Ok(body) => Ok(body),
+                // A response that can't be parsed most likely means the server changed the protocol
+                Err(err) => match server_version {
+                    Some(version) if version != self.protocol_version => {
+                        Err(ClientError::Incompatible(format!(
+                            "Server responded with protocol version {} instead of {}, please upgrade",
+                            version, self.protocol_version
+                        )))
+                    }
+                    _ => Err(err.into()),
+                },
+            };
+        }
+
+        let status = res.status().as_u16();
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs);
+        let status_err = res.error_for_status_ref().err();
+        match res.json::<ErrorResponse>().await {
+            Ok(err) => Err(ClientError::Server {
+                status,
+                code: err.code,
+                message: err.message,
+                retry_after,
+            }),
+            Err(_) => Err(status_err.expect("Response status is an error").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{LoginFlow, MIN_PROTOCOL_VERSION};
+    use chrono::Utc;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+    use wg_utils::WireguardPeer;
+
+    /// Serves `responses` in order, one per connection, returning the server URL
+    /// and the request lines it received
+    async fn stub_server(responses: Vec<String>) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                // Requests are small, read until the end of the headers and the body
+                while !is_complete_request(&request) {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let request_line = request.lines().next().unwrap_or_default();
+                received.lock().unwrap().push(request_line.to_owned());
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn is_complete_request(request: &[u8]) -> bool {
+        let request = String::from_utf8_lossy(request);
+        let (headers, body) = match request.split_once("\r\n\r\n") {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let content_length = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .unwrap_or(0);
+        body.len() >= content_length
+    }
+
+    fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        response
+    }
+
+    fn error_response(status: &str, headers: &[(&str, &str)], code: ErrorCode) -> String {
+        let body = serde_json::to_string(&ErrorResponse {
+            code,
+            message: "Stub error".to_owned(),
+        })
+        .unwrap();
+        response(status, headers, &body)
+    }
+
+    fn client(url: Url) -> Client {
+        Client::builder(url)
+            .retry_policy(RetryPolicy {
+                max_retries: 2,
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_secs(5),
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn start_login_request() -> StartLoginRequest {
+        StartLoginRequest {
+            device_id: Uuid::nil(),
+            client_public_key: "key".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_body() {
+        let (url, requests) = stub_server(vec![error_response(
+            "403 Forbidden",
+            &[],
+            ErrorCode::DomainNotAllowed,
+        )])
+        .await;
+        let err = client(url)
+            .start_login(&start_login_request())
+            .await
+            .unwrap_err();
+        match err {
+            ClientError::Server {
+                status,
+                code,
+                ref message,
+                retry_after,
+            } => {
+                assert_eq!(status, 403);
+                assert_eq!(code, ErrorCode::DomainNotAllowed);
+                assert_eq!(message, "Stub error");
+                assert_eq!(retry_after, None);
+            }
+            err => panic!("Unexpected error: {:?}", err),
+        }
+        assert!(!err.is_retryable());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let (url, requests) = stub_server(vec![
+            error_response(
+                "429 Too Many Requests",
+                &[("Retry-After", "1")],
+                ErrorCode::RateLimited,
+            ),
+            response(
+                "200 OK",
+                &[],
+                r#"{"auth_url": "https://idp.example.com/auth", "login_token": "token"}"#,
+            ),
+        ])
+        .await;
+        let started = Instant::now();
+        let res = client(url)
+            .start_login(&start_login_request())
+            .await
+            .unwrap();
+        assert_eq!(res.login_token, "token");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_retries_exhausted() {
+        let (url, requests) = stub_server(vec![
+            error_response(
+                "503 Service Unavailable",
+                &[],
+                ErrorCode::IdpUnreachable
+            );
+            3
+        ])
+        .await;
+        let err = client(url)
+            .start_login(&start_login_request())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::IdpUnreachable));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_finish_login_not_retried() {
+        let (url, requests) = stub_server(vec![
+            error_response("503 Service Unavailable", &[], ErrorCode::IdpUnreachable),
+            error_response("503 Service Unavailable", &[], ErrorCode::IdpUnreachable),
+        ])
+        .await;
+        let err = client(url)
+            .finish_login(&FinishLoginRequest {
+                login_token: "token".to_owned(),
+                auth_code: "code".to_owned(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::IdpUnreachable));
+        assert!(err.is_retryable());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    fn finish_login_request() -> FinishLoginRequest {
+        FinishLoginRequest {
+            login_token: "token".to_owned(),
+            auth_code: "code".to_owned(),
+        }
+    }
+
+    fn finish_login_body() -> String {
+        serde_json::to_string(&FinishLoginResponse {
+            session_token: "session".to_owned(),
+            session_ends_at: Utc::now(),
+            interface: Default::default(),
+            peer: WireguardPeer {
+                public_key: "server".to_owned(),
+                preshared_key: None,
+                allowed_ips: vec!["10.0.0.1/32".parse().unwrap()],
+                endpoint: None,
+                persistent_keepalive: None,
+            },
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_signature() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let body = finish_login_body();
+        let signature = base64::encode(key_pair.sign(body.as_bytes()).as_ref());
+        let tampered = body.replace("session", "stolen");
+        let (url, _) = stub_server(vec![
+            response("200 OK", &[(SIGNATURE_HEADER, &signature)], &body),
+            response("200 OK", &[(SIGNATURE_HEADER, &signature)], &tampered),
+            response("200 OK", &[], &body),
+        ])
+        .await;
+        let client = Client::builder(url)
+            .pin_signing_key(&base64::encode(key_pair.public_key().as_ref()))
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let res = client.finish_login(&finish_login_request()).await.unwrap();
+        assert_eq!(res.session_token, "session");
+        assert!(matches!(
+            client.finish_login(&finish_login_request()).await,
+            Err(ClientError::KeyMismatch(_))
+        ));
+        assert!(matches!(
+            client.finish_login(&finish_login_request()).await,
+            Err(ClientError::KeyMismatch(_))
+        ));
+    }
+
+    fn discovery(protocol_version: u32) -> DiscoveryDocument {
+        DiscoveryDocument {
+            protocol_version,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            paths: ApiPaths {
+                start_login: "/v2/login/start".to_owned(),
+                finish_login: "/v2/login/finish".to_owned(),
+                refresh_session: "/v2/session/refresh".to_owned(),
+                logout: "/v2/logout".to_owned(),
+                finish_page: "/v2/finish".to_owned(),
+            },
+            login_flows: vec![LoginFlow::BrowserRedirect],
+            capabilities: vec![],
+            display_name: None,
+            icon_url: None,
+            wg_public_key_fingerprint: "SHA256:".to_owned(),
+            signing_public_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_paths() {
+        let start_login_body =
+            r#"{"auth_url": "https://idp.example.com/auth", "login_token": "token"}"#;
+        let (url, requests) = stub_server(vec![
+            response("200 OK", &[], start_login_body),
+            response("200 OK", &[], start_login_body),
+        ])
+        .await;
+        let mut client = client(url.clone());
+        assert_eq!(
+            client.finish_page_url().unwrap(),
+            url.join("/finish").unwrap()
+        );
+        client.start_login(&start_login_request()).await.unwrap();
+
+        client
+            .use_discovery(&discovery(PROTOCOL_VERSION + 1))
+            .unwrap();
+        assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+        assert_eq!(
+            client.finish_page_url().unwrap(),
+            url.join("/v2/finish").unwrap()
+        );
+        client.start_login(&start_login_request()).await.unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "POST /api/v1/login/start HTTP/1.1",
+                "POST /v2/login/start HTTP/1.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incompatible_discovery_ignored() {
+        let mut client = client(Url::parse("https://vpn.example.com").unwrap());
+        let mut incompatible = discovery(PROTOCOL_VERSION);
+        incompatible.min_protocol_version = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            client.use_discovery(&incompatible),
+            Err(ClientError::Incompatible(_))
+        ));
+        assert_eq!(client.paths, ApiPaths::default());
+    }
+
+    #[test]
+    fn test_pin_invalid_signing_key() {
+        let builder = || Client::builder(Url::parse("https://vpn.example.com").unwrap());
+        assert!(matches!(
+            builder().pin_signing_key("not base64!"),
+            Err(ClientError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            builder().pin_signing_key(&base64::encode([1; 16])),
+            Err(ClientError::InvalidKey(_))
+        ));
+        assert!(builder().pin_signing_key(&base64::encode([1; 32])).is_ok());
+    }
+}
@@ -1,2 +1,4 @@
+#[cfg(feature = "client")]
+mod client;
 pub mod daemon;
 pub mod server;
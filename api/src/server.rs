@@ -4,6 +4,153 @@ use url::Url;
 use uuid::Uuid;
 use wg_utils::{WireguardInterface, WireguardPeer};
 
+#[cfg(feature = "client")]
+pub use crate::client::{Client, ClientBuilder, ClientError, RetryPolicy};
+
+/// Version of the protocol between clients and the server, bumped on incompatible changes.
+/// New fields and enum variants are added without bumping it, since both sides ignore
+/// unknown fields and map unknown variants to an `Unknown` variant.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version the server still accepts requests with
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Header carrying the protocol version of a request or response,
+/// requests without it are treated as version 1
+pub const PROTOCOL_HEADER: &str = "X-Cablescout-Protocol";
+
+/// Header carrying the base64 Ed25519 signature of the body of responses with a server
+/// WireGuard configuration, made with the key published as `signing_public_key`
+pub const SIGNATURE_HEADER: &str = "X-Cablescout-Response-Signature";
+
+/// Where the server publishes its `DiscoveryDocument`
+pub const DISCOVERY_PATH: &str = "/.well-known/cablescout";
+
+/// Why a peer can't talk to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolMismatch {
+    /// The newest version the peer speaks is older than `MIN_PROTOCOL_VERSION`
+    PeerTooOld(u32),
+    /// The oldest version the peer accepts is newer than `PROTOCOL_VERSION`
+    PeerTooNew(u32),
+}
+
+/// Checks whether a peer speaking protocol versions `min_version..=max_version` can talk to us
+pub fn check_protocol_version(min_version: u32, max_version: u32) -> Result<(), ProtocolMismatch> {
+    if max_version < MIN_PROTOCOL_VERSION {
+        return Err(ProtocolMismatch::PeerTooOld(max_version));
+    }
+    if min_version > PROTOCOL_VERSION {
+        return Err(ProtocolMismatch::PeerTooNew(min_version));
+    }
+    Ok(())
+}
+
+/// Paths of the API endpoints, relative to the server URL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiPaths {
+    pub start_login: String,
+    pub finish_login: String,
+    pub refresh_session: String,
+    pub logout: String,
+    /// Page the OIDC server redirects users to after they login
+    pub finish_page: String,
+}
+
+impl Default for ApiPaths {
+    fn default() -> Self {
+        Self {
+            start_login: "/api/v1/login/start".to_owned(),
+            finish_login: "/api/v1/login/finish".to_owned(),
+            refresh_session: "/api/v1/session/refresh".to_owned(),
+            logout: "/api/v1/logout".to_owned(),
+            finish_page: "/finish".to_owned(),
+        }
+    }
+}
+
+/// Optional features of a server, clients check for these before using them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Sessions can be extended with the refresh session endpoint
+    SessionRefresh,
+    /// Sessions can be ended with the logout endpoint
+    Logout,
+    /// Errors are returned as an `ErrorResponse` with an `ErrorCode`
+    ErrorCodes,
+    /// A capability added in a newer server version
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginFlow {
+    /// The user logs in with the OIDC server in a browser, which redirects
+    /// back to the finish page that hands the auth code to the client
+    BrowserRedirect,
+    /// A login flow added in a newer server version
+    #[serde(other)]
+    Unknown,
+}
+
+/// Describes a server to clients setting up a tunnel to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryDocument {
+    /// Newest protocol version the server speaks
+    pub protocol_version: u32,
+    /// Oldest protocol version the server accepts
+    #[serde(default = "default_min_protocol_version")]
+    pub min_protocol_version: u32,
+    pub paths: ApiPaths,
+    pub login_flows: Vec<LoginFlow>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<Url>,
+    /// Fingerprint of the server WireGuard public key, in the form `SHA256:<base64>`
+    pub wg_public_key_fingerprint: String,
+    /// Base64 Ed25519 public key login responses are signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_public_key: Option<String>,
+}
+
+fn default_min_protocol_version() -> u32 {
+    1
+}
+
+impl DiscoveryDocument {
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Checks that this client can talk to the server, returning the reason if it can't
+    pub fn check_compatible(&self) -> Result<(), String> {
+        match check_protocol_version(self.min_protocol_version, self.protocol_version) {
+            Ok(()) => (),
+            Err(ProtocolMismatch::PeerTooOld(version)) => {
+                return Err(format!(
+                    "Server speaks protocol version {}, which is too old for this client, please upgrade the server",
+                    version
+                ))
+            }
+            Err(ProtocolMismatch::PeerTooNew(version)) => {
+                return Err(format!(
+                    "Server requires protocol version {}, please upgrade this client",
+                    version
+                ))
+            }
+        }
+        if !self.login_flows.contains(&LoginFlow::BrowserRedirect) {
+            return Err("Server does not support any login flow this client supports".to_owned());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StartLoginRequest {
     pub device_id: Uuid,
@@ -24,7 +171,185 @@ pub struct FinishLoginRequest {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FinishLoginResponse {
+    pub session_token: String,
+    pub session_ends_at: DateTime<Utc>,
+    pub interface: WireguardInterface,
+    pub peer: WireguardPeer,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshSessionRequest {
+    pub session_token: String,
+    pub client_public_key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshSessionResponse {
+    pub session_token: String,
     pub session_ends_at: DateTime<Utc>,
     pub interface: WireguardInterface,
     pub peer: WireguardPeer,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutRequest {
+    pub session_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutResponse {}
+
+/// Stable error codes returned by the server, clients should check these instead of messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body could not be parsed
+    InvalidRequest,
+    /// The login token is malformed or was not issued by this server
+    InvalidLoginToken,
+    /// The login took too long, and should be started again
+    LoginExpired,
+    /// The OIDC server did not accept the login
+    LoginDenied,
+    /// The user's email address is not in the allowed domain
+    DomainNotAllowed,
+    /// The user is not a member of any allowed group
+    GroupNotAllowed,
+    /// The OIDC server could not be reached
+    IdpUnreachable,
+    /// The session has expired or was logged out
+    UnknownSession,
+    /// The OIDC server no longer allows the user to login, and the session was ended
+    RefreshDenied,
+    /// The user has reached their limit of concurrent sessions
+    TooManySessions,
+    /// The user has reached their limit of devices
+    TooManyDevices,
+    /// There are no free client addresses left
+    AddressPoolExhausted,
+    /// Too many requests, check the Retry-After header
+    RateLimited,
+    /// The client speaks a protocol version the server does not support
+    UnsupportedProtocolVersion,
+    /// An unexpected server error
+    Internal,
+    /// An error code added in a newer server version
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// The HTTP status code the server responds with for this error
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::InvalidRequest | Self::UnsupportedProtocolVersion => 400,
+            Self::InvalidLoginToken
+            | Self::LoginExpired
+            | Self::LoginDenied
+            | Self::UnknownSession
+            | Self::RefreshDenied => 401,
+            Self::DomainNotAllowed
+            | Self::GroupNotAllowed
+            | Self::TooManySessions
+            | Self::TooManyDevices => 403,
+            Self::RateLimited => 429,
+            Self::Internal | Self::Unknown => 500,
+            Self::IdpUnreachable => 502,
+            Self::AddressPoolExhausted => 503,
+        }
+    }
+
+    /// Whether the same request might succeed when retried later
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::IdpUnreachable | Self::RateLimited | Self::AddressPoolExhausted
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discovery(min_protocol_version: u32, protocol_version: u32) -> DiscoveryDocument {
+        DiscoveryDocument {
+            protocol_version,
+            min_protocol_version,
+            paths: ApiPaths::default(),
+            login_flows: vec![LoginFlow::BrowserRedirect],
+            capabilities: vec![],
+            display_name: None,
+            icon_url: None,
+            wg_public_key_fingerprint: "SHA256:".to_owned(),
+            signing_public_key: None,
+        }
+    }
+
+    #[test]
+    fn test_check_protocol_version() {
+        assert_eq!(
+            check_protocol_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            Ok(())
+        );
+        assert_eq!(check_protocol_version(PROTOCOL_VERSION, u32::MAX), Ok(()));
+        assert_eq!(
+            check_protocol_version(0, MIN_PROTOCOL_VERSION - 1),
+            Err(ProtocolMismatch::PeerTooOld(MIN_PROTOCOL_VERSION - 1))
+        );
+        assert_eq!(
+            check_protocol_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2),
+            Err(ProtocolMismatch::PeerTooNew(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        assert!(discovery(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+            .check_compatible()
+            .is_ok());
+        // Servers speaking newer versions still accept ours
+        assert!(discovery(PROTOCOL_VERSION, PROTOCOL_VERSION + 1)
+            .check_compatible()
+            .is_ok());
+
+        let err = discovery(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 1)
+            .check_compatible()
+            .unwrap_err();
+        assert!(err.contains("upgrade this client"), "{}", err);
+        let err = discovery(0, MIN_PROTOCOL_VERSION - 1)
+            .check_compatible()
+            .unwrap_err();
+        assert!(err.contains("upgrade the server"), "{}", err);
+
+        let mut unknown_flow = discovery(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+        unknown_flow.login_flows = vec![LoginFlow::Unknown];
+        assert!(unknown_flow.check_compatible().is_err());
+    }
+
+    #[test]
+    fn test_parse_discovery() -> serde_json::Result<()> {
+        // Documents from servers before versioning, and with values added later
+        let discovery: DiscoveryDocument = serde_json::from_value(serde_json::json!({
+            "protocol_version": 1,
+            "paths": ApiPaths::default(),
+            "login_flows": ["browser_redirect", "device_code"],
+            "capabilities": ["logout", "future_capability"],
+            "wg_public_key_fingerprint": "SHA256:",
+        }))?;
+        assert_eq!(discovery.min_protocol_version, 1);
+        assert_eq!(
+            discovery.login_flows,
+            vec![LoginFlow::BrowserRedirect, LoginFlow::Unknown]
+        );
+        assert!(discovery.has_capability(Capability::Logout));
+        assert!(!discovery.has_capability(Capability::SessionRefresh));
+        Ok(())
+    }
+}
@@ -83,6 +83,7 @@ export function updateTrayFromStatus(status: StatusResponse): void {
             tray.setImage(TRAY_ICON_PROGRESS)
             break
         case TunnelStatus.ERROR:
+        case TunnelStatus.KEY_MISMATCH:
             tray.setImage(TRAY_ICON_ERROR)
             break
         default:
@@ -2,25 +2,68 @@ use anyhow::Result;
 use async_std::fs;
 use async_std::prelude::*;
 use cablescout_api::daemon::TunnelInfo;
+use cablescout_api::server::{Client, DiscoveryDocument};
 use log::*;
 use notify::{watcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tokio::task;
 use url::Url;
 use uuid::Uuid;
+use wg_utils::{interface_name_for, validate_interface_name};
 
 const CONFIG_SUFFIX: &str = ".tunnel.json";
 
 pub type ConfigTunnels = HashMap<String, TunnelConfig>;
 
+/// How long a server discovery document is used before fetching it again
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
 pub struct DaemonConfig {
     path: PathBuf,
+    /// Prefix of interface names derived from tunnel names
+    interface_prefix: String,
     inner: RwLock<Inner>,
+    discovery: DiscoveryCache,
+}
+
+/// Discovery documents by server URL, with when they were fetched
+struct DiscoveryCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<Url, (Instant, Option<DiscoveryDocument>)>>,
+}
+
+impl DiscoveryCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns the document cached for `url`, calling `fetch` when there is none or it expired
+    async fn get_or_fetch<F, Fut>(&self, url: &Url, fetch: F) -> Result<Option<DiscoveryDocument>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<DiscoveryDocument>>>,
+    {
+        if let Some((fetched_at, discovery)) = self.entries.read().await.get(url) {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(discovery.clone());
+            }
+        }
+
+        let discovery = fetch().await?;
+        self.entries
+            .write()
+            .await
+            .insert(url.clone(), (Instant::now(), discovery.clone()));
+        Ok(discovery)
+    }
 }
 
 struct Inner {
@@ -29,9 +72,10 @@ struct Inner {
 }
 
 impl Inner {
-    async fn new(path: &Path) -> Result<Self> {
+    async fn new(path: &Path, interface_prefix: &str) -> Result<Self> {
         let device_id = Self::find_device_id(path).await?;
-        let tunnels = Self::read_tunnels(path).await?;
+        let mut tunnels = Self::read_tunnels(path).await?;
+        Self::skip_invalid_interfaces(&mut tunnels, interface_prefix);
         Ok(Self { device_id, tunnels })
     }
 
@@ -89,12 +133,45 @@ impl Inner {
         info!("Found {} configured tunnels", tunnels.len());
         Ok(tunnels)
     }
+
+    /// Skips tunnels without a valid interface name, and tunnels using the same interface
+    /// as another tunnel, connecting one would otherwise replace the interface of the other
+    fn skip_invalid_interfaces(tunnels: &mut ConfigTunnels, interface_prefix: &str) {
+        let mut skipped = HashSet::new();
+        let mut interfaces: HashMap<String, Vec<String>> = HashMap::with_capacity(tunnels.len());
+        for (name, tunnel_config) in tunnels.iter() {
+            match tunnel_config.interface_name(interface_prefix, name) {
+                Ok(interface) => interfaces.entry(interface).or_default().push(name.clone()),
+                Err(err) => {
+                    error!("Skipping tunnel {}, invalid interface: {:#}", name, err);
+                    skipped.insert(name.clone());
+                }
+            }
+        }
+        for (interface, mut names) in interfaces {
+            if names.len() > 1 {
+                names.sort();
+                error!(
+                    "Skipping tunnels {}, they all use interface {}, set \"interface\" in all but one of them",
+                    names.join(", "),
+                    interface
+                );
+                skipped.extend(names);
+            }
+        }
+        tunnels.retain(|name, _| !skipped.contains(name));
+    }
 }
 
 impl DaemonConfig {
-    pub async fn new(path: PathBuf) -> Result<Arc<Self>> {
-        let inner = RwLock::new(Inner::new(&path).await?);
-        let self_ = Arc::new(Self { path, inner });
+    pub async fn new(path: PathBuf, interface_prefix: String) -> Result<Arc<Self>> {
+        let inner = RwLock::new(Inner::new(&path, &interface_prefix).await?);
+        let self_ = Arc::new(Self {
+            path,
+            interface_prefix,
+            inner,
+            discovery: DiscoveryCache::new(DISCOVERY_TTL),
+        });
         self_.watch();
         Ok(self_)
     }
@@ -103,6 +180,10 @@ impl DaemonConfig {
         self.path.clone()
     }
 
+    pub fn interface_prefix(&self) -> &str {
+        &self.interface_prefix
+    }
+
     pub async fn get_device_id(self: &Arc<Self>) -> Uuid {
         self.inner.read().await.device_id
     }
@@ -121,6 +202,17 @@ impl DaemonConfig {
         self.inner.read().await.tunnels.get(name).cloned()
     }
 
+    /// Fetches the discovery document of the server `client` talks to, using a cached
+    /// one when it was recently fetched
+    pub async fn discover(self: &Arc<Self>, client: &Client) -> Result<Option<DiscoveryDocument>> {
+        self.discovery
+            .get_or_fetch(client.base_url(), || async {
+                debug!("Fetching discovery document of {}", client.base_url());
+                Ok(client.discover().await?)
+            })
+            .await
+    }
+
     fn watch(self: &Arc<Self>) {
         debug!("Watching for changes in {:?}", self.path);
 
@@ -154,14 +246,29 @@ impl DaemonConfig {
 
     async fn refresh(self: Arc<Self>) -> Result<()> {
         let mut writer = self.inner.write().await;
-        *writer = Inner::new(&self.path).await?;
+        *writer = Inner::new(&self.path, &self.interface_prefix).await?;
         Ok(())
     }
 }
 
+static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TunnelConfig {
     pub endpoint: Url,
+    /// Optional CA certificate in PEM format for servers using a private CA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_certificate: Option<PathBuf>,
+    /// Base64 WireGuard public key the server must present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_public_key: Option<String>,
+    /// Base64 Ed25519 key the server must sign its login responses with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    /// WireGuard interface name, derived from the tunnel name when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
 }
 
 impl From<&TunnelConfig> for TunnelInfo {
@@ -173,15 +280,95 @@ impl From<&TunnelConfig> for TunnelInfo {
 }
 
 impl TunnelConfig {
-    pub fn start_api_url(&self) -> Result<Url> {
-        Ok(self.endpoint.join("/api/v1/login/start")?)
+    /// WireGuard interface of tunnel `name`
+    pub fn interface_name(&self, interface_prefix: &str, name: &str) -> Result<String> {
+        match self.interface.as_ref() {
+            Some(interface) => {
+                validate_interface_name(interface)?;
+                Ok(interface.clone())
+            }
+            None => interface_name_for(interface_prefix, name),
+        }
     }
 
-    pub fn finish_api_url(&self) -> Result<Url> {
-        Ok(self.endpoint.join("/api/v1/login/finish")?)
+    pub fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder(self.endpoint.clone())
+            .user_agent(USER_AGENT)
+            .timeout(REQUEST_TIMEOUT);
+        if let Some(ca_certificate) = self.ca_certificate.as_ref() {
+            builder = builder.add_root_certificate_pem(&std::fs::read(ca_certificate)?)?;
+        }
+        if let Some(server_public_key) = self.server_public_key.as_ref() {
+            builder = builder.pin_server_public_key(server_public_key);
+        }
+        if let Some(signing_key) = self.signing_key.as_ref() {
+            builder = builder.pin_signing_key(signing_key)?;
+        }
+        Ok(builder.build()?)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cablescout_api::server::{ApiPaths, LoginFlow, PROTOCOL_VERSION};
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn discovery(display_name: &str) -> DiscoveryDocument {
+        DiscoveryDocument {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: PROTOCOL_VERSION,
+            paths: ApiPaths::default(),
+            login_flows: vec![LoginFlow::BrowserRedirect],
+            capabilities: vec![],
+            display_name: Some(display_name.to_owned()),
+            icon_url: None,
+            wg_public_key_fingerprint: "SHA256:".to_owned(),
+            signing_public_key: None,
+        }
+    }
+
+    #[test]
+    fn test_discovery_cache() -> Result<()> {
+        let fetches = AtomicUsize::new(0);
+        let fetch = |display_name: &'static str| {
+            let fetches = &fetches;
+            move || async move {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(discovery(display_name)))
+            }
+        };
+        let display_name =
+            |discovery: Option<DiscoveryDocument>| discovery.and_then(|d| d.display_name);
+        let first = Url::parse("https://first.example.com")?;
+        let second = Url::parse("https://second.example.com")?;
+
+        block_on(async {
+            let cache = DiscoveryCache::new(DISCOVERY_TTL);
+            let res = cache.get_or_fetch(&first, fetch("first")).await?;
+            assert_eq!(display_name(res).as_deref(), Some("first"));
+            let res = cache.get_or_fetch(&first, fetch("changed")).await?;
+            assert_eq!(display_name(res).as_deref(), Some("first"));
+            let res = cache.get_or_fetch(&second, fetch("second")).await?;
+            assert_eq!(display_name(res).as_deref(), Some("second"));
+            assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+            // A failed fetch leaves nothing cached
+            let expired = DiscoveryCache::new(Duration::from_secs(0));
+            let res = expired
+                .get_or_fetch(&first, || async { Err(anyhow::anyhow!("Unreachable")) })
+                .await;
+            assert!(res.is_err());
+            assert!(expired.entries.read().await.is_empty());
 
-    pub fn finish_url(&self) -> Result<Url> {
-        Ok(self.endpoint.join("/finish")?)
+            // Expired documents are fetched again
+            let res = expired.get_or_fetch(&first, fetch("first")).await?;
+            assert_eq!(display_name(res).as_deref(), Some("first"));
+            let res = expired.get_or_fetch(&first, fetch("changed")).await?;
+            assert_eq!(display_name(res).as_deref(), Some("changed"));
+            assert_eq!(fetches.load(Ordering::SeqCst), 4);
+            Ok(())
+        })
     }
 }
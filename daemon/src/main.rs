@@ -1,5 +1,4 @@
 mod config;
-mod http;
 mod server;
 mod tunnel;
 
@@ -8,7 +7,9 @@ use async_std::fs::create_dir_all;
 use config::DaemonConfig;
 use log::*;
 use server::Server;
+use std::path::PathBuf;
 use structopt::StructOpt;
+use wg_utils::{validate_interface_prefix, BackendKind, BackendOptions};
 
 #[derive(Debug, StructOpt)]
 struct Options {
@@ -18,6 +19,23 @@ struct Options {
 
     #[structopt(short, long, default_value = "51889")]
     port: u16,
+
+    /// How tunnel interfaces are managed: "wg-quick", "uapi" for a userspace
+    /// implementation such as wireguard-go, "boringtun" to run boringtun-cli
+    /// without kernel WireGuard, "netlink" to configure kernel WireGuard without
+    /// the WireGuard tools on Linux, or "mock" to run without interfaces
+    #[structopt(long, env = "WG_BACKEND", default_value = "wg-quick")]
+    backend: BackendKind,
+
+    /// Directory the wg-quick backend writes tunnel configurations to,
+    /// /etc/wireguard when not set
+    #[structopt(long, env = "WG_CONFIG_DIR")]
+    wg_config_dir: Option<PathBuf>,
+
+    /// Interface names of tunnels without one in their configuration are this prefix
+    /// followed by a hash of the tunnel name
+    #[structopt(long, env = "WG_INTERFACE_PREFIX", default_value = "cs-")]
+    interface_prefix: String,
 }
 
 #[tokio::main]
@@ -36,6 +54,7 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     info!("Daemon starting: {:?}", options);
+    validate_interface_prefix(&options.interface_prefix)?;
 
     let config_dir = dirs::config_dir()
         .ok_or_else(|| anyhow!("Could not find config directory"))?
@@ -44,9 +63,20 @@ async fn main() -> anyhow::Result<()> {
     debug!("Creating {:?}", config_dir);
     create_dir_all(config_dir.clone()).await?;
 
-    let daemon_config = DaemonConfig::new(config_dir).await?;
+    let daemon_config = DaemonConfig::new(config_dir, options.interface_prefix).await?;
+
+    let mut backend_options = BackendOptions::default();
+    if let Some(wg_config_dir) = options.wg_config_dir {
+        backend_options.config_dir = wg_config_dir;
+    }
 
-    Server::new(options.port, daemon_config).run().await?;
+    Server::new(
+        options.port,
+        daemon_config,
+        options.backend.backend(&backend_options),
+    )
+    .run()
+    .await?;
 
     Ok(())
 }
@@ -1,23 +1,35 @@
 use crate::config::DaemonConfig;
 use crate::tunnel::Tunnel;
 use cablescout_api::daemon as daemon_api;
+use chrono::prelude::*;
 use log::*;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 use tokio::sync::RwLock;
+use tokio::time;
 use tonic::{Request, Response, Status};
+use wg_utils::WireguardBackend;
+
+type CurrentTunnel = Arc<RwLock<Option<Tunnel>>>;
 
 pub struct Server {
     port: u16,
     daemon_config: Arc<DaemonConfig>,
-    tunnel: RwLock<Option<Tunnel>>,
+    backend: Arc<dyn WireguardBackend>,
+    tunnel: CurrentTunnel,
 }
 
 impl Server {
-    pub fn new(port: u16, daemon_config: Arc<DaemonConfig>) -> Self {
+    pub fn new(
+        port: u16,
+        daemon_config: Arc<DaemonConfig>,
+        backend: Arc<dyn WireguardBackend>,
+    ) -> Self {
         Self {
             port,
             daemon_config,
+            backend,
             tunnel: Default::default(),
         }
     }
@@ -30,6 +42,40 @@ impl Server {
             .await?;
         Ok(())
     }
+
+    /// Keeps refreshing the session of the current tunnel for as long as it stays connected
+    async fn refresh_sessions(tunnel: CurrentTunnel) {
+        loop {
+            let refresh_at = match tunnel.read().await.as_ref().and_then(Tunnel::refresh_at) {
+                None => break,
+                Some(refresh_at) => refresh_at,
+            };
+            let until = (refresh_at - Utc::now())
+                .to_std()
+                .unwrap_or_else(|_| std::time::Duration::from_nanos(0));
+            info!("Refreshing session in {:?}", until);
+            time::sleep(until).await;
+
+            // The request is sent without holding the lock, so the tunnel can be inspected
+            // or disconnected while waiting for the server
+            let refresh = match tunnel.read().await.as_ref().map(Tunnel::start_refresh) {
+                None => break,
+                Some(Ok(refresh)) => refresh,
+                Some(Err(err)) => {
+                    error!("Could not refresh session: {}", err);
+                    continue;
+                }
+            };
+            let result = refresh.send().await;
+
+            let mut writer = tunnel.write().await;
+            if let Some(tunnel) = writer.as_mut() {
+                if let Err(err) = tunnel.finish_refresh(refresh, result).await {
+                    error!("Error refreshing session of {}: {}", tunnel.name(), err);
+                }
+            }
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -40,13 +86,28 @@ impl daemon_api::daemon_server::Daemon for Server {
     ) -> Result<Response<daemon_api::StatusResponse>, Status> {
         info!("Handling get_status");
         let tunnel = self.tunnel.read().await;
+        let current_tunnel = match tunnel.as_ref() {
+            None => None,
+            Some(tunnel) => {
+                let peer = tunnel.peer_status().await;
+                Some(daemon_api::CurrentTunnel {
+                    name: tunnel.name(),
+                    status: tunnel.status().into(),
+                    latest_handshake: peer
+                        .as_ref()
+                        .and_then(|peer| peer.latest_handshake)
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map(|since_epoch| since_epoch.as_secs() as i64)
+                        .unwrap_or(0),
+                    rx_bytes: peer.as_ref().map(|peer| peer.rx_bytes).unwrap_or(0),
+                    tx_bytes: peer.as_ref().map(|peer| peer.tx_bytes).unwrap_or(0),
+                })
+            }
+        };
         Ok(Response::new(daemon_api::StatusResponse {
             config: self.daemon_config.get_tunnels_info().await,
             tunnels_path: self.daemon_config.path().to_string_lossy().to_string(),
-            current_tunnel: tunnel.as_ref().map(|tunnel| daemon_api::CurrentTunnel {
-                name: tunnel.name(),
-                status: tunnel.status().into(),
-            }),
+            current_tunnel,
         }))
     }
 
@@ -66,22 +127,28 @@ impl daemon_api::daemon_server::Daemon for Server {
             Some(tunnel_config) => tunnel_config,
         };
 
-        let finish_url = tunnel_config
+        let mut tunnel = Tunnel::new(
+            req.name,
+            self.daemon_config.clone(),
+            self.backend.clone(),
+            tunnel_config,
+        )
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        let auth_url = tunnel
+            .start_connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        // The finish page is known once the server discovery document was fetched
+        let finish_url = tunnel
             .finish_url()
             .map_err(|e| Status::internal(e.to_string()))?
             .to_string();
-
-        let mut tunnel = Tunnel::new(req.name, self.daemon_config.clone(), tunnel_config);
-        match tunnel.start_connect().await {
-            Ok(auth_url) => {
-                *writer = Some(tunnel);
-                Ok(Response::new(daemon_api::StartConnectTunnelResponse {
-                    auth_url: auth_url.to_string(),
-                    finish_url,
-                }))
-            }
-            Err(err) => Err(Status::internal(err.to_string())),
-        }
+        *writer = Some(tunnel);
+        Ok(Response::new(daemon_api::StartConnectTunnelResponse {
+            auth_url: auth_url.to_string(),
+            finish_url,
+        }))
     }
 
     async fn finish_connect_tunnel(
@@ -97,7 +164,12 @@ impl daemon_api::daemon_server::Daemon for Server {
                 "No tunnel is currently connecting",
             )),
             Some(tunnel) => match tunnel.finish_connect(req.auth_code).await {
-                Ok(()) => Ok(Response::new(daemon_api::FinishConnectTunnelResponse {})),
+                Ok(()) => {
+                    tunnel.set_refresh_task(tokio::spawn(Self::refresh_sessions(
+                        self.tunnel.clone(),
+                    )));
+                    Ok(Response::new(daemon_api::FinishConnectTunnelResponse {}))
+                }
                 Err(err) => Err(Status::internal(err.to_string())),
             },
         }
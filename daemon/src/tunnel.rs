@@ -1,22 +1,92 @@
 use crate::config::{DaemonConfig, TunnelConfig};
-use crate::http::http_post;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cablescout_api::daemon::TunnelStatus;
 use cablescout_api::server::{
-    FinishLoginRequest, FinishLoginResponse, StartLoginRequest, StartLoginResponse,
+    Capability, Client, ClientError, FinishLoginRequest, LogoutRequest, RefreshSessionRequest,
+    RefreshSessionResponse, StartLoginRequest, StartLoginResponse,
 };
+use chrono::prelude::*;
 use log::*;
 use std::sync::Arc;
+use tokio::task::JoinHandle;
 use url::Url;
-use wg_utils::{wg_quick_down, wg_quick_up, FullWireguardInterface, WgKeyPair, WireguardConfig};
+use wg_utils::{
+    FullWireguardInterface, PeerStatus, WgKeyPair, WireguardBackend, WireguardConfig,
+    WireguardInterface, WireguardPeer,
+};
+
+/// Sessions are refreshed after this part of their remaining time has passed
+const REFRESH_AFTER_FRACTION: i32 = 4;
+const REFRESH_FRACTIONS: i32 = 5;
+/// How long to wait before refreshing again after a refresh failed for a temporary reason
+const REFRESH_RETRY_SECONDS: i64 = 30;
+
+struct TunnelSession {
+    token: String,
+    refresh_at: DateTime<Utc>,
+}
+
+impl TunnelSession {
+    fn new(token: String, ends_at: DateTime<Utc>) -> Self {
+        let now = Utc::now();
+        Self {
+            token,
+            refresh_at: now + (ends_at - now) * REFRESH_AFTER_FRACTION / REFRESH_FRACTIONS,
+        }
+    }
+}
+
+/// Session refresh request, sent without holding the lock of the tunnel so the
+/// tunnel can be inspected or disconnected while waiting for the server
+pub struct PendingRefresh {
+    client: Client,
+    session_token: String,
+    /// Every refresh rotates the client key
+    key_pair: WgKeyPair,
+}
+
+impl PendingRefresh {
+    pub async fn send(&self) -> Result<RefreshSessionResponse> {
+        let req = RefreshSessionRequest {
+            session_token: self.session_token.clone(),
+            client_public_key: self.key_pair.public_key.to_string(),
+        };
+        debug!("Sending session refresh request");
+        let refresh_res = self.client.refresh_session(&req).await?;
+        debug!("Session refreshed, ends at {}", refresh_res.session_ends_at);
+        Ok(refresh_res)
+    }
+}
+
+/// Whether a failed request might succeed when sent again later
+fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ClientError>()
+        .map(ClientError::is_retryable)
+        .unwrap_or(false)
+}
+
+/// Status to report after a failed request, flagging servers that failed key pinning
+fn error_status(err: &anyhow::Error) -> TunnelStatus {
+    match err.downcast_ref::<ClientError>() {
+        Some(ClientError::KeyMismatch(_)) => TunnelStatus::KeyMismatch,
+        _ => TunnelStatus::Error,
+    }
+}
 
 pub struct Tunnel {
     name: String,
+    /// WireGuard interface of the tunnel, tunnel names can be too long or invalid for one
+    interface: String,
     daemon_config: Arc<DaemonConfig>,
-    tunnel_config: TunnelConfig,
+    backend: Arc<dyn WireguardBackend>,
+    client: Client,
+    /// Capabilities published by the server, `None` for servers without a discovery document
+    capabilities: Option<Vec<Capability>>,
     status: TunnelStatus,
     key_pair: Option<WgKeyPair>,
     login_token: Option<String>,
+    session: Option<TunnelSession>,
+    refresh_task: Option<JoinHandle<()>>,
     error: Option<String>,
 }
 
@@ -24,37 +94,104 @@ impl Tunnel {
     pub fn new(
         name: String,
         daemon_config: Arc<DaemonConfig>,
+        backend: Arc<dyn WireguardBackend>,
         tunnel_config: TunnelConfig,
-    ) -> Self {
-        Self {
+    ) -> Result<Self> {
+        let interface = tunnel_config.interface_name(daemon_config.interface_prefix(), &name)?;
+        debug!("Tunnel {} uses interface {}", name, interface);
+        Ok(Self {
             name,
+            interface,
             daemon_config,
-            tunnel_config,
+            backend,
+            client: tunnel_config.client()?,
+            capabilities: None,
             status: TunnelStatus::Disconnected,
             key_pair: None,
             login_token: None,
+            session: None,
+            refresh_task: None,
             error: None,
-        }
+        })
     }
 
     pub fn name(&self) -> String {
         self.name.clone()
     }
 
+    pub fn finish_url(&self) -> Result<Url> {
+        Ok(self.client.finish_page_url()?)
+    }
+
     pub fn status(&self) -> TunnelStatus {
         self.status
     }
 
+    /// Handshake and transfer statistics of the server peer, while the interface is up
+    pub async fn peer_status(&self) -> Option<PeerStatus> {
+        if self.status != TunnelStatus::Connected {
+            return None;
+        }
+        match self.backend.status(&self.interface).await {
+            Ok(status) => status.peers.into_iter().next(),
+            Err(err) => {
+                warn!("Could not read status of {}: {}", self.interface, err);
+                None
+            }
+        }
+    }
+
+    /// Servers without a discovery document are assumed to support everything
+    fn supports(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .map(|capabilities| capabilities.contains(&capability))
+            .unwrap_or(true)
+    }
+
+    /// When the current session should be refreshed, if the tunnel is connected
+    /// and the server supports refreshing sessions
+    pub fn refresh_at(&self) -> Option<DateTime<Utc>> {
+        if !self.supports(Capability::SessionRefresh) {
+            return None;
+        }
+        match self.status {
+            TunnelStatus::Connected => self.session.as_ref().map(|session| session.refresh_at),
+            _ => None,
+        }
+    }
+
+    pub fn set_refresh_task(&mut self, refresh_task: JoinHandle<()>) {
+        if let Some(previous) = self.refresh_task.replace(refresh_task) {
+            previous.abort();
+        }
+    }
+
+    /// Checks the server is compatible and learns its API paths
+    async fn discover(&mut self) -> Result<()> {
+        match self.daemon_config.discover(&self.client).await? {
+            Some(discovery) => {
+                debug!("Got discovery document: {:#?}", discovery);
+                self.client.use_discovery(&discovery)?;
+                self.capabilities = Some(discovery.capabilities);
+            }
+            None => warn!(
+                "Server of {} has no discovery document, assuming default API paths",
+                self.name
+            ),
+        }
+        Ok(())
+    }
+
     async fn start_login(&self) -> Result<(WgKeyPair, StartLoginResponse)> {
-        let key_pair = WgKeyPair::new().await?;
+        let key_pair = WgKeyPair::new();
 
         let req = StartLoginRequest {
             device_id: self.daemon_config.get_device_id().await,
-            client_public_key: key_pair.public_key.clone(),
+            client_public_key: key_pair.public_key.to_string(),
         };
         debug!("Sending login start request: {:#?}", req);
-        let start_res: StartLoginResponse =
-            http_post(self.tunnel_config.start_api_url()?, req).await?;
+        let start_res = self.client.start_login(&req).await?;
         debug!("Got login start response: {:#?}", start_res);
 
         Ok((key_pair, start_res))
@@ -65,23 +202,41 @@ impl Tunnel {
         login_token: String,
         key_pair: WgKeyPair,
         auth_code: String,
-    ) -> Result<()> {
+    ) -> Result<TunnelSession> {
         let req = FinishLoginRequest {
             login_token,
             auth_code,
         };
         debug!("Sending login finish request: {:#?}", req);
-        let finish_res: FinishLoginResponse =
-            http_post(self.tunnel_config.finish_api_url()?, req).await?;
+        let finish_res = self.client.finish_login(&req).await?;
         debug!("Got login finish response: {:#?}", finish_res);
 
-        let wg_config = WireguardConfig::new(
-            FullWireguardInterface::new(&key_pair, finish_res.interface),
-            vec![finish_res.peer],
-        );
+        self.bring_up(&key_pair, finish_res.interface, finish_res.peer)
+            .await?;
 
-        wg_quick_up(&self.name, wg_config).await?;
+        Ok(TunnelSession::new(
+            finish_res.session_token,
+            finish_res.session_ends_at,
+        ))
+    }
 
+    async fn bring_up(
+        &self,
+        key_pair: &WgKeyPair,
+        interface: WireguardInterface,
+        peer: WireguardPeer,
+    ) -> Result<()> {
+        let wg_config =
+            WireguardConfig::new(FullWireguardInterface::new(key_pair, interface), vec![peer]);
+        self.backend
+            .create_interface(&self.interface, &wg_config)
+            .await
+    }
+
+    async fn logout(&self, session_token: String) -> Result<()> {
+        let req = LogoutRequest { session_token };
+        debug!("Sending logout request");
+        self.client.logout(&req).await?;
         Ok(())
     }
 
@@ -89,14 +244,18 @@ impl Tunnel {
         self.status = TunnelStatus::Connecting;
         self.error = None;
 
-        match self.start_login().await {
+        let result = match self.discover().await {
+            Ok(()) => self.start_login().await,
+            Err(err) => Err(err),
+        };
+        match result {
             Ok((key_pair, start_res)) => {
                 self.key_pair = Some(key_pair);
                 self.login_token = Some(start_res.login_token);
                 Ok(start_res.auth_url)
             }
             Err(err) => {
-                self.status = TunnelStatus::Error;
+                self.status = error_status(&err);
                 self.error = Some(err.to_string());
                 Err(err)
             }
@@ -113,13 +272,77 @@ impl Tunnel {
             .take()
             .expect("No key_pair while calling finish_connect");
         match self.finish_login(login_token, key_pair, auth_code).await {
-            Ok(()) => {
+            Ok(session) => {
+                self.session = Some(session);
                 self.status = TunnelStatus::Connected;
                 Ok(())
             }
             Err(err) => {
                 self.error = Some(err.to_string());
-                self.status = TunnelStatus::Error;
+                self.status = error_status(&err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Request refreshing the current session, to send with `PendingRefresh::send`
+    /// and apply with `finish_refresh`
+    pub fn start_refresh(&self) -> Result<PendingRefresh> {
+        let session_token = self
+            .session
+            .as_ref()
+            .map(|session| session.token.clone())
+            .ok_or_else(|| anyhow!("No session to refresh"))?;
+        Ok(PendingRefresh {
+            client: self.client.clone(),
+            session_token,
+            key_pair: WgKeyPair::new(),
+        })
+    }
+
+    /// Brings the tunnel up with the refreshed session, unless the tunnel
+    /// was disconnected or logged in again while the refresh was sent
+    pub async fn finish_refresh(
+        &mut self,
+        refresh: PendingRefresh,
+        result: Result<RefreshSessionResponse>,
+    ) -> Result<()> {
+        let current_token = self.session.as_ref().map(|session| session.token.as_str());
+        if current_token != Some(refresh.session_token.as_str()) {
+            info!(
+                "Session of {} changed while it was refreshed, ignoring the refresh",
+                self.name
+            );
+            return Ok(());
+        }
+
+        let result = match result {
+            Ok(RefreshSessionResponse {
+                session_token,
+                session_ends_at,
+                interface,
+                peer,
+            }) => self
+                .bring_up(&refresh.key_pair, interface, peer)
+                .await
+                .map(|()| TunnelSession::new(session_token, session_ends_at)),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(session) => {
+                self.session = Some(session);
+                Ok(())
+            }
+            Err(err) if is_retryable(&err) => {
+                if let Some(session) = self.session.as_mut() {
+                    session.refresh_at =
+                        Utc::now() + chrono::Duration::seconds(REFRESH_RETRY_SECONDS);
+                }
+                Err(err)
+            }
+            Err(err) => {
+                self.error = Some(err.to_string());
+                self.status = error_status(&err);
                 Err(err)
             }
         }
@@ -128,7 +351,20 @@ impl Tunnel {
     pub async fn disconnect(&mut self) -> Result<()> {
         self.status = TunnelStatus::Disconnecting;
 
-        match wg_quick_down(&self.name).await {
+        if let Some(refresh_task) = self.refresh_task.take() {
+            refresh_task.abort();
+        }
+
+        let session = self.session.take();
+        if let Some(session) = session.filter(|_| self.supports(Capability::Logout)) {
+            // The server expires the session eventually, so a failed logout
+            // should not keep the tunnel up
+            if let Err(err) = self.logout(session.token).await {
+                warn!("Could not log out of {}: {}", self.name, err);
+            }
+        }
+
+        match self.backend.destroy_interface(&self.interface).await {
             Ok(_) => {
                 self.status = TunnelStatus::Disconnected;
                 Ok(())
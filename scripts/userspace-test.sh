@@ -0,0 +1,13 @@
+#!/bin/sh
+# Runs the tests that need a WireGuard interface, with boringtun and with kernel WireGuard
+# over netlink, inside unprivileged user, network and mount namespaces so they need no root.
+set -eu
+
+cargo test -p wg-utils --no-run
+exec unshare --user --map-root-user --net --mount sh -euc '
+    # boringtun opens its UAPI socket in /var/run/wireguard, which only the real root can create
+    mount -t tmpfs tmpfs /run
+    mkdir -p /run/wireguard
+    ip link set lo up
+    cargo test -p wg-utils -- --ignored
+'
@@ -1,19 +1,38 @@
-use crate::api_result::ApiResult;
+use crate::api_result::{ApiError, ApiResult, LoginError};
+use crate::audit::{public_key_fingerprint, AuditEvent, AuditLog, AuditRecord};
+use crate::health::HealthReport;
+use crate::keys::ResponseSigner;
 use crate::login::{LoginSettings, OidcLogin};
+use crate::rate_limit::{LoginRateLimits, RateLimitSettings};
+use crate::tls::ReloadingCertResolver;
 use crate::tokens::{random_string, TokenGenerator};
 use crate::wireguard::Wireguard;
-use actix_web::middleware::Logger;
-use actix_web::{web, App, HttpResponse, HttpServer};
-use anyhow::Result;
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::middleware::{DefaultHeaders, Logger};
+use actix_web::{web, App, FromRequest, HttpRequest, HttpResponse, HttpServer};
+use anyhow::{anyhow, Result};
 use cablescout_api::server::{
-    FinishLoginRequest, FinishLoginResponse, StartLoginRequest, StartLoginResponse,
+    check_protocol_version, ApiPaths, Capability, DiscoveryDocument, FinishLoginRequest,
+    FinishLoginResponse, LoginFlow, LogoutRequest, LogoutResponse, ProtocolMismatch,
+    RefreshSessionRequest, RefreshSessionResponse, StartLoginRequest, StartLoginResponse,
+    MIN_PROTOCOL_VERSION, PROTOCOL_HEADER, PROTOCOL_VERSION, SIGNATURE_HEADER,
 };
+use ipnetwork::IpNetwork;
+use log::*;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::net::IpAddr;
-use std::sync::Arc;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use structopt::StructOpt;
+use url::Url;
 use uuid::Uuid;
+use wg_utils::PublicKey;
+
+/// How long readiness checks wait for the session store
+const READINESS_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug, StructOpt)]
 pub struct ApiSettings {
@@ -24,6 +43,46 @@ pub struct ApiSettings {
     /// API server port
     #[structopt(long, env = "HTTP_PORT", default_value = "8080")]
     http_port: u16,
+
+    /// TLS certificate chain file in PEM format, the API server is served over HTTPS when set
+    #[structopt(long, env = "TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// TLS private key file in PEM format, either PKCS#8 or RSA
+    #[structopt(long, env = "TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// How often to check the TLS certificate and key files for changes
+    #[structopt(long, env = "TLS_RELOAD_INTERVAL", default_value = "1m")]
+    tls_reload_interval: humantime::Duration,
+
+    /// Optional port for a plain HTTP listener redirecting all requests to HTTPS
+    #[structopt(long, env = "HTTP_REDIRECT_PORT")]
+    http_redirect_port: Option<u16>,
+
+    /// Address of a separate plain HTTP listener serving Prometheus metrics on /metrics,
+    /// e.g. 127.0.0.1:9090. Metrics are not served when not set, they are never served
+    /// by the API server since they reveal how many clients are connected.
+    #[structopt(long, env = "METRICS_BIND_ADDRESS")]
+    metrics_bind_address: Option<SocketAddr>,
+
+    /// Public URL of the API server, used for the OIDC redirect URI.
+    /// When not set it is taken from the request, see --trusted-proxies.
+    #[structopt(long, env = "PUBLIC_URL")]
+    public_url: Option<Url>,
+
+    /// Name of this server shown to users when adding a tunnel
+    #[structopt(long, env = "DISPLAY_NAME")]
+    display_name: Option<String>,
+
+    /// URL of an icon shown to users next to this server
+    #[structopt(long, env = "ICON_URL")]
+    icon_url: Option<Url>,
+
+    /// Networks of reverse proxies whose X-Forwarded-For, X-Forwarded-Proto and
+    /// X-Forwarded-Host headers are honoured. Headers from other peers are ignored.
+    #[structopt(long, env = "TRUSTED_PROXIES")]
+    trusted_proxies: Vec<IpNetwork>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,23 +97,170 @@ async fn finish_page() -> ApiResult {
     Ok(HttpResponse::Ok().body(include_str!("pages/finish.html")))
 }
 
-fn get_hostname(req: &web::HttpRequest) -> String {
-    req.connection_info()
-        .host()
-        .split(':')
-        .next()
-        .unwrap()
-        .to_owned()
+fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNetwork]) -> bool {
+    trusted_proxies.iter().any(|network| network.contains(ip))
+}
+
+/// Finds the client address in an X-Forwarded-For header sent by a trusted proxy, which is the
+/// rightmost address not belonging to a trusted proxy, since earlier ones are set by the client
+fn forwarded_client_ip(
+    peer_ip: IpAddr,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNetwork],
+) -> IpAddr {
+    if !is_trusted(peer_ip, trusted_proxies) {
+        return peer_ip;
+    }
+    let mut client_ip = peer_ip;
+    for address in forwarded_for.unwrap_or_default().rsplit(',') {
+        match address.trim().parse() {
+            Ok(address) => {
+                client_ip = address;
+                if !is_trusted(address, trusted_proxies) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    client_ip
+}
+
+/// Fails requests sent with a protocol version this server does not speak,
+/// requests without a version come from clients older than versioning
+fn check_protocol_version_header(req: &web::HttpRequest) -> Result<(), ApiError> {
+    let version = match header_value(req, PROTOCOL_HEADER) {
+        None => return Ok(()),
+        Some(version) => version.trim().parse().map_err(|_| {
+            ApiError::InvalidRequest(format!("Invalid {} header: {}", PROTOCOL_HEADER, version))
+        })?,
+    };
+    match check_protocol_version(version, version) {
+        Ok(()) => Ok(()),
+        Err(ProtocolMismatch::PeerTooOld(version)) => Err(ApiError::UnsupportedProtocolVersion(
+            format!(
+                "Client speaks protocol version {}, which is no longer supported, please upgrade the client",
+                version
+            ),
+        )),
+        Err(ProtocolMismatch::PeerTooNew(version)) => Err(ApiError::UnsupportedProtocolVersion(
+            format!(
+                "Client speaks protocol version {}, which is newer than this server, please upgrade the server",
+                version
+            ),
+        )),
+    }
+}
+
+/// Extractor failing requests sent with a protocol version this server does not speak.
+/// Handlers take it before their body, so clients with an incompatible body are told to
+/// upgrade rather than that their request is invalid.
+struct SupportedProtocol;
+
+impl FromRequest for SupportedProtocol {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(check_protocol_version_header(req).map(|()| Self))
+    }
+}
+
+/// Fails requests with a client key WireGuard would not accept
+fn check_client_public_key(public_key: &str) -> Result<(), ApiError> {
+    public_key
+        .parse::<PublicKey>()
+        .map(|_| ())
+        .map_err(|err| ApiError::InvalidRequest(format!("Invalid client_public_key: {}", err)))
+}
+
+fn header_value<'a>(req: &'a web::HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name)?.to_str().ok()
+}
+
+fn login_token_error(err: anyhow::Error) -> LoginError {
+    use jsonwebtoken::errors::ErrorKind;
+
+    match err
+        .downcast_ref::<jsonwebtoken::errors::Error>()
+        .map(|err| err.kind())
+    {
+        Some(ErrorKind::ExpiredSignature) => LoginError::LoginExpired,
+        _ => LoginError::InvalidLoginToken,
+    }
+}
+
+#[actix_web::get("/.well-known/cablescout")]
+async fn discovery_document(api_server: web::Data<Arc<ApiServer>>) -> ApiResult {
+    Ok(HttpResponse::Ok().json(DiscoveryDocument {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        paths: ApiPaths::default(),
+        login_flows: vec![LoginFlow::BrowserRedirect],
+        capabilities: vec![
+            Capability::SessionRefresh,
+            Capability::Logout,
+            Capability::ErrorCodes,
+        ],
+        display_name: api_server.api_settings.display_name.clone(),
+        icon_url: api_server.api_settings.icon_url.clone(),
+        wg_public_key_fingerprint: public_key_fingerprint(&api_server.wireguard.public_key()),
+        signing_public_key: Some(api_server.signer.public_key()),
+    }))
+}
+
+#[actix_web::get("/healthz")]
+async fn health_check() -> HttpResponse {
+    HealthReport::new().into_response()
+}
+
+#[actix_web::get("/metrics")]
+async fn metrics(api_server: web::Data<Arc<ApiServer>>) -> ApiResult {
+    Ok(api_server.wireguard.metrics().await?.into_response())
+}
+
+#[actix_web::get("/readyz")]
+async fn readiness_check(api_server: web::Data<Arc<ApiServer>>) -> HttpResponse {
+    let oidc_discovery = api_server.oidc_login.check_discovery().await;
+    HealthReport::new()
+        .check(
+            "wireguard_interface",
+            api_server.wireguard.check_interface(),
+        )
+        .check("oidc_discovery", oidc_discovery)
+        .check(
+            "session_store",
+            api_server.wireguard.check_sessions(READINESS_TIMEOUT).await,
+        )
+        .into_response()
 }
 
 #[actix_web::post("/api/v1/login/start")]
 async fn start_login_api(
     req: web::HttpRequest,
     api_server: web::Data<Arc<ApiServer>>,
+    _protocol: SupportedProtocol,
     data: web::Json<StartLoginRequest>,
 ) -> ApiResult {
+    check_client_public_key(&data.client_public_key)?;
+    let rate_limits = api_server.login_rate_limits();
+    rate_limits
+        .check_ip(api_server.source_ip(&req))
+        .map_err(ApiError::RateLimited)?;
+    rate_limits
+        .check_device(data.device_id)
+        .map_err(ApiError::RateLimited)?;
+
     let nonce = random_string::<15>();
 
+    api_server.audit_log.record(
+        AuditRecord::new(AuditEvent::LoginStarted)
+            .device_id(data.device_id)
+            .public_key(&data.client_public_key)
+            .source_ip(api_server.source_ip(&req)),
+    );
+
     let login_token = api_server
         .token_generator
         .generate(LoginData {
@@ -64,9 +270,10 @@ async fn start_login_api(
         })
         .await?;
 
+    let public_url = api_server.public_url(&req)?;
     let auth_url = api_server
         .oidc_login
-        .get_auth_url(&req.connection_info(), &login_token, &nonce)
+        .get_auth_url(&public_url, &login_token, &nonce)
         .await?;
 
     Ok(HttpResponse::Ok().json(StartLoginResponse {
@@ -79,20 +286,49 @@ async fn start_login_api(
 async fn finish_login_api(
     req: web::HttpRequest,
     api_server: web::Data<Arc<ApiServer>>,
+    _protocol: SupportedProtocol,
     data: web::Json<FinishLoginRequest>,
 ) -> ApiResult {
+    let rate_limits = api_server.login_rate_limits();
+    rate_limits
+        .check_ip(api_server.source_ip(&req))
+        .map_err(ApiError::RateLimited)?;
+
     let login_data: LoginData = api_server
         .token_generator
         .validate(&data.login_token)
-        .await?;
+        .await
+        .map_err(login_token_error)?;
+    rate_limits
+        .check_device(login_data.device_id)
+        .map_err(ApiError::RateLimited)?;
 
-    let user_data = api_server
+    let public_url = api_server.public_url(&req)?;
+    let record = |event| {
+        AuditRecord::new(event)
+            .device_id(login_data.device_id)
+            .public_key(&login_data.client_public_key)
+            .source_ip(api_server.source_ip(&req))
+    };
+    let user_data = match api_server
         .oidc_login
-        .validate_user(&req.connection_info(), &data.auth_code, &login_data.nonce)
-        .await?;
-    let hostname = get_hostname(&req);
+        .validate_user(&public_url, &data.auth_code, &login_data.nonce)
+        .await
+    {
+        Ok(user_data) => user_data,
+        Err(err) => {
+            api_server.audit_log.record(record(AuditEvent::LoginDenied {
+                reason: err.to_string(),
+            }));
+            return Err(err.into());
+        }
+    };
+    api_server
+        .audit_log
+        .record(record(AuditEvent::LoginSucceeded).user(&user_data));
+    let hostname = api_server.public_hostname(&req)?;
 
-    let (interface, peer, session_ends_at) = api_server
+    let (session, interface, peer) = api_server
         .wireguard
         .clone()
         .start_session(
@@ -102,11 +338,122 @@ async fn finish_login_api(
             user_data,
         )
         .await?;
-    Ok(HttpResponse::Ok().json(FinishLoginResponse {
-        session_ends_at,
+    api_server.signed_response(&FinishLoginResponse {
+        session_token: session.session_token,
+        session_ends_at: session.ends_at,
         interface,
         peer,
-    }))
+    })
+}
+
+#[actix_web::post("/api/v1/session/refresh")]
+async fn refresh_session_api(
+    req: web::HttpRequest,
+    api_server: web::Data<Arc<ApiServer>>,
+    _protocol: SupportedProtocol,
+    data: web::Json<RefreshSessionRequest>,
+) -> ApiResult {
+    check_client_public_key(&data.client_public_key)?;
+    api_server
+        .login_rate_limits()
+        .check_ip(api_server.source_ip(&req))
+        .map_err(ApiError::RateLimited)?;
+
+    let session = api_server
+        .wireguard
+        .find_session(&data.session_token)
+        .await
+        .ok_or(LoginError::UnknownSession)?;
+
+    let public_url = api_server.public_url(&req)?;
+    let user_data = match api_server
+        .oidc_login
+        .refresh_user(&public_url, &session.user_data)
+        .await
+    {
+        Ok(user_data) => user_data,
+        Err(err) => {
+            // The session is kept when the OIDC server could not be reached, it might be back
+            // before the session ends
+            if let Some(LoginError::IdpUnreachable(_)) = err.downcast_ref() {
+                warn!(
+                    "Could not refresh session of {}, keeping it: {}",
+                    session.user_data.email, err
+                );
+                return Err(err.into());
+            }
+            warn!(
+                "Refreshing session of {} failed, ending it: {}",
+                session.user_data.email, err
+            );
+            api_server.audit_log.record(
+                AuditRecord::from_session(
+                    AuditEvent::LoginDenied {
+                        reason: err.to_string(),
+                    },
+                    &session,
+                )
+                .source_ip(api_server.source_ip(&req)),
+            );
+            api_server.wireguard.end_session(&data.session_token).await;
+            return Err(LoginError::RefreshDenied(err.to_string()).into());
+        }
+    };
+    let hostname = api_server.public_hostname(&req)?;
+
+    let (session, interface, peer) = api_server
+        .wireguard
+        .renew_session(
+            &hostname,
+            &data.session_token,
+            data.client_public_key.clone(),
+            user_data,
+        )
+        .await?
+        .ok_or(LoginError::UnknownSession)?;
+    api_server.signed_response(&RefreshSessionResponse {
+        session_token: session.session_token,
+        session_ends_at: session.ends_at,
+        interface,
+        peer,
+    })
+}
+
+#[actix_web::post("/api/v1/logout")]
+async fn logout_api(
+    api_server: web::Data<Arc<ApiServer>>,
+    _protocol: SupportedProtocol,
+    data: web::Json<LogoutRequest>,
+) -> ApiResult {
+    api_server
+        .wireguard
+        .end_session(&data.session_token)
+        .await
+        .ok_or(LoginError::UnknownSession)?;
+    Ok(HttpResponse::Ok().json(LogoutResponse {}))
+}
+
+/// Redirects a plain HTTP request to the same path on the HTTPS server
+async fn redirect_to_https(req: web::HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    let host = header_value(&req, "host")
+        .unwrap_or_else(|| req.app_config().host())
+        .to_owned();
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if port.parse::<u16>().is_ok() => hostname,
+        _ => &host,
+    };
+    let authority = match **https_port {
+        443 => hostname.to_owned(),
+        port => format!("{}:{}", hostname, port),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, format!("https://{}{}", authority, path)))
+        .finish()
 }
 
 pub(crate) struct ApiServer {
@@ -114,14 +461,28 @@ pub(crate) struct ApiServer {
     oidc_login: OidcLogin,
     wireguard: Arc<Wireguard>,
     token_generator: TokenGenerator,
+    login_rate_limits: RwLock<Arc<LoginRateLimits>>,
+    signer: ResponseSigner,
+    audit_log: Arc<AuditLog>,
 }
 
 impl ApiServer {
     pub fn new(
         api_settings: ApiSettings,
         login_settings: LoginSettings,
+        rate_limit_settings: RateLimitSettings,
         wireguard: Arc<Wireguard>,
+        signer: ResponseSigner,
+        audit_log: Arc<AuditLog>,
     ) -> Result<Arc<Self>> {
+        if api_settings.tls_cert.is_some() != api_settings.tls_key.is_some() {
+            return Err(anyhow!("TLS certificate and key must be set together"));
+        }
+        if api_settings.http_redirect_port.is_some() && api_settings.tls_cert.is_none() {
+            return Err(anyhow!(
+                "Redirecting HTTP requires a TLS certificate and key"
+            ));
+        }
         let token_generator = TokenGenerator::new(chrono::Duration::from_std(
             login_settings.login_duration.into(),
         )?)?;
@@ -131,9 +492,97 @@ impl ApiServer {
             oidc_login,
             wireguard,
             token_generator,
+            signer,
+            login_rate_limits: RwLock::new(Arc::new(LoginRateLimits::new(&rate_limit_settings))),
+            audit_log,
         }))
     }
 
+    fn is_from_trusted_proxy(&self, req: &web::HttpRequest) -> bool {
+        req.peer_addr()
+            .map(|addr| is_trusted(addr.ip(), &self.api_settings.trusted_proxies))
+            .unwrap_or(false)
+    }
+
+    fn source_ip(&self, req: &web::HttpRequest) -> Option<IpAddr> {
+        let peer_ip = req.peer_addr()?.ip();
+        Some(forwarded_client_ip(
+            peer_ip,
+            header_value(req, "x-forwarded-for"),
+            &self.api_settings.trusted_proxies,
+        ))
+    }
+
+    /// The URL clients reach the API server at, X-Forwarded-* headers are only
+    /// used when the request came from a trusted proxy
+    fn public_url(&self, req: &web::HttpRequest) -> Result<Url> {
+        if let Some(public_url) = self.api_settings.public_url.as_ref() {
+            let mut public_url = public_url.clone();
+            if !public_url.path().ends_with('/') {
+                public_url.set_path(&format!("{}/", public_url.path()));
+            }
+            return Ok(public_url);
+        }
+
+        let trusted = self.is_from_trusted_proxy(req);
+        let forwarded = |name| match trusted {
+            true => header_value(req, name).and_then(|value| value.split(',').next()),
+            false => None,
+        };
+        let scheme = forwarded("x-forwarded-proto").unwrap_or_else(|| {
+            match self.api_settings.tls_cert.is_some() {
+                true => "https",
+                false => "http",
+            }
+        });
+        let host = forwarded("x-forwarded-host")
+            .or_else(|| header_value(req, "host"))
+            .ok_or_else(|| anyhow!("Request has no Host header"))?;
+        Ok(Url::parse(&format!(
+            "{}://{}/",
+            scheme.trim(),
+            host.trim()
+        ))?)
+    }
+
+    fn public_hostname(&self, req: &web::HttpRequest) -> Result<String> {
+        self.public_url(req)?
+            .host_str()
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("Public URL has no host"))
+    }
+
+    /// Responds with `body` as JSON, signed so clients can tell it came from this server
+    fn signed_response<T: Serialize>(&self, body: &T) -> ApiResult {
+        let body = serde_json::to_vec(body).map_err(anyhow::Error::from)?;
+        Ok(HttpResponse::Ok()
+            .content_type(mime::APPLICATION_JSON)
+            .insert_header((SIGNATURE_HEADER, self.signer.sign(&body)))
+            .body(body))
+    }
+
+    fn login_rate_limits(&self) -> Arc<LoginRateLimits> {
+        self.login_rate_limits
+            .read()
+            .expect("Rate limits lock is poisoned")
+            .clone()
+    }
+
+    /// Applies new login settings and rate limits, the login duration and
+    /// HTTP settings are only read on startup
+    pub async fn reload(
+        &self,
+        login_settings: LoginSettings,
+        rate_limit_settings: RateLimitSettings,
+    ) {
+        *self
+            .login_rate_limits
+            .write()
+            .expect("Rate limits lock is poisoned") =
+            Arc::new(LoginRateLimits::new(&rate_limit_settings));
+        self.oidc_login.reload(login_settings).await;
+    }
+
     fn bind_address(&self) -> String {
         format!(
             "{}:{}",
@@ -141,26 +590,236 @@ impl ApiServer {
         )
     }
 
+    fn tls_config(&self) -> Result<Option<rustls::ServerConfig>> {
+        let (cert, key) = match (&self.api_settings.tls_cert, &self.api_settings.tls_key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => return Ok(None),
+        };
+        let resolver = ReloadingCertResolver::new(cert, key)?;
+        resolver
+            .clone()
+            .watch(self.api_settings.tls_reload_interval.into());
+        Ok(Some(resolver.server_config()))
+    }
+
+    async fn run_redirect(&self) -> Result<()> {
+        let redirect_port = match self.api_settings.http_redirect_port {
+            None => return Ok(()),
+            Some(redirect_port) => redirect_port,
+        };
+        let https_port = self.api_settings.http_port;
+
+        Ok(HttpServer::new(move || {
+            App::new()
+                .wrap(Logger::default())
+                .app_data(web::Data::new(https_port))
+                .default_service(web::route().to(redirect_to_https))
+        })
+        .bind((self.api_settings.http_bind_ip, redirect_port))?
+        .run()
+        .await?)
+    }
+
+    async fn run_metrics(self: Arc<Self>) -> Result<()> {
+        let bind_address = match self.api_settings.metrics_bind_address {
+            None => return Ok(()),
+            Some(bind_address) => bind_address,
+        };
+        info!("Serving metrics on http://{}/metrics", bind_address);
+
+        Ok(HttpServer::new(move || {
+            App::new()
+                .wrap(Logger::default())
+                .app_data(web::Data::new(self.clone()))
+                .service(metrics)
+        })
+        .bind(bind_address)?
+        .run()
+        .await?)
+    }
+
     pub async fn run(self: Arc<Self>) -> Result<()> {
         let bind_address = self.bind_address();
+        let tls_config = self.tls_config()?;
+        let redirect = self.clone();
+        let metrics_server = self.clone();
+        let discovery = self.clone();
+        tokio::spawn(async move { discovery.oidc_login.keep_discovered().await });
 
-        Ok(HttpServer::new(move || {
-            let json_config = web::JsonConfig::default().error_handler(|err, _req| {
-                actix_web::error::ErrorBadRequest(json!({
-                    "message": err.to_string(),
-                }))
-            });
+        let server = HttpServer::new(move || {
+            let json_config = web::JsonConfig::default()
+                .error_handler(|err, _req| ApiError::InvalidRequest(err.to_string()).into());
 
             App::new()
+                .wrap(DefaultHeaders::new().header(PROTOCOL_HEADER, PROTOCOL_VERSION.to_string()))
                 .wrap(Logger::default())
                 .app_data(json_config)
                 .app_data(self.clone())
                 .service(finish_page)
+                .service(discovery_document)
+                .service(health_check)
+                .service(readiness_check)
                 .service(start_login_api)
                 .service(finish_login_api)
-        })
-        .bind(&bind_address)?
-        .run()
-        .await?)
+                .service(refresh_session_api)
+                .service(logout_api)
+        });
+        let server = match tls_config {
+            Some(tls_config) => server.bind_rustls(&bind_address, tls_config)?,
+            None => server.bind(&bind_address)?,
+        };
+
+        tokio::try_join!(
+            async { Ok::<_, anyhow::Error>(server.run().await?) },
+            redirect.run_redirect(),
+            metrics_server.run_metrics(),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use cablescout_api::server::{ErrorCode, ErrorResponse};
+
+    #[actix_web::post("/logout")]
+    async fn protocol_test_api(
+        _protocol: SupportedProtocol,
+        _data: web::Json<LogoutRequest>,
+    ) -> ApiResult {
+        Ok(HttpResponse::Ok().json(LogoutResponse {}))
+    }
+
+    #[tokio::test]
+    async fn test_protocol_checked_before_body() {
+        let app =
+            init_service(
+                App::new()
+                    .app_data(web::JsonConfig::default().error_handler(|err, _req| {
+                        ApiError::InvalidRequest(err.to_string()).into()
+                    }))
+                    .service(protocol_test_api),
+            )
+            .await;
+        let request = |version: Option<u32>, body: serde_json::Value| {
+            let mut request = TestRequest::post().uri("/logout").set_json(&body);
+            if let Some(version) = version {
+                request = request.insert_header((PROTOCOL_HEADER, version.to_string()));
+            }
+            request.to_request()
+        };
+        let error_code = |response| async {
+            let response: ErrorResponse = read_body_json(response).await;
+            response.code
+        };
+
+        let response = call_service(
+            &app,
+            request(
+                Some(PROTOCOL_VERSION + 1),
+                serde_json::json!({"changed": true}),
+            ),
+        )
+        .await;
+        assert_eq!(
+            error_code(response).await,
+            ErrorCode::UnsupportedProtocolVersion
+        );
+
+        let response = call_service(
+            &app,
+            request(Some(PROTOCOL_VERSION), serde_json::json!({"changed": true})),
+        )
+        .await;
+        assert_eq!(error_code(response).await, ErrorCode::InvalidRequest);
+
+        for version in [None, Some(PROTOCOL_VERSION)] {
+            let response = call_service(
+                &app,
+                request(version, serde_json::json!({"session_token": "token"})),
+            )
+            .await;
+            assert!(response.status().is_success());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_https() {
+        let location = |https_port: u16, host: &'static str, uri: &'static str| async move {
+            let app = init_service(
+                App::new()
+                    .app_data(web::Data::new(https_port))
+                    .default_service(web::route().to(redirect_to_https)),
+            )
+            .await;
+            let request = TestRequest::get()
+                .uri(uri)
+                .insert_header((header::HOST, host))
+                .to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(
+                response.status(),
+                actix_web::http::StatusCode::PERMANENT_REDIRECT
+            );
+            response
+                .headers()
+                .get(header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(
+            location(443, "vpn.example.com", "/").await,
+            "https://vpn.example.com/"
+        );
+        assert_eq!(
+            location(443, "vpn.example.com:80", "/finish-login?code=1&state=2").await,
+            "https://vpn.example.com/finish-login?code=1&state=2"
+        );
+        assert_eq!(
+            location(8443, "vpn.example.com:8080", "/api/v1/info").await,
+            "https://vpn.example.com:8443/api/v1/info"
+        );
+        assert_eq!(
+            location(8443, "[::1]:8080", "/").await,
+            "https://[::1]:8443/"
+        );
+    }
+
+    #[test]
+    fn test_forwarded_client_ip() -> Result<()> {
+        let trusted: Vec<IpNetwork> = vec!["10.0.0.0/8".parse()?];
+        let proxy: IpAddr = "10.0.0.1".parse()?;
+        let client: IpAddr = "203.0.113.7".parse()?;
+
+        // Headers from untrusted peers are ignored
+        assert_eq!(
+            forwarded_client_ip(client, Some("198.51.100.1"), &trusted),
+            client
+        );
+        assert_eq!(forwarded_client_ip(proxy, None, &trusted), proxy);
+        assert_eq!(
+            forwarded_client_ip(proxy, Some("203.0.113.7"), &trusted),
+            client
+        );
+        // Addresses set by the client, left of the first untrusted one, are ignored
+        assert_eq!(
+            forwarded_client_ip(proxy, Some("198.51.100.1, 203.0.113.7, 10.0.0.2"), &trusted),
+            client
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_client_public_key() {
+        let public_key = wg_utils::WgKeyPair::new().public_key.to_string();
+        assert!(check_client_public_key(&public_key).is_ok());
+        assert!(check_client_public_key("not a key").is_err());
+        // Valid base64, but not 32 bytes
+        assert!(check_client_public_key("a2V5").is_err());
     }
 }
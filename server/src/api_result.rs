@@ -1,34 +1,130 @@
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 use actix_web::{HttpResponse, ResponseError};
-use serde_json::json;
+use cablescout_api::server::{ErrorCode, ErrorResponse};
+use std::time::Duration;
 
 #[derive(thiserror::Error, Debug)]
 pub enum LoginError {
-    //    #[error("The ID token provided for identifying the user is wrong, please check the OIDC provider settings to make sure the client ID and secret are configured correctly")]
-//    InvalidIdToken,
+    #[error("Invalid login token, please login again")]
+    InvalidLoginToken,
+    #[error("Login took too long, please login again")]
+    LoginExpired,
+    #[error("Login was denied by the OIDC server: {0}")]
+    LoginDenied(String),
+    #[error("Email domain of {0} is not allowed to login")]
+    DomainNotAllowed(String),
+    #[error("User {0} is not a member of an allowed group")]
+    GroupNotAllowed(String),
+    #[error("Could not reach the OIDC server: {0}")]
+    IdpUnreachable(String),
+    #[error("Unknown session, it might have already expired or been logged out")]
+    UnknownSession,
+    #[error("Session could not be refreshed, please login again: {0}")]
+    RefreshDenied(String),
+    #[error("Reached the limit of {0} concurrent sessions, please logout from another device")]
+    TooManySessions(usize),
+    #[error("Reached the limit of {0} devices")]
+    TooManyDevices(usize),
+    #[error("Out of client addresses")]
+    AddressPoolExhausted,
+}
+
+impl LoginError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidLoginToken => ErrorCode::InvalidLoginToken,
+            Self::LoginExpired => ErrorCode::LoginExpired,
+            Self::LoginDenied(_) => ErrorCode::LoginDenied,
+            Self::DomainNotAllowed(_) => ErrorCode::DomainNotAllowed,
+            Self::GroupNotAllowed(_) => ErrorCode::GroupNotAllowed,
+            Self::IdpUnreachable(_) => ErrorCode::IdpUnreachable,
+            Self::UnknownSession => ErrorCode::UnknownSession,
+            Self::RefreshDenied(_) => ErrorCode::RefreshDenied,
+            Self::TooManySessions(_) => ErrorCode::TooManySessions,
+            Self::TooManyDevices(_) => ErrorCode::TooManyDevices,
+            Self::AddressPoolExhausted => ErrorCode::AddressPoolExhausted,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ApiError {
     #[error("{0}")]
-    Anyhow(#[from] anyhow::Error),
+    Anyhow(anyhow::Error),
     #[error("{0}")]
     LoginError(#[from] LoginError),
+    #[error("Too many requests, retry in {} seconds", retry_after_secs(.0))]
+    RateLimited(Duration),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    UnsupportedProtocolVersion(String),
 }
 
-impl ResponseError for ApiError {
-    fn status_code(&self) -> StatusCode {
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<LoginError>() {
+            Ok(err) => Self::LoginError(err),
+            Err(err) => Self::Anyhow(err),
+        }
+    }
+}
+
+impl ApiError {
+    pub fn code(&self) -> ErrorCode {
         match self {
-            Self::Anyhow(_) => StatusCode::SERVICE_UNAVAILABLE,
-            Self::LoginError(_) => StatusCode::UNAUTHORIZED,
+            Self::Anyhow(_) => ErrorCode::Internal,
+            Self::LoginError(err) => err.code(),
+            Self::RateLimited(_) => ErrorCode::RateLimited,
+            Self::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            Self::UnsupportedProtocolVersion(_) => ErrorCode::UnsupportedProtocolVersion,
         }
     }
+}
+
+fn retry_after_secs(retry_after: &Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.code().http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(json!({
-            "message": self.to_string(),
-        }))
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::RateLimited(retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_after_secs(retry_after)));
+        }
+        response.json(ErrorResponse {
+            code: self.code(),
+            message: self.to_string(),
+        })
     }
 }
 
 pub type ApiResult = Result<HttpResponse, ApiError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_error_codes() {
+        let err = ApiError::from(anyhow::Error::from(LoginError::DomainNotAllowed(
+            "user@example.org".to_owned(),
+        )));
+        assert_eq!(err.code(), ErrorCode::DomainNotAllowed);
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+
+        let err = ApiError::from(anyhow!("Something broke"));
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let err = ApiError::RateLimited(Duration::from_millis(1500));
+        assert_eq!(err.status_code(), StatusCode::TOO_MANY_REQUESTS);
+        let response = err.error_response();
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+    }
+}
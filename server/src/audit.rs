@@ -0,0 +1,204 @@
+use crate::login::UserData;
+use crate::sessions::{Session, SessionEvent, SessionEventKind};
+use anyhow::Result;
+use chrono::prelude::*;
+use log::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct AuditSettings {
+    /// File to append the audit log to, one JSON record per line.
+    /// Use "-" to write the audit log to stdout. When not set, no audit log is written.
+    #[structopt(long, env = "AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum AuditEvent {
+    LoginStarted,
+    LoginSucceeded,
+    LoginDenied { reason: String },
+    SessionCreated,
+    SessionRenewed,
+    SessionExpired,
+    SessionRevoked,
+    PeerAdded,
+    PeerRemoved,
+    AddressAssigned,
+}
+
+impl From<SessionEventKind> for AuditEvent {
+    fn from(kind: SessionEventKind) -> Self {
+        match kind {
+            SessionEventKind::Created => Self::SessionCreated,
+            SessionEventKind::Renewed => Self::SessionRenewed,
+            SessionEventKind::Expired => Self::SessionExpired,
+            SessionEventKind::Revoked => Self::SessionRevoked,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditRecord {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: AuditEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_address: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_ends_at: Option<DateTime<Utc>>,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            event,
+            email: None,
+            sub: None,
+            device_id: None,
+            public_key_fingerprint: None,
+            client_address: None,
+            source_ip: None,
+            session_ends_at: None,
+        }
+    }
+
+    pub fn from_session(event: AuditEvent, session: &Session<UserData>) -> Self {
+        Self::new(event)
+            .user(&session.user_data)
+            .device_id(session.device_id)
+            .public_key(&session.client_public_key)
+            .client_address(session.client_address)
+            .session_ends_at(session.ends_at)
+    }
+
+    pub fn user(mut self, user_data: &UserData) -> Self {
+        self.email = Some(user_data.email.clone());
+        self.sub = user_data.sub.clone();
+        self
+    }
+
+    pub fn device_id(mut self, device_id: Uuid) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    pub fn public_key(mut self, public_key: &str) -> Self {
+        self.public_key_fingerprint = Some(public_key_fingerprint(public_key));
+        self
+    }
+
+    pub fn client_address(mut self, client_address: IpAddr) -> Self {
+        self.client_address = Some(client_address);
+        self
+    }
+
+    pub fn source_ip(mut self, source_ip: Option<IpAddr>) -> Self {
+        self.source_ip = source_ip;
+        self
+    }
+
+    pub fn session_ends_at(mut self, session_ends_at: DateTime<Utc>) -> Self {
+        self.session_ends_at = Some(session_ends_at);
+        self
+    }
+}
+
+/// Short, non-secret identifier of a WireGuard public key, in the form `SHA256:<base64>`
+pub(crate) fn public_key_fingerprint(public_key: &str) -> String {
+    let key_bytes = base64::decode(public_key).unwrap_or_else(|_| public_key.as_bytes().to_vec());
+    format!(
+        "SHA256:{}",
+        base64::encode_config(Sha256::digest(&key_bytes), base64::STANDARD_NO_PAD)
+    )
+}
+
+type AuditWriter = Box<dyn Write + Send>;
+
+pub(crate) struct AuditLog {
+    writer: Option<Mutex<AuditWriter>>,
+}
+
+impl AuditLog {
+    pub fn new(settings: AuditSettings) -> Result<Arc<Self>> {
+        let writer: Option<AuditWriter> = match settings.audit_log {
+            None => None,
+            Some(path) if path.to_str() == Some("-") => {
+                info!("Writing audit log to stdout");
+                Some(Box::new(LineWriter::new(std::io::stdout())))
+            }
+            Some(path) => {
+                info!("Writing audit log to {:?}", path);
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Box::new(LineWriter::new(file)))
+            }
+        };
+        Ok(Arc::new(Self {
+            writer: writer.map(Mutex::new),
+        }))
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        let writer = match self.writer.as_ref() {
+            None => return,
+            Some(writer) => writer,
+        };
+        let result = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut writer = writer.lock().expect("Audit log lock is poisoned");
+                writeln!(writer, "{}", line)?;
+                Ok(())
+            });
+        if let Err(err) = result {
+            error!("Could not write audit record {:?}: {}", record, err);
+        }
+    }
+
+    /// Records every change to client sessions
+    pub fn watch_sessions(
+        self: Arc<Self>,
+        mut events: broadcast::Receiver<SessionEvent<UserData>>,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if event.kind == SessionEventKind::Created {
+                            self.record(AuditRecord::from_session(
+                                AuditEvent::AddressAssigned,
+                                &event.session,
+                            ));
+                        }
+                        self.record(AuditRecord::from_session(event.kind.into(), &event.session));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        error!("Audit log missed {} session events", count);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
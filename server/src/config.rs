@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+const CONFIG_FLAG: &str = "--config";
+const CONFIG_ENV: &str = "CONFIG_FILE";
+
+/// Finds the configuration file given with `--config` or in the `CONFIG_FILE` environment variable
+fn config_file_path(args: &[String]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix(&format!("{}=", CONFIG_FLAG)) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+fn value_to_string(key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Datetime(d) => Ok(d.to_string()),
+        _ => Err(anyhow!("Unsupported value for {}: {}", key, value)),
+    }
+}
+
+/// Turns the keys of a configuration file into command line arguments, skipping
+/// settings that were given on the command line or through the environment.
+/// Keys are named like the command line options, e.g. `oidc_server` or `oidc-server`.
+fn config_file_args(
+    config: &toml::value::Table,
+    cli_args: &[String],
+    is_env_set: impl Fn(&str) -> bool,
+) -> Result<Vec<String>> {
+    let mut args = vec![];
+
+    for (key, value) in config.iter() {
+        let flag = format!("--{}", key.replace('_', "-"));
+        let env = key.replace('-', "_").to_uppercase();
+        let on_command_line = cli_args
+            .iter()
+            .any(|arg| arg == &flag || arg.starts_with(&format!("{}=", flag)));
+        if on_command_line || is_env_set(&env) {
+            continue;
+        }
+
+        match value {
+            toml::Value::Boolean(true) => args.push(flag),
+            toml::Value::Boolean(false) => (),
+            toml::Value::Array(values) => {
+                for value in values.iter() {
+                    args.push(flag.clone());
+                    args.push(value_to_string(key, value)?);
+                }
+            }
+            value => {
+                args.push(flag);
+                args.push(value_to_string(key, value)?);
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+fn read_config_file(path: &Path) -> Result<toml::value::Table> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&raw)?)
+}
+
+/// Parses options from the command line, environment and optional configuration file,
+/// in this order of precedence
+pub(crate) fn load_options<T>() -> Result<T>
+where
+    T: StructOpt,
+{
+    let mut args: Vec<String> = std::env::args().collect();
+
+    if let Some(path) = config_file_path(&args) {
+        let config = read_config_file(&path)
+            .with_context(|| format!("Could not read configuration file {:?}", path))?;
+        let file_args =
+            config_file_args(&config, &args[1..], |env| std::env::var_os(env).is_some())
+                .with_context(|| format!("Invalid configuration file {:?}", path))?;
+        args.extend(file_args);
+    }
+
+    Ok(T::from_iter_safe(args)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_args() -> Result<()> {
+        let config: toml::value::Table = toml::from_str(
+            r#"
+            debug = true
+            oidc_server = "https://accounts.example.com"
+            wg-port = 51821
+            wg_additional_networks = ["10.0.0.0/8", "192.168.0.0/16"]
+            email_domain = "example.com"
+            wg_mtu = 1400
+            "#,
+        )?;
+        let cli_args = vec!["--email-domain".to_owned(), "example.org".to_owned()];
+
+        let args = config_file_args(&config, &cli_args, |env| env == "WG_MTU")?;
+        assert_eq!(
+            args,
+            vec![
+                "--debug",
+                "--oidc-server",
+                "https://accounts.example.com",
+                "--wg-port",
+                "51821",
+                "--wg-additional-networks",
+                "10.0.0.0/8",
+                "--wg-additional-networks",
+                "192.168.0.0/16",
+            ]
+        );
+
+        let config: toml::value::Table = toml::from_str("wg_client_keepalive = { s = 1 }")?;
+        assert!(config_file_args(&config, &[], |_| false).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use actix_web::HttpResponse;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HealthStatus {
+    Ok,
+    Unavailable,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CheckResult {
+    status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl From<Result<()>> for CheckResult {
+    fn from(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Self {
+                status: HealthStatus::Ok,
+                message: None,
+            },
+            Err(err) => Self {
+                status: HealthStatus::Unavailable,
+                message: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+/// Status of the server along with the result of each check that went into it
+#[derive(Debug, Serialize)]
+pub(crate) struct HealthReport {
+    status: HealthStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+impl HealthReport {
+    pub fn new() -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            checks: Default::default(),
+        }
+    }
+
+    pub fn check(mut self, name: &'static str, result: Result<()>) -> Self {
+        let result = CheckResult::from(result);
+        if result.status != HealthStatus::Ok {
+            self.status = HealthStatus::Unavailable;
+        }
+        self.checks.insert(name, result);
+        self
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        match self.status {
+            HealthStatus::Ok => HttpResponse::Ok().json(self),
+            HealthStatus::Unavailable => HttpResponse::ServiceUnavailable().json(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_health_report() -> Result<()> {
+        let report = HealthReport::new().check("first", Ok(()));
+        assert_eq!(report.status, HealthStatus::Ok);
+
+        let report = report.check("second", Err(anyhow!("Broken")));
+        assert_eq!(report.status, HealthStatus::Unavailable);
+        assert_eq!(
+            serde_json::to_value(&report)?,
+            serde_json::json!({
+                "status": "unavailable",
+                "checks": {
+                    "first": { "status": "ok" },
+                    "second": { "status": "unavailable", "message": "Broken" },
+                },
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::tokens::random_bytes;
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::io::Write;
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use wg_utils::{PrivateKey, WgKeyPair};
+use zeroize::Zeroizing;
+
+const SIGNING_SEED_BYTES: usize = 32;
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct KeySettings {
+    /// File holding the WireGuard private key of the server, created if missing.
+    /// Without it a new key is generated on every start, which breaks clients pinning the key.
+    #[structopt(long, env = "WG_PRIVATE_KEY_FILE")]
+    wg_private_key_file: Option<PathBuf>,
+
+    /// File holding the key used to sign login responses, created if missing.
+    /// Without it a new key is generated on every start, which breaks clients pinning the key.
+    #[structopt(long, env = "SIGNING_KEY_FILE")]
+    signing_key_file: Option<PathBuf>,
+}
+
+/// Reads a base64 secret from `path`, creating the file readable only by its owner if missing
+fn load_or_create_secret(
+    path: &Path,
+    create: impl FnOnce() -> Zeroizing<String>,
+) -> Result<Zeroizing<String>> {
+    match std::fs::read_to_string(path).map(Zeroizing::new) {
+        Ok(secret) => return Ok(Zeroizing::new(secret.trim().to_owned())),
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        Err(_) => (),
+    }
+
+    info!("Creating new key in {:?}", path);
+    let secret = create();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(target_family = "unix")]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", secret.as_str())?;
+    Ok(secret)
+}
+
+/// Signs responses carrying the server WireGuard configuration, so clients
+/// pinning the public key can detect responses that did not come from this server
+pub(crate) struct ResponseSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ResponseSigner {
+    fn from_seed(seed: &str) -> Result<Self> {
+        let seed = Zeroizing::new(base64::decode(seed)?);
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|err| anyhow!("Invalid signing key: {}", err))?;
+        Ok(Self { key_pair })
+    }
+
+    /// Base64 of the public key clients verify signatures with
+    pub fn public_key(&self) -> String {
+        base64::encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Base64 of the signature of `body`
+    pub fn sign(&self, body: &[u8]) -> String {
+        base64::encode(self.key_pair.sign(body).as_ref())
+    }
+}
+
+pub(crate) struct ServerKeys {
+    pub wg_key_pair: WgKeyPair,
+    pub signer: ResponseSigner,
+}
+
+impl ServerKeys {
+    pub fn load(settings: &KeySettings) -> Result<Self> {
+        let wg_key_pair = match settings.wg_private_key_file.as_ref() {
+            None => {
+                warn!("No WireGuard private key file, clients pinning the server key will fail after a restart");
+                WgKeyPair::new()
+            }
+            Some(path) => {
+                let private_key =
+                    load_or_create_secret(path, || PrivateKey::generate().to_base64())?;
+                let private_key = private_key
+                    .parse()
+                    .with_context(|| format!("Invalid WireGuard private key in {:?}", path))?;
+                WgKeyPair::from_private_key(private_key)
+            }
+        };
+
+        let new_seed = || Zeroizing::new(base64::encode(random_bytes::<SIGNING_SEED_BYTES>()));
+        let seed = match settings.signing_key_file.as_ref() {
+            None => {
+                warn!("No signing key file, clients pinning the signing key will fail after a restart");
+                new_seed()
+            }
+            Some(path) => load_or_create_secret(path, new_seed)?,
+        };
+        let signer = ResponseSigner::from_seed(&seed).context("Could not load signing key")?;
+        info!("Signing responses with public key {}", signer.public_key());
+
+        Ok(Self {
+            wg_key_pair,
+            signer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    #[test]
+    fn test_sign_response() {
+        let seed = base64::encode(random_bytes::<SIGNING_SEED_BYTES>());
+        let signer = ResponseSigner::from_seed(&seed).unwrap();
+        let body = br#"{"session_token":"abc"}"#;
+        let signature = base64::decode(signer.sign(body)).unwrap();
+        let public_key = base64::decode(signer.public_key()).unwrap();
+
+        let verifier = UnparsedPublicKey::new(&ED25519, &public_key);
+        assert!(verifier.verify(body, &signature).is_ok());
+        assert!(verifier.verify(b"{}", &signature).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+const DEFAULT_DEVICE_REGISTRATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct LimitSettings {
+    /// Maximum number of concurrent sessions for a single user, unlimited when not set
+    #[structopt(long, env = "MAX_SESSIONS_PER_USER")]
+    max_sessions_per_user: Option<usize>,
+
+    /// Maximum number of devices a single user can login from, unlimited when not set
+    #[structopt(long, env = "MAX_DEVICES_PER_USER")]
+    max_devices_per_user: Option<usize>,
+
+    /// How long a device counts towards the device limit of its user after its last
+    /// login or session refresh. Logging out from a device removes it right away.
+    #[structopt(long, env = "DEVICE_REGISTRATION_DURATION", default_value = "30d")]
+    device_registration_duration: humantime::Duration,
+
+    /// Limits for members of a group, replacing the limits above. Formatted as
+    /// `group=sessions:devices`, where an empty number means unlimited, e.g. `admins=5:`.
+    /// Users in more than one of these groups get the highest limits.
+    #[structopt(long, env = "GROUP_LIMITS")]
+    group_limits: Vec<GroupLimits>,
+
+    /// What to do when a user reaches a limit, either "refuse" the login
+    /// or "evict-oldest" session or device of that user
+    #[structopt(long, env = "LIMIT_POLICY", default_value = "refuse")]
+    limit_policy: LimitPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LimitPolicy {
+    Refuse,
+    EvictOldest,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "refuse" => Ok(Self::Refuse),
+            "evict-oldest" => Ok(Self::EvictOldest),
+            _ => Err(anyhow!("Unknown limit policy: {}", s)),
+        }
+    }
+}
+
+/// Limits applied to a single user, `None` means unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Limits {
+    pub max_sessions: Option<usize>,
+    pub max_devices: Option<usize>,
+    pub policy: LimitPolicy,
+    /// How long a device without a session stays registered
+    pub device_registration: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_sessions: None,
+            max_devices: None,
+            policy: LimitPolicy::Refuse,
+            device_registration: DEFAULT_DEVICE_REGISTRATION,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GroupLimits {
+    group: String,
+    max_sessions: Option<usize>,
+    max_devices: Option<usize>,
+}
+
+impl FromStr for GroupLimits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_limit = |limit: &str| -> Result<Option<usize>> {
+            match limit.trim() {
+                "" => Ok(None),
+                limit => Ok(Some(limit.parse()?)),
+            }
+        };
+        let (group, limits) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Group limits must look like group=sessions:devices"))?;
+        let (max_sessions, max_devices) = limits
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Group limits must look like group=sessions:devices"))?;
+        Ok(Self {
+            group: group.trim().to_owned(),
+            max_sessions: parse_limit(max_sessions)?,
+            max_devices: parse_limit(max_devices)?,
+        })
+    }
+}
+
+/// The most permissive of two limits, where `None` is unlimited
+fn most_permissive(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a?.max(b?))
+}
+
+impl LimitSettings {
+    pub fn for_groups(&self, groups: &[String]) -> Limits {
+        let mut matching = self
+            .group_limits
+            .iter()
+            .filter(|group_limits| groups.contains(&group_limits.group));
+
+        let (max_sessions, max_devices) = match matching.next() {
+            None => (self.max_sessions_per_user, self.max_devices_per_user),
+            Some(first) => matching.fold(
+                (first.max_sessions, first.max_devices),
+                |(max_sessions, max_devices), group_limits| {
+                    (
+                        most_permissive(max_sessions, group_limits.max_sessions),
+                        most_permissive(max_devices, group_limits.max_devices),
+                    )
+                },
+            ),
+        };
+
+        Limits {
+            max_sessions,
+            max_devices,
+            policy: self.limit_policy,
+            device_registration: self.device_registration_duration.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_limits() -> Result<()> {
+        let settings = LimitSettings {
+            max_sessions_per_user: Some(1),
+            max_devices_per_user: Some(2),
+            group_limits: vec!["admins=5:".parse()?, "staff=3:4".parse()?],
+            limit_policy: LimitPolicy::Refuse,
+            device_registration_duration: DEFAULT_DEVICE_REGISTRATION.into(),
+        };
+
+        let limits = settings.for_groups(&["users".to_owned()]);
+        assert_eq!(limits.max_sessions, Some(1));
+        assert_eq!(limits.max_devices, Some(2));
+
+        let limits = settings.for_groups(&["staff".to_owned()]);
+        assert_eq!(limits.max_sessions, Some(3));
+        assert_eq!(limits.max_devices, Some(4));
+
+        let limits = settings.for_groups(&["staff".to_owned(), "admins".to_owned()]);
+        assert_eq!(limits.max_sessions, Some(5));
+        assert_eq!(limits.max_devices, None);
+
+        assert!("admins".parse::<GroupLimits>().is_err());
+        assert!("admins=x:1".parse::<GroupLimits>().is_err());
+        Ok(())
+    }
+}
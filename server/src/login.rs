@@ -1,9 +1,42 @@
+use crate::api_result::LoginError;
+use crate::sessions::SessionUser;
 use anyhow::{anyhow, Result};
 use email_address_parser::EmailAddress;
-use openid::DiscoveredClient;
+use log::*;
+use openid::biscuit::jwk::JWKSet;
+use openid::{Bearer, Claims, CompactJson, CustomClaims, Discovered, StandardClaims, Token};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use tokio::sync::RwLock;
 use url::Url;
 
+const SCOPE: &str = "openid profile email offline_access";
+
+/// How long to wait before discovering the OIDC server again after failing to
+const DISCOVERY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// ID token claims, with the non-standard `groups` claim most providers can be configured to add
+#[derive(Debug, Serialize, Deserialize)]
+struct OidcClaims {
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(flatten)]
+    standard_claims: StandardClaims,
+}
+
+impl CustomClaims for OidcClaims {
+    fn standard_claims(&self) -> &StandardClaims {
+        &self.standard_claims
+    }
+}
+
+impl CompactJson for OidcClaims {}
+
+type OidcClient = openid::Client<Discovered, OidcClaims>;
+
 #[derive(Debug, Clone, StructOpt)]
 pub(crate) struct LoginSettings {
     /// OIDC server
@@ -22,48 +55,181 @@ pub(crate) struct LoginSettings {
     #[structopt(long, env = "EMAIL_DOMAIN")]
     pub email_domain: String,
 
+    /// Groups allowed to login, taken from the `groups` claim of the ID token.
+    /// When empty, users are not checked for group membership.
+    #[structopt(long, env = "ALLOWED_GROUPS")]
+    pub allowed_groups: Vec<String>,
+
     /// Login duration, sets how long it might take between when a user
     /// starts the login process and until the moment they post their credentials
     /// back into the server for getting connection information.
     #[structopt(long, env = "LOGIN_DURATION", default_value = "2m")]
     pub login_duration: humantime::Duration,
+
+    /// How long the OIDC server discovery document and keys are cached before fetching them again
+    #[structopt(long, env = "OIDC_DISCOVERY_TTL", default_value = "1h")]
+    pub oidc_discovery_ttl: humantime::Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct UserData {
-    email: String,
+    pub email: String,
+    pub sub: Option<String>,
+    pub groups: Vec<String>,
+    nonce: String,
+    bearer: Bearer,
+}
+
+/// Leaves out the nonce and tokens, user data ends up in logs with the sessions holding it
+impl fmt::Debug for UserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserData")
+            .field("email", &self.email)
+            .field("sub", &self.sub)
+            .field("groups", &self.groups)
+            .field("nonce", &"<redacted>")
+            .field("bearer", &"<redacted>")
+            .finish()
+    }
+}
+
+struct CachedClient {
+    client: Arc<OidcClient>,
+    discovered_at: Instant,
+}
+
+impl SessionUser for UserData {
+    fn user_id(&self) -> &str {
+        &self.email
+    }
+}
+
+/// Tells apart OIDC servers that could not be reached from ones that refused the login
+fn oidc_error(err: openid::error::Error) -> LoginError {
+    use openid::error::{ClientError, Error};
+
+    match err {
+        Error::Http(_)
+        | Error::ClientError(ClientError::Reqwest(_))
+        | Error::ClientError(ClientError::Io(_)) => LoginError::IdpUnreachable(err.to_string()),
+        err => LoginError::LoginDenied(err.to_string()),
+    }
 }
 
 pub(crate) struct OidcLogin {
-    settings: LoginSettings,
+    settings: std::sync::RwLock<Arc<LoginSettings>>,
+    /// Client of the configured OIDC server, discovered without a redirect URL so a single
+    /// discovery serves every public URL the server is reached with
+    discovered: RwLock<Option<CachedClient>>,
 }
 
 impl OidcLogin {
     pub fn new(settings: LoginSettings) -> Self {
-        Self { settings }
+        Self {
+            settings: std::sync::RwLock::new(Arc::new(settings)),
+            discovered: Default::default(),
+        }
     }
 
-    async fn client(&self, conn: &actix_web::dev::ConnectionInfo) -> Result<DiscoveredClient> {
-        let redirect = Url::parse(&format!("{}://{}/finish", conn.scheme(), conn.host()))?;
-        Ok(DiscoveredClient::discover(
-            self.settings.oidc_client_id.clone(),
-            self.settings.oidc_client_secret.clone(),
+    fn settings(&self) -> Arc<LoginSettings> {
+        self.settings
+            .read()
+            .expect("Login settings lock is poisoned")
+            .clone()
+    }
+
+    /// Replaces the login settings, forgetting the discovered client in case the OIDC server has changed
+    pub async fn reload(&self, settings: LoginSettings) {
+        *self
+            .settings
+            .write()
+            .expect("Login settings lock is poisoned") = Arc::new(settings);
+        *self.discovered.write().await = None;
+        if let Err(err) = self.discovered(Duration::ZERO).await {
+            warn!("Could not discover OIDC server: {}", err);
+        }
+    }
+
+    /// Keeps the OIDC server discovered, discovering it again halfway through the TTL
+    /// so logins and readiness checks never find the discovery expired
+    pub async fn keep_discovered(&self) {
+        loop {
+            let refresh_after = self.settings().oidc_discovery_ttl.as_ref().div_f64(2.0);
+            let wait = match self.discovered(refresh_after).await {
+                Ok(_) => refresh_after,
+                Err(err) => {
+                    warn!("Could not discover OIDC server: {}", err);
+                    DISCOVERY_RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait.max(DISCOVERY_RETRY_INTERVAL)).await;
+        }
+    }
+
+    /// Checks the OIDC server was discovered within the TTL, without discovering it
+    pub async fn check_discovery(&self) -> Result<()> {
+        let ttl = self.settings().oidc_discovery_ttl;
+        match self.discovered.read().await.as_ref() {
+            Some(cached) if cached.discovered_at.elapsed() < *ttl => Ok(()),
+            Some(_) => Err(anyhow!("OIDC server discovery is older than {}", ttl)),
+            None => Err(anyhow!("OIDC server was not discovered yet")),
+        }
+    }
+
+    /// Client of the OIDC server, discovering it again when the cached one is older than `max_age`
+    async fn discovered(&self, max_age: Duration) -> Result<Arc<OidcClient>> {
+        let settings = self.settings();
+
+        if let Some(cached) = self.discovered.read().await.as_ref() {
+            if cached.discovered_at.elapsed() < max_age {
+                return Ok(cached.client.clone());
+            }
+        }
+
+        debug!("Discovering OIDC server {}", settings.oidc_server);
+        let client = Arc::new(
+            OidcClient::discover(
+                settings.oidc_client_id.clone(),
+                settings.oidc_client_secret.clone(),
+                None,
+                settings.oidc_server.clone(),
+            )
+            .await
+            .map_err(|err| LoginError::IdpUnreachable(err.to_string()))?,
+        );
+        *self.discovered.write().await = Some(CachedClient {
+            client: client.clone(),
+            discovered_at: Instant::now(),
+        });
+        Ok(client)
+    }
+
+    /// Client of the OIDC server redirecting users back to `public_url`
+    async fn client(&self, public_url: &Url) -> Result<OidcClient> {
+        let redirect = public_url.join("finish")?;
+        let discovered = self.discovered(*self.settings().oidc_discovery_ttl).await?;
+        Ok(OidcClient::new(
+            discovered.provider.clone(),
+            discovered.client_id.clone(),
+            discovered.client_secret.clone(),
             Some(redirect.to_string()),
-            self.settings.oidc_server.clone(),
-        )
-        .await?)
+            discovered.http_client.clone(),
+            discovered.jwks.as_ref().map(|jwks| JWKSet {
+                keys: jwks.keys.clone(),
+            }),
+        ))
     }
 
     pub async fn get_auth_url(
         &self,
-        conn: &actix_web::dev::ConnectionInfo,
+        public_url: &Url,
         login_token: &str,
         nonce: &str,
     ) -> Result<Url> {
-        let client = self.client(conn).await?;
+        let client = self.client(public_url).await?;
 
         let options = openid::Options {
-            scope: Some("openid profile email".to_owned()),
+            scope: Some(SCOPE.to_owned()),
             nonce: Some(nonce.to_owned()),
             state: Some(login_token.to_owned()),
             ..Default::default()
@@ -74,22 +240,131 @@ impl OidcLogin {
 
     pub async fn validate_user(
         &self,
-        conn: &actix_web::dev::ConnectionInfo,
+        public_url: &Url,
         auth_code: &str,
         nonce: &str,
     ) -> Result<UserData> {
-        let client = self.client(conn).await?;
-        let token = client.authenticate(auth_code, Some(nonce), None).await?;
-        let userinfo = client.request_userinfo(&token).await?;
-        let email = userinfo
-            .email
-            .ok_or_else(|| anyhow!("Login succeeded but user has no email address"))?;
-        let email_address = EmailAddress::parse(&email, None)
-            .ok_or_else(|| anyhow!("Login succeeded but could not parse user email address"))?;
-        if email_address.get_domain() == self.settings.email_domain {
-            Ok(UserData { email })
-        } else {
-            Err(anyhow!("Wrong email domain: {}", email))
+        let client = self.client(public_url).await?;
+        let token = client
+            .authenticate(auth_code, Some(nonce), None)
+            .await
+            .map_err(oidc_error)?;
+        self.user_data(&client, token, nonce, None).await
+    }
+
+    /// Uses the refresh token received while logging in to check the user with the
+    /// OIDC server again, failing if the user can no longer login.
+    pub async fn refresh_user(&self, public_url: &Url, user_data: &UserData) -> Result<UserData> {
+        if user_data.bearer.refresh_token.is_none() {
+            return Err(LoginError::RefreshDenied(
+                "OIDC server did not provide a refresh token".to_owned(),
+            )
+            .into());
+        }
+        let client = self.client(public_url).await?;
+        let bearer = client
+            .refresh_token(user_data.bearer.clone(), Some(SCOPE))
+            .await
+            .map_err(|err| oidc_error(err.into()))?;
+        let mut token: Token<OidcClaims> = bearer.into();
+        if let Some(id_token) = token.id_token.as_mut() {
+            client.decode_token(id_token)?;
+            // Providers are not required to repeat the nonce in refreshed ID tokens
+            let nonce = id_token
+                .payload()?
+                .nonce()
+                .map(|_| user_data.nonce.as_str());
+            client.validate_token(id_token, nonce, None)?;
+        }
+        self.user_data(&client, token, &user_data.nonce, Some(user_data))
+            .await
+    }
+
+    async fn user_data(
+        &self,
+        client: &OidcClient,
+        token: Token<OidcClaims>,
+        nonce: &str,
+        previous: Option<&UserData>,
+    ) -> Result<UserData> {
+        let userinfo = client.request_userinfo(&token).await.map_err(oidc_error)?;
+        let sub = userinfo.sub;
+        let email = userinfo.email.ok_or_else(|| {
+            LoginError::LoginDenied("Login succeeded but user has no email address".to_owned())
+        })?;
+        let email_address = EmailAddress::parse(&email, None).ok_or_else(|| {
+            LoginError::LoginDenied(
+                "Login succeeded but could not parse user email address".to_owned(),
+            )
+        })?;
+        let settings = self.settings();
+        if email_address.get_domain() != settings.email_domain {
+            return Err(LoginError::DomainNotAllowed(email).into());
+        }
+
+        let groups = match (token.id_token.as_ref(), previous) {
+            (Some(id_token), _) => id_token.payload()?.groups.clone(),
+            (None, Some(previous)) => previous.groups.clone(),
+            (None, None) => vec![],
+        };
+        if !settings.allowed_groups.is_empty()
+            && !groups
+                .iter()
+                .any(|group| settings.allowed_groups.contains(group))
+        {
+            return Err(LoginError::GroupNotAllowed(email).into());
+        }
+
+        Ok(UserData {
+            email,
+            sub,
+            groups,
+            nonce: nonce.to_owned(),
+            bearer: token.bearer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_env_log::test;
+
+    #[test(tokio::test)]
+    async fn test_check_discovery_does_not_discover() -> Result<()> {
+        let login = OidcLogin::new(LoginSettings {
+            oidc_server: "http://127.0.0.1:9/".parse()?,
+            oidc_client_id: "client".to_owned(),
+            oidc_client_secret: "secret".to_owned(),
+            email_domain: "example.com".to_owned(),
+            allowed_groups: vec![],
+            login_duration: "2m".parse()?,
+            oidc_discovery_ttl: "1h".parse()?,
+        });
+        assert!(login.check_discovery().await.is_err());
+        assert!(login.discovered.read().await.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_data_debug_is_redacted() {
+        let user_data = UserData {
+            email: "user@example.com".to_owned(),
+            sub: None,
+            groups: vec![],
+            nonce: "nonce-value".to_owned(),
+            bearer: Bearer {
+                access_token: "access-token".to_owned(),
+                scope: None,
+                refresh_token: Some("refresh-token".to_owned()),
+                expires: None,
+                id_token: Some("id-token".to_owned()),
+            },
+        };
+        let debug = format!("{:?}", user_data);
+        assert!(debug.contains("user@example.com"));
+        for secret in ["nonce-value", "access-token", "refresh-token", "id-token"] {
+            assert!(!debug.contains(secret), "{} is in {}", secret, debug);
         }
     }
 }
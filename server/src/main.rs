@@ -1,14 +1,30 @@
 mod api;
 mod api_result;
+mod audit;
+mod config;
+mod health;
+mod keys;
+mod limits;
 mod login;
+mod metrics;
+mod rate_limit;
 mod sessions;
+mod tls;
 mod tokens;
+mod webhooks;
 mod wireguard;
 
 use crate::api::ApiServer;
+use crate::audit::AuditLog;
+use crate::keys::ServerKeys;
+use crate::webhooks::Webhooks;
 use crate::wireguard::Wireguard;
 use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
+#[cfg(target_family = "unix")]
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Debug, StructOpt)]
 struct Options {
@@ -16,27 +32,102 @@ struct Options {
     #[structopt(short, long)]
     debug: bool,
 
+    /// TOML configuration file, with keys named like the options here, e.g. `oidc_server = "..."`.
+    /// Options given on the command line or in environment variables override the file.
+    /// The file is read again on SIGHUP, applying login policy, limits and client configuration changes.
+    #[structopt(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+
     #[structopt(flatten)]
     api: api::ApiSettings,
 
     #[structopt(flatten)]
     login: login::LoginSettings,
 
+    #[structopt(flatten)]
+    rate_limits: rate_limit::RateLimitSettings,
+
     #[structopt(flatten)]
     wireguard: wireguard::WireguardSettings,
+
+    #[structopt(flatten)]
+    keys: keys::KeySettings,
+
+    #[structopt(flatten)]
+    limits: limits::LimitSettings,
+
+    #[structopt(flatten)]
+    audit: audit::AuditSettings,
+
+    #[structopt(flatten)]
+    webhooks: webhooks::WebhookSettings,
+}
+
+impl Options {
+    fn load() -> Result<Self> {
+        let options: Self = config::load_options()?;
+        options.wireguard.validate()?;
+        Ok(options)
+    }
+}
+
+#[cfg(target_family = "unix")]
+async fn reload_on_hangup(wireguard: Arc<Wireguard>, api: Arc<ApiServer>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        log::info!("Got SIGHUP, reloading configuration");
+        match Options::load() {
+            Ok(options) => {
+                wireguard.reload(options.wireguard, options.limits);
+                api.reload(options.login, options.rate_limits).await;
+            }
+            Err(err) => log::error!("Not reloading, configuration is invalid: {:?}", err),
+        }
+    }
+    Ok(())
 }
 
 async fn _main(options: Options) -> Result<()> {
-    let wireguard = Wireguard::new(options.wireguard).await?;
+    if let Some(config) = options.config.as_ref() {
+        log::info!("Loaded configuration file {:?}", config);
+    }
+    let audit_log = AuditLog::new(options.audit)?;
+    let webhooks = Webhooks::new(options.webhooks)?;
+    let keys = ServerKeys::load(&options.keys)?;
+    let wireguard = Wireguard::new(
+        options.wireguard,
+        options.limits,
+        keys.wg_key_pair,
+        audit_log.clone(),
+        webhooks,
+    )?;
     wireguard.clone().run();
-    let api = ApiServer::new(options.api, options.login, wireguard)?;
+    let api = ApiServer::new(
+        options.api,
+        options.login,
+        options.rate_limits,
+        wireguard.clone(),
+        keys.signer,
+        audit_log,
+    )?;
+    #[cfg(target_family = "unix")]
+    tokio::spawn(reload_on_hangup(wireguard, api.clone()));
     api.run().await?;
     Ok(())
 }
 
 #[actix_web::main]
 async fn main() {
-    let options = Options::from_args();
+    let options = match Options::load() {
+        Ok(options) => options,
+        Err(err) => match err.downcast::<structopt::clap::Error>() {
+            Ok(err) => err.exit(),
+            Err(err) => {
+                eprintln!("Error: {:?}", err);
+                std::process::exit(1);
+            }
+        },
+    };
     let default_level = match options.debug {
         true => log::LevelFilter::Debug,
         false => log::LevelFilter::Info,
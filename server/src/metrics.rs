@@ -0,0 +1,96 @@
+use actix_web::HttpResponse;
+use std::fmt::{Display, Write};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Metrics in the Prometheus text format
+pub(crate) struct MetricsReport {
+    text: String,
+}
+
+impl MetricsReport {
+    pub fn new() -> Self {
+        Self {
+            text: String::new(),
+        }
+    }
+
+    fn header(mut self, kind: &str, name: &str, help: &str) -> Self {
+        // Writing to a String can't fail
+        let _ = writeln!(self.text, "# HELP cablescout_{} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE cablescout_{} {}", name, kind);
+        self
+    }
+
+    fn metric(self, kind: &str, name: &str, help: &str, value: impl Display) -> Self {
+        let mut report = self.header(kind, name, help);
+        let _ = writeln!(report.text, "cablescout_{} {}", name, value);
+        report
+    }
+
+    /// Value that can go up and down
+    pub fn gauge(self, name: &str, help: &str, value: impl Display) -> Self {
+        self.metric("gauge", name, help, value)
+    }
+
+    /// Value that only goes up, until the server restarts
+    pub fn counter(self, name: &str, help: &str, value: impl Display) -> Self {
+        self.metric("counter", name, help, value)
+    }
+
+    /// Counter with a value for each value of `label`
+    pub fn labelled_counter<'a, V: Display>(
+        self,
+        name: &str,
+        help: &str,
+        label: &str,
+        values: impl IntoIterator<Item = (&'a str, V)>,
+    ) -> Self {
+        let mut report = self.header("counter", name, help);
+        for (label_value, value) in values {
+            let _ = writeln!(
+                report.text,
+                "cablescout_{}{{{}=\"{}\"}} {}",
+                name, label, label_value, value
+            );
+        }
+        report
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type(CONTENT_TYPE)
+            .body(self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_report() {
+        let report = MetricsReport::new()
+            .gauge("peers", "Number of peers", 3)
+            .counter("received_bytes_total", "Bytes received", 1024)
+            .labelled_counter(
+                "changes_total",
+                "Changes made",
+                "change",
+                [("added", 2), ("removed", 0)],
+            );
+        assert_eq!(
+            report.text,
+            "# HELP cablescout_peers Number of peers\n\
+             # TYPE cablescout_peers gauge\n\
+             cablescout_peers 3\n\
+             # HELP cablescout_received_bytes_total Bytes received\n\
+             # TYPE cablescout_received_bytes_total counter\n\
+             cablescout_received_bytes_total 1024\n\
+             # HELP cablescout_changes_total Changes made\n\
+             # TYPE cablescout_changes_total counter\n\
+             cablescout_changes_total{change=\"added\"} 2\n\
+             cablescout_changes_total{change=\"removed\"} 0\n"
+        );
+    }
+}
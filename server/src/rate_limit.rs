@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use uuid::Uuid;
+
+/// Most keys tracked at once, full buckets and then the least recently used ones are
+/// forgotten beyond it
+const MAX_TRACKED_KEYS: usize = 10000;
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct RateLimitSettings {
+    /// Login requests allowed per minute from a single source IP
+    #[structopt(long, env = "LOGIN_RATE_LIMIT_PER_IP", default_value = "30")]
+    login_rate_limit_per_ip: u32,
+
+    /// Login requests allowed per minute for a single device
+    #[structopt(long, env = "LOGIN_RATE_LIMIT_PER_DEVICE", default_value = "10")]
+    login_rate_limit_per_device: u32,
+
+    /// Number of login requests allowed in a burst before the rate limits apply
+    #[structopt(long, env = "LOGIN_RATE_BURST", default_value = "5")]
+    login_rate_burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token bucket rate limiter, keeping a separate bucket for every key
+pub(crate) struct RateLimiter<K> {
+    per_second: f64,
+    burst: f64,
+    max_keys: usize,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Hash + Eq,
+{
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self::with_max_keys(per_minute, burst, MAX_TRACKED_KEYS)
+    }
+
+    fn with_max_keys(per_minute: u32, burst: u32, max_keys: usize) -> Self {
+        Self {
+            per_second: f64::from(per_minute) / 60.0,
+            burst: f64::from(burst.max(1)),
+            max_keys: max_keys.max(1),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Makes room for a new key. Evicts down to 90% of the limit so the scans over all
+    /// buckets happen once every many new keys rather than on each of them.
+    fn evict(&self, buckets: &mut HashMap<K, Bucket>, now: Instant) {
+        let (per_second, burst) = (self.per_second, self.burst);
+        buckets.retain(|_, bucket| {
+            bucket.tokens
+                + now
+                    .saturating_duration_since(bucket.updated_at)
+                    .as_secs_f64()
+                    * per_second
+                < burst
+        });
+
+        let keep = self.max_keys - 1 - self.max_keys / 10;
+        if buckets.len() > keep {
+            let mut updated_at: Vec<Instant> =
+                buckets.values().map(|bucket| bucket.updated_at).collect();
+            let evicted = updated_at.len() - keep;
+            let (_, cutoff, _) = updated_at.select_nth_unstable(evicted - 1);
+            let cutoff = *cutoff;
+            buckets.retain(|_, bucket| bucket.updated_at > cutoff);
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, returning how long to wait if it is empty
+    pub fn check(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock is poisoned");
+
+        if buckets.len() >= self.max_keys && !buckets.contains_key(&key) {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if self.per_second > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+}
+
+pub(crate) struct LoginRateLimits {
+    by_ip: RateLimiter<IpAddr>,
+    by_device: RateLimiter<Uuid>,
+}
+
+impl LoginRateLimits {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        Self {
+            by_ip: RateLimiter::new(settings.login_rate_limit_per_ip, settings.login_rate_burst),
+            by_device: RateLimiter::new(
+                settings.login_rate_limit_per_device,
+                settings.login_rate_burst,
+            ),
+        }
+    }
+
+    pub fn check_ip(&self, source_ip: Option<IpAddr>) -> Result<(), Duration> {
+        match source_ip {
+            None => Ok(()),
+            Some(source_ip) => self.by_ip.check(source_ip, Instant::now()),
+        }
+    }
+
+    pub fn check_device(&self, device_id: Uuid) -> Result<(), Duration> {
+        self.by_device.check(device_id, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_rate() {
+        let limiter = RateLimiter::new(60, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", start), Ok(()));
+        }
+        let retry_after = limiter.check("a", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // Other keys have their own buckets
+        assert_eq!(limiter.check("b", start), Ok(()));
+
+        // One token per second is added back
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check("a", later), Ok(()));
+        assert!(limiter.check("a", later).is_err());
+
+        // Buckets never hold more than the burst
+        let much_later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", much_later), Ok(()));
+        }
+        assert!(limiter.check("a", much_later).is_err());
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let limiter = RateLimiter::with_max_keys(1, 1, 10);
+        let start = Instant::now();
+        let tracked = || limiter.buckets.lock().unwrap().len();
+
+        for key in 0..10 {
+            let now = start + Duration::from_millis(key);
+            assert_eq!(limiter.check(key, now), Ok(()));
+        }
+        assert_eq!(tracked(), 10);
+        // Known keys don't evict others
+        assert!(limiter.check(9, start + Duration::from_millis(9)).is_err());
+        assert_eq!(tracked(), 10);
+
+        // None of the buckets refilled, the least recently used ones go
+        let now = start + Duration::from_millis(10);
+        assert_eq!(limiter.check(10, now), Ok(()));
+        assert_eq!(tracked(), 9);
+        assert!(limiter.check(9, now).is_err());
+        assert_eq!(limiter.check(0, now), Ok(()));
+
+        for key in 11..1000 {
+            let now = start + Duration::from_millis(key);
+            assert_eq!(limiter.check(key, now), Ok(()));
+            assert!(tracked() <= 10);
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::api_result::LoginError;
+use crate::limits::{LimitPolicy, Limits};
+use crate::tokens::random_string;
 use anyhow::{anyhow, Result};
 use chrono::prelude::*;
 use ipnetwork::{IpNetwork, IpNetworkError};
@@ -5,14 +8,17 @@ use log::*;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::select;
-use tokio::sync::{Notify, RwLock};
+use tokio::sync::{broadcast, Notify, RwLock};
 use tokio::time;
 use uuid::Uuid;
 use wg_utils::WireguardPeer;
 
+const SESSION_TOKEN_LENGTH: usize = 32;
+const SESSION_EVENTS_CAPACITY: usize = 1024;
+
 pub fn ip_address_as_ip_network(ip: IpAddr) -> Result<IpNetwork, IpNetworkError> {
     IpNetwork::new(
         ip,
@@ -23,6 +29,11 @@ pub fn ip_address_as_ip_network(ip: IpAddr) -> Result<IpNetwork, IpNetworkError>
     )
 }
 
+/// Identifies the user a session belongs to, for applying per-user limits
+pub(crate) trait SessionUser {
+    fn user_id(&self) -> &str;
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Session<U>
 where
@@ -33,6 +44,7 @@ where
     pub(crate) device_id: Uuid,
     pub(crate) client_public_key: String,
     pub(crate) client_address: IpAddr,
+    pub(crate) session_token: String,
 }
 
 impl<U> TryFrom<&Session<U>> for WireguardPeer
@@ -44,6 +56,7 @@ where
     fn try_from(session: &Session<U>) -> Result<Self, Self::Error> {
         Ok(Self {
             public_key: session.client_public_key.clone(),
+            preshared_key: None,
             allowed_ips: vec![ip_address_as_ip_network(session.client_address)?],
             endpoint: None,
             persistent_keepalive: None,
@@ -51,6 +64,32 @@ where
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SessionEventKind {
+    Created,
+    Renewed,
+    Expired,
+    Revoked,
+}
+
+/// Device a user logged in from, counting towards their device limit until it expires
+#[derive(Debug, Clone)]
+struct RegisteredDevice {
+    device_id: Uuid,
+    /// Last login or session refresh from the device
+    last_seen: DateTime<Utc>,
+}
+
+/// Sent to subscribers of a `SessionManager` whenever one of its sessions changes
+#[derive(Debug, Clone)]
+pub(crate) struct SessionEvent<U>
+where
+    U: Send,
+{
+    pub(crate) kind: SessionEventKind,
+    pub(crate) session: Session<U>,
+}
+
 pub(crate) struct SessionManager<U>
 where
     U: Send,
@@ -59,11 +98,15 @@ where
     session_duration: chrono::Duration,
     sessions: RwLock<HashMap<Uuid, Session<U>>>,
     notify: Arc<Notify>,
+    events: broadcast::Sender<SessionEvent<U>>,
+    /// Devices each user has logged in from, least recently used first. Devices stay
+    /// registered after their session ends, until they expire or the user logs out.
+    devices: Mutex<HashMap<String, Vec<RegisteredDevice>>>,
 }
 
 impl<U> SessionManager<U>
 where
-    U: Send + Sync + Clone + 'static,
+    U: SessionUser + Send + Sync + Clone + 'static,
 {
     pub fn new(client_network: IpNetwork, session_duration: chrono::Duration) -> Arc<Self> {
         Arc::new(Self {
@@ -71,6 +114,8 @@ where
             session_duration,
             sessions: Default::default(),
             notify: Default::default(),
+            events: broadcast::channel(SESSION_EVENTS_CAPACITY).0,
+            devices: Default::default(),
         })
     }
 
@@ -82,6 +127,26 @@ where
         self.notify.clone()
     }
 
+    /// Fails if the sessions can't be locked within `timeout`, e.g. when a lock is held for too long
+    pub async fn check_available(&self, timeout: std::time::Duration) -> Result<()> {
+        tokio::time::timeout(timeout, self.sessions.read())
+            .await
+            .map(|_| ())
+            .map_err(|_| anyhow!("Timed out waiting for the session store"))
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent<U>> {
+        self.events.subscribe()
+    }
+
+    fn send_event(&self, kind: SessionEventKind, session: &Session<U>) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(SessionEvent {
+            kind,
+            session: session.clone(),
+        });
+    }
+
     /// The first address in the client network is reserved for the server
     pub fn server_address(&self) -> IpAddr {
         self.client_network
@@ -90,26 +155,39 @@ where
             .expect("Client network is too small")
     }
 
+    fn next_ends_at(&self) -> Result<DateTime<Utc>> {
+        Utc::now()
+            .checked_add_signed(self.session_duration)
+            .ok_or_else(|| anyhow!("Overflow while calculating session end time"))
+    }
+
     pub async fn create(
         &self,
         device_id: Uuid,
         client_public_key: String,
         user_data: U,
+        limits: &Limits,
     ) -> Result<Session<U>> {
         let mut sessions = self.sessions.write().await;
 
-        let ends_at = Utc::now()
-            .checked_add_signed(self.session_duration)
-            .ok_or_else(|| anyhow!("Overflow while calculating session end time"))?;
+        let ends_at = self.next_ends_at()?;
 
-        let session = if let Some(session) = sessions.get_mut(&device_id) {
+        let user_id = user_data.user_id().to_owned();
+        if !sessions.contains_key(&device_id) {
+            self.make_room_for_session(&mut sessions, &user_id, limits)?;
+        }
+        self.register_device(&mut sessions, &user_id, device_id, limits)?;
+
+        let (kind, session) = if let Some(session) = sessions.get_mut(&device_id) {
             info!(
                 "Updating existing session of device {} to end at {}",
                 device_id, ends_at
             );
             session.ends_at = ends_at;
+            session.user_data = user_data;
             session.client_public_key = client_public_key;
-            session.clone()
+            session.session_token = random_string::<SESSION_TOKEN_LENGTH>();
+            (SessionEventKind::Renewed, session.clone())
         } else {
             info!(
                 "Creating new session for device {}, ends at {}",
@@ -124,7 +202,7 @@ where
                 .client_network
                 .iter()
                 .find(|ip| Some(ip) != addresses_in_use.next())
-                .ok_or_else(|| anyhow!("Out of client addresses"))?;
+                .ok_or(LoginError::AddressPoolExhausted)?;
 
             let session = Session {
                 ends_at,
@@ -132,29 +210,244 @@ where
                 device_id,
                 client_public_key,
                 client_address,
+                session_token: random_string::<SESSION_TOKEN_LENGTH>(),
             };
 
             sessions.insert(device_id, session.clone());
-            session
+            (SessionEventKind::Created, session)
         };
 
+        self.send_event(kind, &session);
         self.notify.notify_waiters();
         Ok(session)
     }
 
+    fn evict(&self, sessions: &mut HashMap<Uuid, Session<U>>, device_id: &Uuid) {
+        if let Some(session) = sessions.remove(device_id) {
+            info!(
+                "Evicted session of device {}, releasing {}",
+                device_id, session.client_address
+            );
+            self.send_event(SessionEventKind::Revoked, &session);
+        }
+    }
+
+    /// Removes the device of `session` after the user logged out from it, so it stops
+    /// counting towards the device limit of its user
+    fn deregister_device(&self, session: &Session<U>) {
+        let mut devices = self.devices.lock().expect("Devices lock is poisoned");
+        let user_id = session.user_data.user_id();
+        if let Some(user_devices) = devices.get_mut(user_id) {
+            user_devices.retain(|device| device.device_id != session.device_id);
+            if user_devices.is_empty() {
+                devices.remove(user_id);
+            }
+        }
+    }
+
+    /// Marks the device of `session` as used now, postponing its expiry
+    fn touch_device(&self, session: &Session<U>) {
+        let mut devices = self.devices.lock().expect("Devices lock is poisoned");
+        if let Some(device) =
+            devices
+                .get_mut(session.user_data.user_id())
+                .and_then(|user_devices| {
+                    user_devices
+                        .iter_mut()
+                        .find(|device| device.device_id == session.device_id)
+                })
+        {
+            device.last_seen = Utc::now();
+        }
+    }
+
+    fn register_device(
+        &self,
+        sessions: &mut HashMap<Uuid, Session<U>>,
+        user_id: &str,
+        device_id: Uuid,
+        limits: &Limits,
+    ) -> Result<()> {
+        let mut devices = self.devices.lock().expect("Devices lock is poisoned");
+        let now = Utc::now();
+
+        // Devices without a session expire a while after they were last used
+        let registration = chrono::Duration::from_std(limits.device_registration)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        for user_devices in devices.values_mut() {
+            user_devices.retain(|device| {
+                sessions.contains_key(&device.device_id)
+                    || now.signed_duration_since(device.last_seen) < registration
+            });
+        }
+
+        let user_devices = devices.entry(user_id.to_owned()).or_default();
+        let mut forgotten = vec![];
+        if !user_devices
+            .iter()
+            .any(|device| device.device_id == device_id)
+        {
+            if let Some(max_devices) = limits.max_devices {
+                while user_devices.len() >= max_devices {
+                    if limits.policy == LimitPolicy::Refuse || user_devices.is_empty() {
+                        return Err(LoginError::TooManyDevices(max_devices).into());
+                    }
+                    let oldest = user_devices.remove(0).device_id;
+                    info!(
+                        "User {} reached the limit of {} devices, forgetting device {}",
+                        user_id, max_devices, oldest
+                    );
+                    forgotten.push(oldest);
+                }
+            }
+        }
+        user_devices.retain(|device| device.device_id != device_id);
+        user_devices.push(RegisteredDevice {
+            device_id,
+            last_seen: now,
+        });
+
+        // A device belongs to the last user that logged in from it
+        for (other_user_id, other_devices) in devices.iter_mut() {
+            if other_user_id != user_id {
+                other_devices.retain(|device| device.device_id != device_id);
+            }
+        }
+        devices.retain(|_, user_devices| !user_devices.is_empty());
+        drop(devices);
+
+        for oldest in forgotten.iter() {
+            self.evict(sessions, oldest);
+        }
+        Ok(())
+    }
+
+    fn make_room_for_session(
+        &self,
+        sessions: &mut HashMap<Uuid, Session<U>>,
+        user_id: &str,
+        limits: &Limits,
+    ) -> Result<()> {
+        let max_sessions = match limits.max_sessions {
+            None => return Ok(()),
+            Some(max_sessions) => max_sessions,
+        };
+        loop {
+            let user_sessions: Vec<(DateTime<Utc>, Uuid)> = sessions
+                .values()
+                .filter(|session| session.user_data.user_id() == user_id)
+                .map(|session| (session.ends_at, session.device_id))
+                .collect();
+            if user_sessions.len() < max_sessions {
+                return Ok(());
+            }
+            match (limits.policy, user_sessions.iter().min()) {
+                (LimitPolicy::EvictOldest, Some((_, oldest))) => {
+                    info!(
+                        "User {} reached the limit of {} sessions",
+                        user_id, max_sessions
+                    );
+                    self.evict(sessions, oldest);
+                }
+                _ => return Err(LoginError::TooManySessions(max_sessions).into()),
+            }
+        }
+    }
+
+    pub async fn find(&self, session_token: &str) -> Option<Session<U>> {
+        self.sessions
+            .read()
+            .await
+            .values()
+            .find(|session| session.session_token == session_token)
+            .cloned()
+    }
+
+    /// Extends the session identified by `session_token`, replacing its client key and token
+    pub async fn renew(
+        &self,
+        session_token: &str,
+        client_public_key: String,
+        user_data: U,
+    ) -> Result<Option<Session<U>>> {
+        let mut sessions = self.sessions.write().await;
+
+        let ends_at = self.next_ends_at()?;
+        let session = match sessions
+            .values_mut()
+            .find(|session| session.session_token == session_token)
+        {
+            None => return Ok(None),
+            Some(session) => session,
+        };
+        info!(
+            "Renewing session of device {} to end at {}",
+            session.device_id, ends_at
+        );
+        session.ends_at = ends_at;
+        session.user_data = user_data;
+        session.client_public_key = client_public_key;
+        session.session_token = random_string::<SESSION_TOKEN_LENGTH>();
+        let session = session.clone();
+        self.touch_device(&session);
+
+        self.send_event(SessionEventKind::Renewed, &session);
+        self.notify.notify_waiters();
+        Ok(Some(session))
+    }
+
+    /// Removes the session identified by `session_token`, releasing its client address
+    /// and deregistering its device
+    pub async fn revoke(&self, session_token: &str) -> Option<Session<U>> {
+        let mut sessions = self.sessions.write().await;
+
+        let device_id = sessions
+            .values()
+            .find(|session| session.session_token == session_token)
+            .map(|session| session.device_id)?;
+        let session = sessions.remove(&device_id)?;
+        info!(
+            "Revoked session of device {}, releasing {}",
+            device_id, session.client_address
+        );
+        self.deregister_device(&session);
+
+        self.send_event(SessionEventKind::Revoked, &session);
+        self.notify.notify_waiters();
+        Some(session)
+    }
+
+    /// Removes the session of the client using `client_public_key`, which stopped being active
+    pub async fn expire_idle(&self, client_public_key: &str) -> Option<Session<U>> {
+        let mut sessions = self.sessions.write().await;
+
+        let device_id = sessions
+            .values()
+            .find(|session| session.client_public_key == client_public_key)
+            .map(|session| session.device_id)?;
+        let session = sessions.remove(&device_id)?;
+        info!(
+            "Session of device {} is idle, releasing {}",
+            device_id, session.client_address
+        );
+
+        self.send_event(SessionEventKind::Expired, &session);
+        self.notify.notify_waiters();
+        Some(session)
+    }
+
     pub async fn get_peers(&self) -> Result<Vec<WireguardPeer>> {
-        Ok(self
-            .sessions
+        self.sessions
             .read()
             .await
             .values()
             .map(WireguardPeer::try_from)
-            .collect::<Result<_>>()?)
+            .collect()
     }
 
     async fn next_expiring_session(self: Arc<Self>) -> Option<DateTime<Utc>> {
         let sessions = self.sessions.read().await;
-        sessions.values().map(|session| session.ends_at).max()
+        sessions.values().map(|session| session.ends_at).min()
     }
 
     async fn expire_old_sessions(self: Arc<Self>) {
@@ -176,16 +469,31 @@ where
             select! {
                 _ = notify.notified() => {
                     // Restart loop to calculate the next session to expire
-                    break;
+                    continue;
                 }
 
                 _ = timeout => {
                     debug!("Removing old sessions");
                     let mut sessions = self.sessions.write().await;
-                    let len_before = sessions.len();
                     let now = Utc::now();
-                    sessions.retain(|_, session| session.ends_at >= now);
-                    info!("Removed {} sessions", sessions.len() - len_before);
+                    let expired: Vec<Uuid> = sessions
+                        .values()
+                        .filter(|session| session.ends_at < now)
+                        .map(|session| session.device_id)
+                        .collect();
+                    for device_id in expired.iter() {
+                        if let Some(session) = sessions.remove(device_id) {
+                            info!(
+                                "Session of device {} expired, releasing {}",
+                                device_id, session.client_address
+                            );
+                            self.send_event(SessionEventKind::Expired, &session);
+                        }
+                    }
+                    info!("Removed {} sessions", expired.len());
+                    if !expired.is_empty() {
+                        self.notify.notify_waiters();
+                    }
                 }
             }
         }
@@ -197,9 +505,15 @@ mod tests {
     use super::*;
     use test_env_log::test;
 
-    #[derive(Clone)]
+    #[derive(Debug, Clone)]
     struct TestUserData {}
 
+    impl SessionUser for TestUserData {
+        fn user_id(&self) -> &str {
+            "user"
+        }
+    }
+
     type TestSessionManager = Arc<SessionManager<TestUserData>>;
 
     fn create_session_manager() -> Result<TestSessionManager> {
@@ -216,13 +530,23 @@ mod tests {
 
         let device_id1 = Uuid::new_v4();
         let session1 = manager
-            .create(device_id1, "key1".to_owned(), TestUserData {})
+            .create(
+                device_id1,
+                "key1".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
             .await?;
         assert_eq!(session1.client_address, "192.168.1.2".parse::<IpAddr>()?);
 
         let device_id2 = Uuid::new_v4();
         let session2 = manager
-            .create(device_id2, "key2".to_owned(), TestUserData {})
+            .create(
+                device_id2,
+                "key2".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
             .await?;
         assert_eq!(session2.client_address, "192.168.1.3".parse::<IpAddr>()?);
         Ok(())
@@ -235,12 +559,311 @@ mod tests {
 
         let device_id = Uuid::new_v4();
         let session1 = manager
-            .create(device_id, "key1".to_owned(), TestUserData {})
+            .create(
+                device_id,
+                "key1".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
             .await?;
         assert_eq!(session1.client_address, "192.168.1.2".parse::<IpAddr>()?);
 
         let session2 = manager
-            .create(device_id, "key2".to_owned(), TestUserData {})
+            .create(
+                device_id,
+                "key2".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
+            .await?;
+        assert_eq!(session2.client_address, session1.client_address);
+        assert_ne!(session2.session_token, session1.session_token);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_renew_session() -> Result<()> {
+        let manager = create_session_manager()?;
+
+        let session1 = manager
+            .create(
+                Uuid::new_v4(),
+                "key1".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
+            .await?;
+        assert!(manager
+            .renew("no-such-token", "key2".to_owned(), TestUserData {})
+            .await?
+            .is_none());
+
+        let session2 = manager
+            .renew(&session1.session_token, "key2".to_owned(), TestUserData {})
+            .await?
+            .expect("Session was not renewed");
+        assert_eq!(session2.device_id, session1.device_id);
+        assert_eq!(session2.client_address, session1.client_address);
+        assert_eq!(session2.client_public_key, "key2");
+        assert!(session2.ends_at >= session1.ends_at);
+        assert!(manager.find(&session1.session_token).await.is_none());
+        assert!(manager.find(&session2.session_token).await.is_some());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_events() -> Result<()> {
+        let manager = create_session_manager()?;
+        let mut events = manager.subscribe();
+
+        let device_id = Uuid::new_v4();
+        manager
+            .create(
+                device_id,
+                "key1".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
+            .await?;
+        let session = manager
+            .create(
+                device_id,
+                "key2".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
+            .await?;
+        manager.revoke(&session.session_token).await;
+
+        for expected in [
+            SessionEventKind::Created,
+            SessionEventKind::Renewed,
+            SessionEventKind::Revoked,
+        ] {
+            let event = events.recv().await?;
+            assert_eq!(event.kind, expected);
+            assert_eq!(event.session.device_id, device_id);
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_expire_idle() -> Result<()> {
+        let manager = create_session_manager()?;
+        let session = manager
+            .create(
+                Uuid::new_v4(),
+                "key1".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
+            .await?;
+
+        assert!(manager.expire_idle("key2").await.is_none());
+        assert!(manager.expire_idle("key1").await.is_some());
+        assert!(manager.find(&session.session_token).await.is_none());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_limits() -> Result<()> {
+        let manager = create_session_manager()?;
+        let limits = Limits {
+            max_sessions: Some(1),
+            max_devices: Some(2),
+            policy: LimitPolicy::Refuse,
+            ..Default::default()
+        };
+        let (device_id1, device_id2, device_id3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        manager
+            .create(device_id1, "key1".to_owned(), TestUserData {}, &limits)
+            .await?;
+        let err = manager
+            .create(device_id2, "key2".to_owned(), TestUserData {}, &limits)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LoginError>(),
+            Some(LoginError::TooManySessions(1))
+        ));
+
+        // Logging in again from the same device is not a new session
+        let session1 = manager
+            .create(device_id1, "key3".to_owned(), TestUserData {}, &limits)
+            .await?;
+        manager.revoke(&session1.session_token).await;
+
+        manager
+            .create(device_id2, "key4".to_owned(), TestUserData {}, &limits)
+            .await?;
+        let device_limits = Limits {
+            max_sessions: None,
+            ..limits
+        };
+        manager
+            .create(
+                device_id1,
+                "key5".to_owned(),
+                TestUserData {},
+                &device_limits,
+            )
+            .await?;
+        let err = manager
+            .create(
+                device_id3,
+                "key5".to_owned(),
+                TestUserData {},
+                &device_limits,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LoginError>(),
+            Some(LoginError::TooManyDevices(2))
+        ));
+
+        let evict_limits = Limits {
+            policy: LimitPolicy::EvictOldest,
+            ..limits
+        };
+        manager
+            .create(
+                device_id1,
+                "key6".to_owned(),
+                TestUserData {},
+                &evict_limits,
+            )
+            .await?;
+        let session3 = manager
+            .create(
+                device_id3,
+                "key7".to_owned(),
+                TestUserData {},
+                &evict_limits,
+            )
+            .await?;
+        let peers = manager.get_peers().await?;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].public_key, "key7");
+        assert_eq!(session3.client_address, session1.client_address);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_device_limit_after_logout() -> Result<()> {
+        let manager = create_session_manager()?;
+        let limits = Limits {
+            max_devices: Some(1),
+            ..Default::default()
+        };
+
+        let session1 = manager
+            .create(Uuid::new_v4(), "key1".to_owned(), TestUserData {}, &limits)
+            .await?;
+        assert!(manager
+            .create(Uuid::new_v4(), "key2".to_owned(), TestUserData {}, &limits)
+            .await
+            .is_err());
+
+        // Logging out deregisters the device, so a new one can log in
+        manager.revoke(&session1.session_token).await;
+        manager
+            .create(Uuid::new_v4(), "key3".to_owned(), TestUserData {}, &limits)
+            .await?;
+        assert_eq!(
+            manager.devices.lock().expect("Devices lock is poisoned")["user"].len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_devices_outlive_sessions() -> Result<()> {
+        let manager = create_session_manager()?;
+        let limits = Limits {
+            max_devices: Some(2),
+            ..Default::default()
+        };
+        let device_count = || {
+            manager
+                .devices
+                .lock()
+                .expect("Devices lock is poisoned")
+                .get("user")
+                .map_or(0, Vec::len)
+        };
+
+        manager
+            .create(Uuid::new_v4(), "key1".to_owned(), TestUserData {}, &limits)
+            .await?;
+        manager.expire_idle("key1").await;
+        let session2 = manager
+            .create(Uuid::new_v4(), "key2".to_owned(), TestUserData {}, &limits)
+            .await?;
+        assert_eq!(manager.get_peers().await?.len(), 1);
+        assert_eq!(device_count(), 2);
+
+        // The idle device is still registered, so a third one is refused
+        let err = manager
+            .create(Uuid::new_v4(), "key3".to_owned(), TestUserData {}, &limits)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LoginError>(),
+            Some(LoginError::TooManyDevices(2))
+        ));
+
+        // Until its registration expires, while the device with a session is kept
+        let expiring_limits = Limits {
+            device_registration: Duration::from_secs(0),
+            ..limits
+        };
+        manager
+            .create(
+                Uuid::new_v4(),
+                "key3".to_owned(),
+                TestUserData {},
+                &expiring_limits,
+            )
+            .await?;
+        assert_eq!(manager.get_peers().await?.len(), 2);
+        assert_eq!(device_count(), 2);
+        assert!(manager.find(&session2.session_token).await.is_some());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_revoke_session() -> Result<()> {
+        let manager = create_session_manager()?;
+
+        let device_id1 = Uuid::new_v4();
+        let session1 = manager
+            .create(
+                device_id1,
+                "key1".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
+            .await?;
+        assert!(manager.revoke("no-such-token").await.is_none());
+
+        let revoked = manager
+            .revoke(&session1.session_token)
+            .await
+            .expect("Session was not revoked");
+        assert_eq!(revoked.device_id, device_id1);
+        assert!(manager.get_peers().await?.is_empty());
+        assert!(manager.revoke(&session1.session_token).await.is_none());
+
+        // The released address is handed out again
+        let session2 = manager
+            .create(
+                Uuid::new_v4(),
+                "key2".to_owned(),
+                TestUserData {},
+                &Limits::default(),
+            )
             .await?;
         assert_eq!(session2.client_address, session1.client_address);
         Ok(())
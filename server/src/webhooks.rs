@@ -0,0 +1,388 @@
+use crate::login::UserData;
+use crate::sessions::{SessionEvent, SessionEventKind};
+use anyhow::Result;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use serde::Serialize;
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+use url::Url;
+use uuid::Uuid;
+
+const SIGNATURE_HEADER: &str = "X-Cablescout-Signature";
+const TIMESTAMP_HEADER: &str = "X-Cablescout-Timestamp";
+const EVENT_HEADER: &str = "X-Cablescout-Event";
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct WebhookSettings {
+    /// URLs to POST a JSON payload to whenever a session is created, renewed, expired or revoked
+    #[structopt(long, env = "WEBHOOK_URLS")]
+    webhook_urls: Vec<Url>,
+
+    /// Secret for signing webhook payloads, the HMAC-SHA256 of the X-Cablescout-Timestamp
+    /// header, a "." and the payload is sent in the X-Cablescout-Signature header
+    #[structopt(long, env = "WEBHOOK_SECRET")]
+    webhook_secret: Option<String>,
+
+    /// How many times to retry delivering a webhook before giving up
+    #[structopt(long, env = "WEBHOOK_RETRIES", default_value = "3")]
+    webhook_retries: u32,
+
+    /// Delay before the first retry, doubled for every retry after it
+    #[structopt(long, env = "WEBHOOK_RETRY_DELAY", default_value = "1s")]
+    webhook_retry_delay: humantime::Duration,
+
+    /// Timeout for each webhook request
+    #[structopt(long, env = "WEBHOOK_TIMEOUT", default_value = "10s")]
+    webhook_timeout: humantime::Duration,
+
+    /// Maximum number of events waiting to be delivered to each URL, events are dropped
+    /// while the queue is full
+    #[structopt(long, env = "WEBHOOK_QUEUE_SIZE", default_value = "100")]
+    webhook_queue_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) enum WebhookEvent {
+    #[serde(rename = "session.created")]
+    Created,
+    #[serde(rename = "session.renewed")]
+    Renewed,
+    #[serde(rename = "session.expired")]
+    Expired,
+    #[serde(rename = "session.revoked")]
+    Revoked,
+}
+
+impl From<SessionEventKind> for WebhookEvent {
+    fn from(kind: SessionEventKind) -> Self {
+        match kind {
+            SessionEventKind::Created => Self::Created,
+            SessionEventKind::Renewed => Self::Renewed,
+            SessionEventKind::Expired => Self::Expired,
+            SessionEventKind::Revoked => Self::Revoked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WebhookSession {
+    device_id: Uuid,
+    email: String,
+    client_address: IpAddr,
+    ends_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WebhookPayload {
+    event: WebhookEvent,
+    timestamp: DateTime<Utc>,
+    session: WebhookSession,
+}
+
+impl From<SessionEvent<UserData>> for WebhookPayload {
+    fn from(event: SessionEvent<UserData>) -> Self {
+        Self {
+            event: event.kind.into(),
+            timestamp: Utc::now(),
+            session: WebhookSession {
+                device_id: event.session.device_id,
+                email: event.session.user_data.email,
+                client_address: event.session.client_address,
+                ends_at: event.session.ends_at,
+            },
+        }
+    }
+}
+
+pub(crate) struct Webhooks {
+    /// Delivery queue of every URL, so a URL that is down doesn't hold back the others
+    queues: Vec<(Url, mpsc::Sender<WebhookPayload>)>,
+}
+
+struct WebhookSender {
+    url: Url,
+    settings: Arc<WebhookSettings>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(settings: WebhookSettings) -> Result<Arc<Self>> {
+        if settings.webhook_urls.is_empty() {
+            return Ok(Arc::new(Self { queues: vec![] }));
+        }
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .timeout(settings.webhook_timeout.into())
+            .build()?;
+        let settings = Arc::new(settings);
+        let queues = settings
+            .webhook_urls
+            .iter()
+            .map(|url| {
+                let (queue, queue_rx) = mpsc::channel(settings.webhook_queue_size);
+                let sender = WebhookSender {
+                    url: url.clone(),
+                    settings: settings.clone(),
+                    client: client.clone(),
+                };
+                tokio::spawn(sender.run(queue_rx));
+                (url.clone(), queue)
+            })
+            .collect();
+
+        Ok(Arc::new(Self { queues }))
+    }
+
+    pub fn send(&self, payload: WebhookPayload) {
+        for (url, queue) in self.queues.iter() {
+            if let Err(err) = queue.try_send(payload.clone()) {
+                warn!("Dropping webhook event for {}: {}", url, err);
+            }
+        }
+    }
+
+    /// Sends a webhook for every change to client sessions
+    pub fn watch_sessions(
+        self: Arc<Self>,
+        mut events: broadcast::Receiver<SessionEvent<UserData>>,
+    ) {
+        if self.queues.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => self.send(event.into()),
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        error!("Webhooks missed {} session events", count);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+impl WebhookSender {
+    async fn run(self, mut queue: mpsc::Receiver<WebhookPayload>) {
+        while let Some(payload) = queue.recv().await {
+            let body = match serde_json::to_vec(&payload) {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("Could not serialize webhook payload {:?}: {}", payload, err);
+                    continue;
+                }
+            };
+            let event = serde_json::to_value(&payload.event)
+                .ok()
+                .and_then(|value| value.as_str().map(str::to_owned))
+                .unwrap_or_default();
+            self.deliver(&event, &body).await;
+        }
+    }
+
+    /// Signs the timestamp along with the body, so receivers can reject replayed requests
+    fn signature(&self, timestamp: &str, body: &[u8]) -> Option<String> {
+        let secret = self.settings.webhook_secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        Some(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+
+    async fn post(&self, event: &str, body: &[u8]) -> Result<()> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event)
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .body(body.to_vec());
+        if let Some(signature) = self.signature(&timestamp, body) {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn deliver(&self, event: &str, body: &[u8]) {
+        let url = &self.url;
+        let mut delay: Duration = self.settings.webhook_retry_delay.into();
+        for attempt in 0..=self.settings.webhook_retries {
+            match self.post(event, body).await {
+                Ok(()) => {
+                    debug!("Delivered {} webhook to {}", event, url);
+                    return;
+                }
+                Err(err) if attempt < self.settings.webhook_retries => {
+                    warn!(
+                        "Delivering {} webhook to {} failed, retrying in {:?}: {}",
+                        event, url, delay, err
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => {
+                    error!(
+                        "Giving up on delivering {} webhook to {}: {}",
+                        event, url, err
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_env_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[derive(Debug)]
+    struct ReceivedRequest {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    /// Accepts HTTP requests, answering each with the next status in `statuses`
+    async fn stand_in_server(
+        statuses: Vec<u16>,
+    ) -> Result<(Url, mpsc::UnboundedReceiver<ReceivedRequest>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr()?))?;
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut data = vec![];
+                let mut buf = [0u8; 4096];
+                let (head, body) = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&data).to_string();
+                    if let Some(head_end) = text.find("\r\n\r\n") {
+                        let head = text[..head_end].to_lowercase();
+                        let content_length: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map(|value| value.trim().parse().unwrap())
+                            .unwrap_or(0);
+                        if data.len() >= head_end + 4 + content_length {
+                            break (head, data[head_end + 4..].to_vec());
+                        }
+                    }
+                };
+                requests_tx.send(ReceivedRequest { head, body }).unwrap();
+                let response = format!(
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        Ok((url, requests_rx))
+    }
+
+    fn test_payload() -> WebhookPayload {
+        WebhookPayload {
+            event: WebhookEvent::Created,
+            timestamp: Utc::now(),
+            session: WebhookSession {
+                device_id: Uuid::new_v4(),
+                email: "user@example.com".to_owned(),
+                client_address: "172.25.0.2".parse().unwrap(),
+                ends_at: Utc::now(),
+            },
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_signed_delivery_with_retry() -> Result<()> {
+        let (url, mut requests) = stand_in_server(vec![500, 200]).await?;
+        let webhooks = Webhooks::new(WebhookSettings {
+            webhook_urls: vec![url],
+            webhook_secret: Some("secret".to_owned()),
+            webhook_retries: 2,
+            webhook_retry_delay: "10ms".parse()?,
+            webhook_timeout: "5s".parse()?,
+            webhook_queue_size: 10,
+        })?;
+        webhooks.send(test_payload());
+
+        let failed = requests.recv().await.expect("No webhook request");
+        let delivered = requests.recv().await.expect("Webhook was not retried");
+        assert_eq!(failed.body, delivered.body);
+        assert!(delivered.head.starts_with("post /hook "));
+        assert!(delivered
+            .head
+            .contains("x-cablescout-event: session.created"));
+
+        let payload: serde_json::Value = serde_json::from_slice(&delivered.body)?;
+        assert_eq!(payload["event"], "session.created");
+        assert_eq!(payload["session"]["email"], "user@example.com");
+
+        let timestamp = delivered
+            .head
+            .lines()
+            .find_map(|line| line.strip_prefix("x-cablescout-timestamp: "))
+            .expect("No timestamp header");
+        let sent_at = Utc.timestamp(timestamp.parse()?, 0);
+        assert!(Utc::now().signed_duration_since(sent_at) < chrono::Duration::minutes(1));
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(&delivered.body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert!(delivered
+            .head
+            .contains(&format!("x-cablescout-signature: {}", signature)));
+        Ok(())
+    }
+    #[test(tokio::test)]
+    async fn test_stuck_url_does_not_block_others() -> Result<()> {
+        // Accepts connections without ever answering them
+        let stuck = TcpListener::bind("127.0.0.1:0").await?;
+        let stuck_url = Url::parse(&format!("http://{}/hook", stuck.local_addr()?))?;
+        let (url, mut requests) = stand_in_server(vec![200, 200]).await?;
+        let webhooks = Webhooks::new(WebhookSettings {
+            webhook_urls: vec![stuck_url, url],
+            webhook_secret: None,
+            webhook_retries: 3,
+            webhook_retry_delay: "10s".parse()?,
+            webhook_timeout: "30s".parse()?,
+            webhook_queue_size: 10,
+        })?;
+        webhooks.send(test_payload());
+        webhooks.send(test_payload());
+
+        for _ in 0..2 {
+            let request = time::timeout(Duration::from_secs(5), requests.recv())
+                .await?
+                .expect("No webhook request");
+            assert!(!request.head.contains("x-cablescout-signature"));
+        }
+        drop(stuck);
+        Ok(())
+    }
+}
@@ -1,18 +1,30 @@
+use crate::audit::{AuditEvent, AuditLog, AuditRecord};
+use crate::limits::LimitSettings;
 use crate::login::UserData;
-use crate::sessions::{ip_address_as_ip_network, SessionManager};
-use anyhow::Result;
-use chrono::prelude::*;
+use crate::metrics::MetricsReport;
+use crate::sessions::{ip_address_as_ip_network, Session, SessionManager};
+use crate::webhooks::Webhooks;
+use anyhow::{anyhow, Result};
 use ipnetwork::IpNetwork;
 use log::*;
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 use uuid::Uuid;
 use wg_utils::{
-    wg_quick_up, FullWireguardInterface, WgKeyPair, WireguardConfig, WireguardInterface,
+    validate_interface_name, BackendKind, BackendOptions, FullWireguardInterface, Netns, PeerDiff,
+    PublicKey, WgKeyPair, WireguardBackend, WireguardConfig, WireguardInterface,
     WireguardInterfaceScripts, WireguardPeer,
 };
 
+/// Peers with a handshake this recent are active, WireGuard rekeys every 2 minutes while in use
+const ACTIVE_HANDSHAKE_AGE: Duration = Duration::from_secs(3 * 60);
+/// How often peers are checked for idle sessions
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, StructOpt)]
 pub(crate) struct WireguardSettings {
     /// Session duration, after which a client that was successfully
@@ -20,14 +32,16 @@ pub(crate) struct WireguardSettings {
     #[structopt(long, env = "SESSION_DURATION", default_value = "1d")]
     session_duration: humantime::Duration,
 
-    /// WireGuard server bind address, use default value to listen on all interfaces
-    #[structopt(long, env = "WG_BIND_IP", default_value = "0.0.0.0")]
-    wg_bind_ip: IpAddr,
-
     /// WireGuard server port
     #[structopt(long, env = "WG_PORT", default_value = "51820")]
     wg_port: u16,
 
+    /// Public WireGuard endpoint given to clients as `host:port`, for when clients reach
+    /// the server through a different address or port than the API.
+    /// When not set, clients use the API server hostname and --wg-port.
+    #[structopt(long, env = "WG_PUBLIC_ENDPOINT")]
+    wg_public_endpoint: Option<String>,
+
     /// Client address CIDR. The server allocates one address for itself,
     /// then clients get addresses following this first address.
     #[structopt(long, env = "WG_CLIENT_CIDR", default_value = "172.25.0.0/24")]
@@ -60,28 +74,273 @@ pub(crate) struct WireguardSettings {
     /// Post down script
     #[structopt(long, env = "WG_POST_DOWN_SCRIPT")]
     wg_post_down_script: Option<String>,
+
+    /// How often peers on the server interface are compared with client sessions,
+    /// correcting any that were changed outside of the server
+    #[structopt(long, env = "WG_RECONCILE_INTERVAL", default_value = "1m")]
+    wg_reconcile_interval: humantime::Duration,
+
+    /// Sessions whose client had no WireGuard handshake for this long are ended,
+    /// sessions are kept until they expire when not set
+    #[structopt(long, env = "IDLE_SESSION_TIMEOUT")]
+    idle_session_timeout: Option<humantime::Duration>,
+
+    /// How the server interface is managed: "wg-quick", "uapi" for a userspace
+    /// implementation such as wireguard-go, "boringtun" to run boringtun-cli
+    /// without kernel WireGuard, "netlink" to configure kernel WireGuard without
+    /// the WireGuard tools on Linux, or "mock" to run without an interface
+    #[structopt(long, env = "WG_BACKEND", default_value = "wg-quick")]
+    wg_backend: BackendKind,
+
+    /// Name of the server WireGuard interface, at most 15 letters, digits and _=+.-
+    #[structopt(long, env = "WG_INTERFACE", default_value = "server")]
+    wg_interface: String,
+
+    /// Directory the wg-quick backend writes the interface configuration to,
+    /// /etc/wireguard when not set
+    #[structopt(long, env = "WG_CONFIG_DIR")]
+    wg_config_dir: Option<PathBuf>,
+
+    /// Linux network namespace to run the server interface in, created beforehand
+    /// with `ip netns add`. The WireGuard UDP socket stays in the namespace the server
+    /// runs in, while client traffic is only routed inside the given one.
+    /// Post up and down scripts run inside the namespace.
+    #[structopt(long, env = "WG_NETNS")]
+    wg_netns: Option<Netns>,
+}
+
+impl WireguardSettings {
+    /// Checks that the networks given to clients make sense together
+    pub fn validate(&self) -> Result<()> {
+        if self.session_duration.as_secs() == 0 {
+            return Err(anyhow!("Session duration must be longer than zero"));
+        }
+
+        if self.wg_reconcile_interval.as_secs() == 0 {
+            return Err(anyhow!("Reconcile interval must be at least a second"));
+        }
+
+        validate_interface_name(&self.wg_interface)?;
+
+        for network in self.wg_additional_networks.iter() {
+            if overlaps(*network, self.wg_client_cidr) {
+                return Err(anyhow!(
+                    "Additional network {} overlaps client CIDR {}",
+                    network,
+                    self.wg_client_cidr
+                ));
+            }
+        }
+
+        if let Some(endpoint) = self.wg_public_endpoint.as_ref() {
+            let valid = matches!(
+                endpoint.rsplit_once(':'),
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok()
+            );
+            if !valid {
+                return Err(anyhow!(
+                    "WireGuard public endpoint must look like host:port, got {}",
+                    endpoint
+                ));
+            }
+        }
+
+        if let Some(dns_server) = self.wg_dns_server {
+            let routed = std::iter::once(&self.wg_client_cidr)
+                .chain(self.wg_additional_networks.iter())
+                .any(|network| network.contains(dns_server));
+            if !routed && !is_global(dns_server) {
+                return Err(anyhow!(
+                    "DNS server {} is not reachable by clients, it is not in the client CIDR or additional networks",
+                    dns_server
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn overlaps(a: IpNetwork, b: IpNetwork) -> bool {
+    match (a, b) {
+        (IpNetwork::V4(a), IpNetwork::V4(b)) => a.overlaps(b),
+        (IpNetwork::V6(a), IpNetwork::V6(b)) => a.overlaps(b),
+        _ => false,
+    }
+}
+
+/// Whether an address can be reached without routing through the VPN
+fn is_global(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            !(address.is_private()
+                || address.is_loopback()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+                || address.is_documentation()
+                // Shared address space, 100.64.0.0/10
+                || (address.octets()[0] == 100 && (address.octets()[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(address) => {
+            !(address.is_loopback()
+                || address.is_unspecified()
+                // Unique local, fc00::/7
+                || (address.segments()[0] & 0xfe00) == 0xfc00
+                // Link local, fe80::/10
+                || (address.segments()[0] & 0xffc0) == 0xfe80)
+        }
+    }
 }
 
 pub(crate) struct Wireguard {
-    settings: WireguardSettings,
+    settings: RwLock<Arc<WireguardSettings>>,
+    limit_settings: RwLock<Arc<LimitSettings>>,
     session_manager: Arc<SessionManager<UserData>>,
     key_pair: WgKeyPair,
+    backend: Arc<dyn WireguardBackend>,
+    /// Name of the server interface, which can't change without a restart
+    interface: String,
+    audit_log: Arc<AuditLog>,
+    webhooks: Arc<Webhooks>,
+    /// Public keys and allowed IPs of the peers last written to the server configuration
+    applied_peers: Mutex<HashMap<String, Vec<IpNetwork>>>,
+    /// Error from the last attempt to bring up the server interface
+    interface_error: Mutex<Option<String>>,
+    /// Held while changing the server interface, so peers are never reconciled
+    /// against sessions that changed in the meantime
+    update_lock: tokio::sync::Mutex<()>,
+    reconcile_stats: Mutex<ReconcileStats>,
+    /// Wakes the server update loop after the settings were reloaded
+    reloaded: tokio::sync::Notify,
+    /// Interface settings the server interface was created with
+    applied_interface: Mutex<Option<InterfaceSettings>>,
+}
+
+/// Settings only applied when the server interface is created, changing
+/// them requires recreating the interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct InterfaceSettings {
+    mtu: Option<u16>,
+    post_up_script: Option<String>,
+    post_down_script: Option<String>,
+}
+
+impl From<&WireguardSettings> for InterfaceSettings {
+    fn from(settings: &WireguardSettings) -> Self {
+        Self {
+            mtu: settings.wg_mtu,
+            post_up_script: settings.wg_post_up_script.clone(),
+            post_down_script: settings.wg_post_down_script.clone(),
+        }
+    }
+}
+
+/// Counts of reconcile runs and of the corrections they made
+#[derive(Debug, Default)]
+struct ReconcileStats {
+    runs: u64,
+    failures: u64,
+    added: u64,
+    updated: u64,
+    removed: u64,
 }
 
 impl Wireguard {
-    pub(crate) async fn new(settings: WireguardSettings) -> Result<Arc<Self>> {
+    pub(crate) fn new(
+        settings: WireguardSettings,
+        limit_settings: LimitSettings,
+        key_pair: WgKeyPair,
+        audit_log: Arc<AuditLog>,
+        webhooks: Arc<Webhooks>,
+    ) -> Result<Arc<Self>> {
+        let mut backend_options = BackendOptions {
+            netns: settings.wg_netns.clone(),
+            ..Default::default()
+        };
+        if let Some(config_dir) = settings.wg_config_dir.as_ref() {
+            backend_options.config_dir = config_dir.clone();
+        }
         Ok(Arc::new(Self {
             session_manager: SessionManager::new(
                 settings.wg_client_cidr,
                 chrono::Duration::from_std(settings.session_duration.into())?,
             ),
-            settings,
-            key_pair: WgKeyPair::new().await?,
+            backend: settings.wg_backend.backend(&backend_options),
+            interface: settings.wg_interface.clone(),
+            settings: RwLock::new(Arc::new(settings)),
+            limit_settings: RwLock::new(Arc::new(limit_settings)),
+            key_pair,
+            audit_log,
+            webhooks,
+            applied_peers: Default::default(),
+            interface_error: Mutex::new(Some("Interface was not brought up yet".to_owned())),
+            update_lock: Default::default(),
+            reconcile_stats: Default::default(),
+            reloaded: Default::default(),
+            applied_interface: Default::default(),
         }))
     }
 
+    fn settings(&self) -> Arc<WireguardSettings> {
+        self.settings
+            .read()
+            .expect("WireGuard settings lock is poisoned")
+            .clone()
+    }
+
+    fn limit_settings(&self) -> Arc<LimitSettings> {
+        self.limit_settings
+            .read()
+            .expect("Limit settings lock is poisoned")
+            .clone()
+    }
+
+    /// Applies new settings to new sessions and the server interface. MTU and post up and
+    /// down script changes recreate the server interface, briefly interrupting clients.
+    /// The session duration, listen port, client CIDR and backend are in use and are
+    /// kept as they are.
+    pub(crate) fn reload(&self, mut settings: WireguardSettings, limit_settings: LimitSettings) {
+        let current = self.settings();
+        if settings.session_duration != current.session_duration
+            || settings.wg_port != current.wg_port
+            || settings.wg_client_cidr != current.wg_client_cidr
+            || settings.wg_backend != current.wg_backend
+            || settings.wg_interface != current.wg_interface
+            || settings.wg_config_dir != current.wg_config_dir
+            || settings.wg_netns != current.wg_netns
+        {
+            warn!("Session duration, WireGuard address, client CIDR, backend, interface and namespace changes require a restart");
+            settings.session_duration = current.session_duration;
+            settings.wg_port = current.wg_port;
+            settings.wg_client_cidr = current.wg_client_cidr;
+            settings.wg_backend = current.wg_backend;
+            settings.wg_interface = current.wg_interface.clone();
+            settings.wg_config_dir = current.wg_config_dir.clone();
+            settings.wg_netns = current.wg_netns.clone();
+        }
+
+        *self
+            .settings
+            .write()
+            .expect("WireGuard settings lock is poisoned") = Arc::new(settings);
+        *self
+            .limit_settings
+            .write()
+            .expect("Limit settings lock is poisoned") = Arc::new(limit_settings);
+        self.reloaded.notify_one();
+    }
+
     pub(crate) fn run(self: Arc<Self>) {
+        self.audit_log
+            .clone()
+            .watch_sessions(self.session_manager.subscribe());
+        self.webhooks
+            .clone()
+            .watch_sessions(self.session_manager.subscribe());
         self.session_manager.clone().run();
+        tokio::spawn(self.clone().reap_idle_sessions());
+        tokio::spawn(self.clone().reconcile_peers());
         tokio::spawn(self.run_server());
     }
 
@@ -91,65 +350,516 @@ impl Wireguard {
         device_id: Uuid,
         client_public_key: String,
         user_data: UserData,
-    ) -> Result<(WireguardInterface, WireguardPeer, DateTime<Utc>)> {
+    ) -> Result<(Session<UserData>, WireguardInterface, WireguardPeer)> {
+        let limits = self.limit_settings().for_groups(&user_data.groups);
         let session = self
             .session_manager
-            .create(device_id, client_public_key, user_data)
+            .create(device_id, client_public_key, user_data, &limits)
             .await?;
+        let (interface, peer) = self.client_config(hostname, &session)?;
+        Ok((session, interface, peer))
+    }
+
+    pub(crate) async fn find_session(&self, session_token: &str) -> Option<Session<UserData>> {
+        self.session_manager.find(session_token).await
+    }
 
+    pub(crate) async fn renew_session(
+        &self,
+        hostname: &str,
+        session_token: &str,
+        client_public_key: String,
+        user_data: UserData,
+    ) -> Result<Option<(Session<UserData>, WireguardInterface, WireguardPeer)>> {
+        let session = match self
+            .session_manager
+            .renew(session_token, client_public_key, user_data)
+            .await?
+        {
+            None => return Ok(None),
+            Some(session) => session,
+        };
+        let (interface, peer) = self.client_config(hostname, &session)?;
+        Ok(Some((session, interface, peer)))
+    }
+
+    pub(crate) async fn end_session(&self, session_token: &str) -> Option<Session<UserData>> {
+        let session = self.session_manager.revoke(session_token).await?;
+        info!(
+            "User {} logged out from device {}",
+            session.user_data.email, session.device_id
+        );
+        Some(session)
+    }
+
+    fn client_config(
+        &self,
+        hostname: &str,
+        session: &Session<UserData>,
+    ) -> Result<(WireguardInterface, WireguardPeer)> {
+        let settings = self.settings();
         let interface = WireguardInterface {
-            address: ip_address_as_ip_network(session.client_address)?,
-            dns: self.settings.wg_dns_server,
-            mtu: self.settings.wg_mtu,
-            listen_port: None,
+            address: vec![ip_address_as_ip_network(session.client_address)?],
+            dns: settings.wg_dns_server.into_iter().collect(),
+            mtu: settings.wg_mtu,
+            ..Default::default()
         };
 
         let peer = WireguardPeer {
-            public_key: self.key_pair.public_key.clone(),
-            endpoint: Some(format!("{}:{}", hostname, self.settings.wg_port)),
-            allowed_ips: vec![self.settings.wg_client_cidr]
+            public_key: self.key_pair.public_key.to_string(),
+            preshared_key: None,
+            endpoint: Some(
+                settings
+                    .wg_public_endpoint
+                    .clone()
+                    .unwrap_or_else(|| format!("{}:{}", hostname, settings.wg_port)),
+            ),
+            allowed_ips: vec![settings.wg_client_cidr]
                 .into_iter()
-                .chain(self.settings.wg_additional_networks.iter().copied())
+                .chain(settings.wg_additional_networks.iter().copied())
                 .collect(),
-            persistent_keepalive: self.settings.wg_client_keepalive.map(|value| value.into()),
+            persistent_keepalive: settings.wg_client_keepalive.map(|value| value.into()),
         };
 
-        Ok((interface, peer, session.ends_at))
+        Ok((interface, peer))
+    }
+
+    pub(crate) fn public_key(&self) -> String {
+        self.key_pair.public_key.to_string()
+    }
+
+    /// Fails unless the server interface was successfully brought up with the current peers
+    pub(crate) fn check_interface(&self) -> Result<()> {
+        match &*self
+            .interface_error
+            .lock()
+            .expect("Interface lock is poisoned")
+        {
+            None => Ok(()),
+            Some(err) => Err(anyhow!("{}", err)),
+        }
+    }
+
+    /// Fails if the session store can't be locked within `timeout`
+    pub(crate) async fn check_sessions(&self, timeout: Duration) -> Result<()> {
+        self.session_manager.check_available(timeout).await
+    }
+
+    /// Metrics of the server interface and its peers
+    pub(crate) async fn metrics(&self) -> Result<MetricsReport> {
+        let status = self.backend.status(&self.interface).await?;
+        let stats = self
+            .reconcile_stats
+            .lock()
+            .expect("Reconcile stats lock is poisoned");
+        let now = SystemTime::now();
+        let active_peers = status
+            .peers
+            .iter()
+            .filter_map(|peer| peer.latest_handshake)
+            .filter(|handshake| {
+                now.duration_since(*handshake)
+                    .map(|age| age < ACTIVE_HANDSHAKE_AGE)
+                    .unwrap_or(true)
+            })
+            .count();
+        Ok(MetricsReport::new()
+            .gauge(
+                "wireguard_peers",
+                "Peers configured on the server interface",
+                status.peers.len(),
+            )
+            .gauge(
+                "wireguard_active_peers",
+                "Peers with a handshake in the last 3 minutes",
+                active_peers,
+            )
+            // Gauges rather than counters, since they drop when peers are removed
+            .gauge(
+                "wireguard_received_bytes",
+                "Bytes received from current peers",
+                status.peers.iter().map(|peer| peer.rx_bytes).sum::<u64>(),
+            )
+            .gauge(
+                "wireguard_sent_bytes",
+                "Bytes sent to current peers",
+                status.peers.iter().map(|peer| peer.tx_bytes).sum::<u64>(),
+            )
+            .counter(
+                "wireguard_reconcile_runs_total",
+                "Times peers were compared with client sessions",
+                stats.runs,
+            )
+            .counter(
+                "wireguard_reconcile_failures_total",
+                "Times peers could not be compared or corrected",
+                stats.failures,
+            )
+            .labelled_counter(
+                "wireguard_reconciled_peers_total",
+                "Peers corrected because they differed from client sessions",
+                "change",
+                [
+                    ("added", stats.added),
+                    ("updated", stats.updated),
+                    ("removed", stats.removed),
+                ],
+            ))
+    }
+
+    /// Ends sessions whose peer had no handshake within the idle session timeout
+    async fn reap_idle_sessions(self: Arc<Self>) {
+        // When each peer was first seen, peers without a handshake are idle since then
+        let mut first_seen: HashMap<PublicKey, SystemTime> = HashMap::new();
+
+        loop {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+            let idle_timeout: Duration = match self.settings().idle_session_timeout {
+                None => continue,
+                Some(idle_timeout) => idle_timeout.into(),
+            };
+            let status = match self.backend.status(&self.interface).await {
+                Ok(status) => status,
+                Err(err) => {
+                    debug!("Not checking for idle sessions: {}", err);
+                    continue;
+                }
+            };
+
+            let now = SystemTime::now();
+            let mut seen = HashMap::new();
+            for peer in status.peers {
+                let added = first_seen.get(&peer.public_key).copied().unwrap_or(now);
+                seen.insert(peer.public_key, added);
+                let last_active = peer.latest_handshake.unwrap_or(added).max(added);
+                if now.duration_since(last_active).unwrap_or_default() < idle_timeout {
+                    continue;
+                }
+                if let Some(session) = self
+                    .session_manager
+                    .expire_idle(&peer.public_key.to_string())
+                    .await
+                {
+                    info!(
+                        "Ended session of {} on device {}, idle for more than {:?}",
+                        session.user_data.email, session.device_id, idle_timeout
+                    );
+                }
+            }
+            first_seen = seen;
+        }
+    }
+
+    /// Corrects peers of the server interface that differ from client sessions,
+    /// such as peers changed by hand with `wg set`
+    async fn reconcile_peers(self: Arc<Self>) {
+        loop {
+            let interval: Duration = self.settings().wg_reconcile_interval.into();
+            tokio::time::sleep(interval).await;
+            // The interface is brought up again by run_server
+            if self.check_interface().is_err() {
+                continue;
+            }
+
+            let result = self.reconcile().await;
+            let mut stats = self
+                .reconcile_stats
+                .lock()
+                .expect("Reconcile stats lock is poisoned");
+            stats.runs += 1;
+            match result {
+                Ok(diff) => {
+                    stats.added += diff.added.len() as u64;
+                    stats.updated += diff.updated.len() as u64;
+                    stats.removed += diff.removed.len() as u64;
+                }
+                Err(err) => {
+                    stats.failures += 1;
+                    error!("Error reconciling peers of {}: {}", self.interface, err);
+                }
+            }
+        }
+    }
+
+    /// Compares the peers of the server interface with client sessions and corrects
+    /// any difference, returning the corrections
+    async fn reconcile(&self) -> Result<PeerDiff> {
+        let _update = self.update_lock.lock().await;
+        let config = self.server_config().await?;
+        let status = self.backend.status(&self.interface).await?;
+        let diff = PeerDiff::new(&config, &status)?;
+        for peer in diff.added.iter() {
+            warn!(
+                "Adding peer {} missing from {}",
+                peer.public_key, self.interface
+            );
+        }
+        for update in diff.updated.iter() {
+            let changes: Vec<String> = update.changes.iter().map(ToString::to_string).collect();
+            warn!(
+                "Resetting {} of peer {} on {}",
+                changes.join(", "),
+                update.peer.public_key,
+                self.interface
+            );
+        }
+        for public_key in diff.removed.iter() {
+            warn!(
+                "Removing peer {} without a session from {}",
+                public_key, self.interface
+            );
+        }
+        diff.apply(self.backend.as_ref(), &self.interface).await?;
+        Ok(diff)
     }
 
     async fn run_server(self: Arc<Self>) {
         let sessions_notify = self.session_manager.clone().get_notify();
 
         loop {
-            sessions_notify.notified().await;
-            debug!("Client sessions have changed, updating server");
+            // Created before updating so changes made during the update are not missed
+            let sessions_changed = sessions_notify.notified();
 
-            if let Err(err) = self.clone().update_server().await {
+            let result = self.clone().update_server().await;
+            if let Err(err) = result.as_ref() {
                 error!("Error updating server configuration: {}", err);
             }
+            *self
+                .interface_error
+                .lock()
+                .expect("Interface lock is poisoned") = result.err().map(|err| err.to_string());
+
+            tokio::select! {
+                _ = sessions_changed => debug!("Client sessions have changed, updating server"),
+                _ = self.reloaded.notified() => debug!("Settings were reloaded, updating server"),
+            }
         }
     }
 
-    async fn update_server(self: Arc<Self>) -> Result<()> {
+    /// Configuration of the server interface, with a peer for every client session
+    async fn server_config(&self) -> Result<WireguardConfig> {
+        let settings = self.settings();
         let interface = FullWireguardInterface::new_with_scripts(
             &self.key_pair,
             WireguardInterface {
-                address: ip_address_as_ip_network(self.session_manager.server_address())?,
-                listen_port: Some(self.settings.wg_port),
-                mtu: self.settings.wg_mtu,
-                dns: None,
+                address: vec![ip_address_as_ip_network(
+                    self.session_manager.server_address(),
+                )?],
+                listen_port: Some(settings.wg_port),
+                mtu: settings.wg_mtu,
+                ..Default::default()
             },
             WireguardInterfaceScripts {
-                post_up: self.settings.wg_post_up_script.clone(),
-                post_down: self.settings.wg_post_down_script.clone(),
+                post_up: settings.wg_post_up_script.iter().cloned().collect(),
+                post_down: settings.wg_post_down_script.iter().cloned().collect(),
+                ..Default::default()
             },
         );
 
-        let peers = self.session_manager.get_peers().await?;
+        let peers: Vec<WireguardPeer> = self
+            .session_manager
+            .get_peers()
+            .await?
+            .into_iter()
+            .map(|peer| WireguardPeer {
+                persistent_keepalive: settings.wg_server_keepalive.map(|value| value.into()),
+                ..peer
+            })
+            .collect();
+
+        Ok(WireguardConfig::new(interface, peers))
+    }
+
+    async fn update_server(self: Arc<Self>) -> Result<()> {
+        let _update = self.update_lock.lock().await;
+        let interface_settings = InterfaceSettings::from(self.settings().as_ref());
+        let config = self.server_config().await?;
+        let new_peers: HashMap<String, Vec<IpNetwork>> = config
+            .peers()
+            .iter()
+            .map(|peer| (peer.public_key.clone(), peer.allowed_ips.clone()))
+            .collect();
+
+        let interface_up = self
+            .interface_error
+            .lock()
+            .expect("Interface lock is poisoned")
+            .is_none();
+        let applied_interface = self
+            .applied_interface
+            .lock()
+            .expect("Interface lock is poisoned")
+            .clone();
+        if interface_up && applied_interface.as_ref() == Some(&interface_settings) {
+            self.backend.set_config(&self.interface, &config).await?;
+        } else {
+            if interface_up {
+                warn!(
+                    "Interface settings changed, recreating {} with {:?}",
+                    self.interface, interface_settings
+                );
+                self.backend.destroy_interface(&self.interface).await?;
+            }
+            self.backend
+                .create_interface(&self.interface, &config)
+                .await?;
+            *self
+                .applied_interface
+                .lock()
+                .expect("Interface lock is poisoned") = Some(interface_settings);
+        }
+
+        let old_peers = std::mem::replace(
+            &mut *self.applied_peers.lock().expect("Peers lock is poisoned"),
+            new_peers.clone(),
+        );
+        self.audit_peer_changes(&old_peers, &new_peers, AuditEvent::PeerRemoved);
+        self.audit_peer_changes(&new_peers, &old_peers, AuditEvent::PeerAdded);
+
+        Ok(())
+    }
+
+    /// Records an audit event for every peer in `peers` missing from `other_peers`
+    fn audit_peer_changes(
+        &self,
+        peers: &HashMap<String, Vec<IpNetwork>>,
+        other_peers: &HashMap<String, Vec<IpNetwork>>,
+        event: AuditEvent,
+    ) {
+        for (public_key, allowed_ips) in peers {
+            if other_peers.contains_key(public_key) {
+                continue;
+            }
+            let mut record = AuditRecord::new(event.clone()).public_key(public_key);
+            if let Some(network) = allowed_ips.first() {
+                record = record.client_address(network.ip());
+            }
+            self.audit_log.record(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditSettings;
+    use crate::webhooks::WebhookSettings;
+    use test_env_log::test;
+
+    fn settings(args: &[&str]) -> Result<WireguardSettings> {
+        Ok(WireguardSettings::from_iter_safe(
+            std::iter::once("cablescout-server").chain(args.iter().copied()),
+        )?)
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        settings(&[])?.validate()?;
+        settings(&[
+            "--wg-additional-networks",
+            "10.0.0.0/8",
+            "--wg-dns-server",
+            "10.0.0.53",
+        ])?
+        .validate()?;
+        settings(&["--wg-dns-server", "172.25.0.1"])?.validate()?;
+        settings(&["--wg-dns-server", "1.1.1.1"])?.validate()?;
+
+        assert!(settings(&["--wg-additional-networks", "172.25.0.128/25"])?
+            .validate()
+            .is_err());
+        assert!(settings(&["--wg-dns-server", "192.168.1.1"])?
+            .validate()
+            .is_err());
+        assert!(settings(&["--session-duration", "0s"])?.validate().is_err());
 
-        let config = WireguardConfig::new(interface, peers);
-        wg_quick_up("server", config).await?;
+        settings(&["--wg-interface", "wg0"])?.validate()?;
+        assert!(settings(&["--wg-interface", "cablescout-server"])?
+            .validate()
+            .is_err());
 
+        settings(&["--wg-public-endpoint", "vpn.example.com:443"])?.validate()?;
+        assert!(settings(&["--wg-public-endpoint", "vpn.example.com"])?
+            .validate()
+            .is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_server() -> Result<()> {
+        let no_args = ["cablescout-server"];
+        let wireguard = Wireguard::new(
+            settings(&["--wg-backend", "mock"])?,
+            LimitSettings::from_iter_safe(no_args)?,
+            WgKeyPair::new(),
+            AuditLog::new(AuditSettings::from_iter_safe(no_args)?)?,
+            Webhooks::new(WebhookSettings::from_iter_safe(no_args)?)?,
+        )?;
+        wireguard.clone().update_server().await?;
+
+        let status = wireguard.backend.status(&wireguard.interface).await?;
+        assert_eq!(status.public_key, Some(wireguard.key_pair.public_key));
+        assert_eq!(status.listen_port, Some(51820));
+        assert!(status.peers.is_empty());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_reconcile() -> Result<()> {
+        let no_args = ["cablescout-server"];
+        let wireguard = Wireguard::new(
+            settings(&["--wg-backend", "mock"])?,
+            LimitSettings::from_iter_safe(no_args)?,
+            WgKeyPair::new(),
+            AuditLog::new(AuditSettings::from_iter_safe(no_args)?)?,
+            Webhooks::new(WebhookSettings::from_iter_safe(no_args)?)?,
+        )?;
+        wireguard.clone().update_server().await?;
+        assert!(wireguard.reconcile().await?.is_empty());
+
+        // A peer added by hand, without a session
+        let public_key = WgKeyPair::new().public_key;
+        let peer = WireguardPeer {
+            public_key: public_key.to_string(),
+            preshared_key: None,
+            allowed_ips: vec!["172.25.0.9/32".parse()?],
+            endpoint: None,
+            persistent_keepalive: None,
+        };
+        wireguard
+            .backend
+            .add_peer(&wireguard.interface, &peer)
+            .await?;
+
+        let diff = wireguard.reconcile().await?;
+        assert_eq!(diff.removed, vec![public_key]);
+        assert!(diff.added.is_empty() && diff.updated.is_empty());
+        let status = wireguard.backend.status(&wireguard.interface).await?;
+        assert!(status.peers.is_empty());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_reload_recreates_interface() -> Result<()> {
+        let no_args = ["cablescout-server"];
+        let wireguard = Wireguard::new(
+            settings(&["--wg-backend", "mock"])?,
+            LimitSettings::from_iter_safe(no_args)?,
+            WgKeyPair::new(),
+            AuditLog::new(AuditSettings::from_iter_safe(no_args)?)?,
+            Webhooks::new(WebhookSettings::from_iter_safe(no_args)?)?,
+        )?;
+        wireguard.clone().update_server().await?;
+        *wireguard.interface_error.lock().unwrap() = None;
+
+        wireguard.reload(
+            settings(&["--wg-backend", "mock", "--wg-mtu", "1380"])?,
+            LimitSettings::from_iter_safe(no_args)?,
+        );
+        wireguard.clone().update_server().await?;
+        let applied = wireguard.applied_interface.lock().unwrap().clone();
+        assert_eq!(applied.and_then(|applied| applied.mtu), Some(1380));
+        assert!(wireguard.backend.status(&wireguard.interface).await.is_ok());
         Ok(())
     }
 }
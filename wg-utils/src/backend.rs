@@ -0,0 +1,100 @@
+use crate::{InterfaceStatus, MockBackend, Netns, WgQuickBackend, WireguardConfig, WireguardPeer};
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Creates and configures WireGuard interfaces
+#[async_trait::async_trait]
+pub trait WireguardBackend: Send + Sync {
+    /// Creates interface `name` configured with `config`, replacing an existing one
+    async fn create_interface(&self, name: &str, config: &WireguardConfig) -> Result<()>;
+
+    /// Applies the WireGuard settings and peers of `config` to an existing interface,
+    /// without taking it down
+    async fn set_config(&self, name: &str, config: &WireguardConfig) -> Result<()>;
+
+    /// Adds `peer`, or replaces the settings of a peer with the same public key
+    async fn add_peer(&self, name: &str, peer: &WireguardPeer) -> Result<()>;
+
+    async fn remove_peer(&self, name: &str, public_key: &str) -> Result<()>;
+
+    async fn status(&self, name: &str) -> Result<InterfaceStatus>;
+
+    async fn destroy_interface(&self, name: &str) -> Result<()>;
+}
+
+/// Settings shared by the backends
+#[derive(Debug, Clone)]
+pub struct BackendOptions {
+    /// Where configuration files of wg-quick interfaces are written
+    pub config_dir: PathBuf,
+    /// Linux network namespace interfaces are moved to after being created
+    pub netns: Option<Netns>,
+}
+
+impl Default for BackendOptions {
+    fn default() -> Self {
+        Self {
+            config_dir: crate::wg_quick::default_config_dir(),
+            netns: None,
+        }
+    }
+}
+
+/// Backend picked at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// The `wg-quick` and `wg` tools, or the WireGuard service on Windows
+    WgQuick,
+    /// A userspace implementation such as wireguard-go, configured through its UAPI socket
+    #[cfg(target_family = "unix")]
+    Uapi,
+    /// boringtun started for each interface, configured through its UAPI socket
+    #[cfg(target_family = "unix")]
+    Boringtun,
+    /// Kernel WireGuard configured over netlink, without the WireGuard tools
+    #[cfg(target_os = "linux")]
+    Netlink,
+    /// Interfaces kept in memory, for tests and development without root
+    Mock,
+}
+
+impl BackendKind {
+    pub fn backend(self, options: &BackendOptions) -> Arc<dyn WireguardBackend> {
+        match self {
+            Self::WgQuick => Arc::new(
+                WgQuickBackend::new(options.config_dir.clone()).with_netns(options.netns.clone()),
+            ),
+            #[cfg(target_family = "unix")]
+            Self::Uapi => Arc::new(crate::UapiBackend::default().with_netns(options.netns.clone())),
+            #[cfg(target_family = "unix")]
+            Self::Boringtun => {
+                Arc::new(crate::UapiBackend::boringtun().with_netns(options.netns.clone()))
+            }
+            #[cfg(target_os = "linux")]
+            Self::Netlink => {
+                Arc::new(crate::NetlinkBackend::default().with_netns(options.netns.clone()))
+            }
+            Self::Mock => Arc::new(MockBackend::default()),
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wg-quick" => Ok(Self::WgQuick),
+            #[cfg(target_family = "unix")]
+            "uapi" => Ok(Self::Uapi),
+            #[cfg(target_family = "unix")]
+            "boringtun" => Ok(Self::Boringtun),
+            #[cfg(target_os = "linux")]
+            "netlink" => Ok(Self::Netlink),
+            "mock" => Ok(Self::Mock),
+            _ => Err(anyhow!("Unknown WireGuard backend {}", s)),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::{anyhow, Result};
+use log::*;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+fn check_output(output: std::process::Output) -> Result<String> {
+    if !output.status.success() {
+        let msg = format!(
+            "Running command failed:\nstdout: {}\nstderr: {}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        error!("Command failed: {}", msg);
+        return Err(anyhow!(msg));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    debug!("Output: {}", stdout);
+    Ok(stdout)
+}
+
+/// Runs `command`, returning its standard output
+pub(crate) async fn run_command(command: &mut Command) -> Result<String> {
+    debug!("Running: {:?}", command);
+    check_output(command.output().await?)
+}
+
+/// Runs `command` with `input` as its standard input, which keeps secrets off the command line
+pub(crate) async fn run_command_with_input(command: &mut Command, input: &[u8]) -> Result<String> {
+    debug!("Running: {:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Child stdin is piped");
+    stdin.write_all(input).await?;
+    drop(stdin);
+    check_output(child.wait_with_output().await?)
+}
@@ -0,0 +1,110 @@
+use anyhow::{anyhow, Result};
+
+/// Longest interface name Linux accepts, IFNAMSIZ minus the terminating NUL
+pub const MAX_INTERFACE_NAME_LEN: usize = 15;
+
+/// Shortest hash in names mapped from tunnel names, 32 bits keeps collisions unlikely
+const MIN_HASH_LEN: usize = 8;
+
+/// Characters wg-quick accepts in interface names
+fn is_valid_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '=' | '+' | '.' | '-')
+}
+
+/// Checks `name` is usable as an interface name on every platform
+pub fn validate_interface_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_INTERFACE_NAME_LEN {
+        return Err(anyhow!(
+            "Interface name {:?} must be 1 to {} characters long",
+            name,
+            MAX_INTERFACE_NAME_LEN
+        ));
+    }
+    if name == "." || name == ".." || !name.chars().all(is_valid_char) {
+        return Err(anyhow!(
+            "Interface name {:?} must only contain letters, digits and _=+.-",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Checks `prefix` leaves room for the hash in names from [`interface_name_for`]
+pub fn validate_interface_prefix(prefix: &str) -> Result<()> {
+    if prefix.len() > MAX_INTERFACE_NAME_LEN - MIN_HASH_LEN {
+        return Err(anyhow!(
+            "Interface prefix {:?} must be at most {} characters long",
+            prefix,
+            MAX_INTERFACE_NAME_LEN - MIN_HASH_LEN
+        ));
+    }
+    if !prefix.chars().all(is_valid_char) {
+        return Err(anyhow!(
+            "Interface prefix {:?} must only contain letters, digits and _=+.-",
+            prefix
+        ));
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a, which unlike the standard library hashers is specified to stay the same
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Interface name for `tunnel_name`, which can be any string such as a server hostname.
+/// The name is `prefix` followed by a hash of the tunnel name filling the remaining
+/// characters, so it is always valid, the same across restarts and versions, and
+/// distinct tunnels only share an interface if their hashes collide.
+pub fn interface_name_for(prefix: &str, tunnel_name: &str) -> Result<String> {
+    validate_interface_prefix(prefix)?;
+    let hash = format!("{:016x}", fnv1a(tunnel_name.as_bytes()));
+    let name = format!(
+        "{}{}",
+        prefix,
+        &hash[..MAX_INTERFACE_NAME_LEN - prefix.len()]
+    );
+    validate_interface_name(&name)?;
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_interface_name() {
+        for name in ["wg0", "server", "cs-0123456789ab", "a.b_c=d+e-f"] {
+            assert!(validate_interface_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", ".", "..", "vpn.example.com.x", "wg 0", "wg/0", "wg0%"] {
+            assert!(validate_interface_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_interface_name_for() -> Result<()> {
+        let name = interface_name_for("cs-", "vpn.example.com")?;
+        assert_eq!(name.len(), MAX_INTERFACE_NAME_LEN);
+        assert!(name.starts_with("cs-"));
+        assert_eq!(name, interface_name_for("cs-", "vpn.example.com")?);
+        assert_eq!(interface_name_for("", "vpn.example.com")?.len(), 15);
+
+        assert_ne!(name, interface_name_for("cs-", "vpn.example.org")?);
+        assert_ne!(name, interface_name_for("cs-", "")?);
+
+        assert!(interface_name_for("cablescout", "vpn").is_err());
+        assert!(interface_name_for("cs/", "vpn").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        // Reference values of the FNV specification
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+}
@@ -1,50 +1,275 @@
 use anyhow::{anyhow, Result};
-use log::*;
-use std::process::Stdio;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
 
+const KEY_BYTES: usize = 32;
+
+fn decode_key(s: &str) -> Result<Zeroizing<[u8; KEY_BYTES]>> {
+    let decoded = Zeroizing::new(
+        base64::decode(s.trim()).map_err(|err| anyhow!("Key is not valid base64: {}", err))?,
+    );
+    if decoded.len() != KEY_BYTES {
+        return Err(anyhow!(
+            "Key must be {} bytes, got {}",
+            KEY_BYTES,
+            decoded.len()
+        ));
+    }
+    let mut key = Zeroizing::new([0u8; KEY_BYTES]);
+    key.copy_from_slice(&decoded);
+    Ok(key)
+}
+
+/// Hex encoding of a key, as used by the UAPI protocol
+#[cfg(target_family = "unix")]
+pub(crate) fn encode_hex(bytes: &[u8]) -> Zeroizing<String> {
+    let mut hex = Zeroizing::new(String::with_capacity(bytes.len() * 2));
+    for byte in bytes {
+        hex.push(char::from_digit((byte >> 4).into(), 16).expect("Nibble is a hex digit"));
+        hex.push(char::from_digit((byte & 0xf).into(), 16).expect("Nibble is a hex digit"));
+    }
+    hex
+}
+
+pub(crate) fn decode_hex_key(s: &str) -> Result<Zeroizing<[u8; KEY_BYTES]>> {
+    if s.len() != KEY_BYTES * 2 || !s.is_ascii() {
+        return Err(anyhow!("Key must be {} hex digits", KEY_BYTES * 2));
+    }
+    let mut key = Zeroizing::new([0u8; KEY_BYTES]);
+    for (byte, digits) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits)?;
+        *byte = u8::from_str_radix(digits, 16)
+            .map_err(|_| anyhow!("Key is not valid hex: {}", digits))?;
+    }
+    Ok(key)
+}
+
+/// WireGuard public key, shown and parsed as base64
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; KEY_BYTES]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; KEY_BYTES] {
+        &self.0
+    }
+}
+
+impl From<[u8; KEY_BYTES]> for PublicKey {
+    fn from(bytes: [u8; KEY_BYTES]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(*decode_key(s)?))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// WireGuard private key, zeroed when dropped and never shown in logs
+#[derive(Clone)]
+pub struct PrivateKey(StaticSecret);
+
+impl PrivateKey {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0).to_bytes())
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; KEY_BYTES]) -> Self {
+        Self(StaticSecret::from(bytes))
+    }
+
+    #[cfg(target_family = "unix")]
+    pub(crate) fn as_bytes(&self) -> &[u8; KEY_BYTES] {
+        self.0.as_bytes()
+    }
+
+    /// Base64 of the key, as written in WireGuard configuration files
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(base64::encode(self.0.as_bytes()))
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(StaticSecret::from(*decode_key(s)?)))
+    }
+}
+
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_bytes() == other.0.as_bytes()
+    }
+}
+
+impl Eq for PrivateKey {}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrivateKey(<redacted>)")
+    }
+}
+
+impl Serialize for PrivateKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+/// Symmetric key mixed into a peer handshake for post-quantum resistance, zeroed when dropped
+#[derive(Clone)]
+pub struct PresharedKey(Zeroizing<[u8; KEY_BYTES]>);
+
+impl PresharedKey {
+    pub(crate) fn from_bytes(bytes: Zeroizing<[u8; KEY_BYTES]>) -> Self {
+        Self(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; KEY_BYTES] {
+        &self.0
+    }
+
+    /// Base64 of the key, as written in WireGuard configuration files
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(base64::encode(*self.0))
+    }
+}
+
+impl FromStr for PresharedKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(decode_key(s)?))
+    }
+}
+
+impl PartialEq for PresharedKey {
+    fn eq(&self, other: &Self) -> bool {
+        *self.0 == *other.0
+    }
+}
+
+impl Eq for PresharedKey {}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PresharedKey(<redacted>)")
+    }
+}
+
+impl Serialize for PresharedKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for PresharedKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = Zeroizing::new(String::deserialize(deserializer)?);
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone)]
 pub struct WgKeyPair {
-    pub public_key: String,
-    pub private_key: String,
+    pub public_key: PublicKey,
+    pub private_key: PrivateKey,
 }
 
 impl WgKeyPair {
-    async fn make_private_key() -> Result<String> {
-        debug!("Generating private key");
-        let child = Command::new("wg").arg("genkey").output().await?;
-        if !child.status.success() {
-            return Err(anyhow!("Running \"wg genkey\" failed"));
-        }
-        Ok(String::from_utf8(child.stdout)?.trim().to_owned())
-    }
-
-    async fn make_public_key(private_key: &[u8]) -> Result<String> {
-        debug!("Getting public key");
-        let mut child = Command::new("wg")
-            .arg("pubkey")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-        child.stdin.take().unwrap().write_all(private_key).await?;
-        let output = child.wait_with_output().await?;
-        if !output.status.success() {
-            return Err(anyhow!(
-                "Running \"wg pubkey\" failed:\nstdout: {}\nstderr: {}",
-                String::from_utf8_lossy(&output.stdout),
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
-        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    pub fn new() -> Self {
+        Self::from_private_key(PrivateKey::generate())
     }
 
-    pub async fn new() -> Result<Self> {
-        let private_key = Self::make_private_key().await?;
-        let public_key = Self::make_public_key(private_key.as_bytes()).await?;
-        Ok(Self {
-            public_key,
+    pub fn from_private_key(private_key: PrivateKey) -> Self {
+        Self {
+            public_key: private_key.public_key(),
             private_key,
-        })
+        }
+    }
+}
+
+impl Default for WgKeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Alice's key pair from RFC 7748 section 6.1, `wg pubkey` gives the same public key
+    const PRIVATE_KEY: &str = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+    const PUBLIC_KEY: &str = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+
+    #[test]
+    fn test_public_key() -> Result<()> {
+        let private_key: PrivateKey = PRIVATE_KEY.parse()?;
+        assert_eq!(private_key.public_key().to_string(), PUBLIC_KEY);
+        assert_eq!(private_key.to_base64().as_str(), PRIVATE_KEY);
+
+        let key_pair = WgKeyPair::from_private_key(private_key);
+        assert_eq!(key_pair.public_key, PUBLIC_KEY.parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert!("not base64!".parse::<PublicKey>().is_err());
+        assert!("AAAAAAAAAAAAAAAAAAAAAA==".parse::<PublicKey>().is_err());
+        assert!(format!("{}AAAA", PUBLIC_KEY).parse::<PublicKey>().is_err());
+        assert!("not base64!".parse::<PrivateKey>().is_err());
+        assert!("AAAAAAAAAAAAAAAAAAAAAA==".parse::<PrivateKey>().is_err());
+        assert!("AAAAAAAAAAAAAAAAAAAAAA==".parse::<PresharedKey>().is_err());
+
+        // Whitespace around keys read from files is ignored
+        assert!(format!(" {}\n", PUBLIC_KEY).parse::<PublicKey>().is_ok());
+    }
+
+    #[test]
+    fn test_hex_keys() -> Result<()> {
+        let public_key: PublicKey = PUBLIC_KEY.parse()?;
+        let hex = "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+        assert_eq!(*decode_hex_key(hex)?, *public_key.as_bytes());
+        assert!(decode_hex_key(&hex[2..]).is_err());
+        assert!(decode_hex_key(&hex.replace('8', "x")).is_err());
+        Ok(())
     }
 }
@@ -1,7 +1,30 @@
+mod backend;
+mod command;
+mod interface_name;
 mod key_pair;
+mod mock_backend;
+#[cfg(target_os = "linux")]
+mod netlink;
+mod netns;
+mod reconcile;
+mod status;
+#[cfg(target_family = "unix")]
+mod uapi;
 mod wg_config;
+mod wg_config_parser;
 mod wg_quick;
 
+pub use backend::*;
+pub use interface_name::*;
 pub use key_pair::*;
+pub use mock_backend::*;
+#[cfg(target_os = "linux")]
+pub use netlink::NetlinkBackend;
+pub use netns::Netns;
+pub use reconcile::*;
+pub use status::*;
+#[cfg(target_family = "unix")]
+pub use uapi::*;
 pub use wg_config::*;
+pub use wg_config_parser::*;
 pub use wg_quick::*;
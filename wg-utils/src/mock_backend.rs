@@ -0,0 +1,125 @@
+use crate::{
+    InterfaceStatus, PeerStatus, PrivateKey, PublicKey, WireguardBackend, WireguardConfig,
+    WireguardPeer,
+};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Interface kept by `MockBackend`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockInterface {
+    pub public_key: Option<PublicKey>,
+    pub listen_port: Option<u16>,
+    pub peers: Vec<WireguardPeer>,
+}
+
+/// Backend keeping interfaces in memory, so the code driving it can run without root
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    interfaces: Mutex<HashMap<String, MockInterface>>,
+}
+
+impl MockBackend {
+    /// Current state of interface `name`, if it exists
+    pub fn interface(&self, name: &str) -> Option<MockInterface> {
+        self.interfaces
+            .lock()
+            .expect("Interfaces lock is poisoned")
+            .get(name)
+            .cloned()
+    }
+
+    fn with_interface<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&mut MockInterface) -> Result<T>,
+    ) -> Result<T> {
+        let mut interfaces = self.interfaces.lock().expect("Interfaces lock is poisoned");
+        let interface = interfaces
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("No interface {}", name))?;
+        f(interface)
+    }
+}
+
+fn mock_interface(config: &WireguardConfig) -> MockInterface {
+    MockInterface {
+        public_key: config
+            .interface
+            .private_key
+            .as_ref()
+            .map(PrivateKey::public_key),
+        listen_port: config.interface.interface.listen_port,
+        peers: config.peers.clone(),
+    }
+}
+
+#[async_trait::async_trait]
+impl WireguardBackend for MockBackend {
+    async fn create_interface(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        self.interfaces
+            .lock()
+            .expect("Interfaces lock is poisoned")
+            .insert(name.to_owned(), mock_interface(config));
+        Ok(())
+    }
+
+    async fn set_config(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        self.with_interface(name, |interface| {
+            *interface = mock_interface(config);
+            Ok(())
+        })
+    }
+
+    async fn add_peer(&self, name: &str, peer: &WireguardPeer) -> Result<()> {
+        self.with_interface(name, |interface| {
+            interface
+                .peers
+                .retain(|other| other.public_key != peer.public_key);
+            interface.peers.push(peer.clone());
+            Ok(())
+        })
+    }
+
+    async fn remove_peer(&self, name: &str, public_key: &str) -> Result<()> {
+        self.with_interface(name, |interface| {
+            interface.peers.retain(|peer| peer.public_key != public_key);
+            Ok(())
+        })
+    }
+
+    async fn status(&self, name: &str) -> Result<InterfaceStatus> {
+        self.with_interface(name, |interface| {
+            Ok(InterfaceStatus {
+                public_key: interface.public_key,
+                listen_port: interface.listen_port,
+                peers: interface
+                    .peers
+                    .iter()
+                    .map(|peer| {
+                        Ok(PeerStatus {
+                            endpoint: peer
+                                .endpoint
+                                .as_ref()
+                                .and_then(|endpoint| endpoint.parse().ok()),
+                            allowed_ips: peer.allowed_ips.clone(),
+                            preshared_key: peer.preshared_key.clone(),
+                            persistent_keepalive: peer.persistent_keepalive,
+                            ..PeerStatus::new(peer.public_key.parse()?)
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            })
+        })
+    }
+
+    async fn destroy_interface(&self, name: &str) -> Result<()> {
+        self.interfaces
+            .lock()
+            .expect("Interfaces lock is poisoned")
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("No interface {}", name))
+    }
+}
@@ -0,0 +1,1111 @@
+use crate::netns::{configure_link, run_scripts};
+use crate::status::{handshake_time, keepalive};
+use crate::{
+    InterfaceStatus, Netns, PeerStatus, PresharedKey, PublicKey, WireguardBackend, WireguardConfig,
+    WireguardInterfaceScripts, WireguardPeer,
+};
+use anyhow::{anyhow, Context, Result};
+use ipnetwork::IpNetwork;
+use log::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Where `ip netns` keeps named network namespaces
+const NETNS_DIR: &str = "/var/run/netns";
+
+/// Requests setting more peers are split, like `wg` does, to stay well below socket buffer sizes
+const MAX_MESSAGE_LEN: usize = 32 * 1024;
+
+// linux/netlink.h
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_DUMP: u16 = 0x300;
+const NLA_HDRLEN: usize = 4;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+// linux/genetlink.h
+const GENL_HDRLEN: usize = 4;
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+// linux/rtnetlink.h and linux/if_link.h
+const IFINFOMSG_LEN: usize = 16;
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+
+// linux/wireguard.h
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1;
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Netlink attributes being encoded, zeroed when dropped since they can hold keys
+#[derive(Default)]
+struct Attributes(Zeroizing<Vec<u8>>);
+
+impl Attributes {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn put(&mut self, kind: u16, value: &[u8]) -> &mut Self {
+        let len = NLA_HDRLEN + value.len();
+        self.0.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(value);
+        let padded = align(self.0.len());
+        self.0.resize(padded, 0);
+        self
+    }
+
+    fn put_u8(&mut self, kind: u16, value: u8) -> &mut Self {
+        self.put(kind, &[value])
+    }
+
+    fn put_u16(&mut self, kind: u16, value: u16) -> &mut Self {
+        self.put(kind, &value.to_ne_bytes())
+    }
+
+    fn put_u32(&mut self, kind: u16, value: u32) -> &mut Self {
+        self.put(kind, &value.to_ne_bytes())
+    }
+
+    /// Puts `value` as a NUL terminated string
+    fn put_str(&mut self, kind: u16, value: &str) -> &mut Self {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.put(kind, &bytes)
+    }
+
+    fn put_nested(&mut self, kind: u16, nested: &Attributes) -> &mut Self {
+        self.put(kind | NLA_F_NESTED, &nested.0)
+    }
+}
+
+/// Type and value of each attribute in `bytes`
+fn parse_attributes(mut bytes: &[u8]) -> Result<Vec<(u16, &[u8])>> {
+    let mut attributes = vec![];
+    while bytes.len() >= NLA_HDRLEN {
+        let len = usize::from(u16::from_ne_bytes([bytes[0], bytes[1]]));
+        let kind = u16::from_ne_bytes([bytes[2], bytes[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDRLEN || len > bytes.len() {
+            return Err(anyhow!("Invalid netlink attribute length {}", len));
+        }
+        attributes.push((kind, &bytes[NLA_HDRLEN..len]));
+        bytes = &bytes[align(len).min(bytes.len())..];
+    }
+    Ok(attributes)
+}
+
+fn parse_u16(value: &[u8]) -> Result<u16> {
+    Ok(u16::from_ne_bytes(value.try_into()?))
+}
+
+fn parse_u32(value: &[u8]) -> Result<u32> {
+    Ok(u32::from_ne_bytes(value.try_into()?))
+}
+
+fn parse_u64(value: &[u8]) -> Result<u64> {
+    Ok(u64::from_ne_bytes(value.try_into()?))
+}
+
+fn parse_key(value: &[u8]) -> Result<[u8; 32]> {
+    value
+        .try_into()
+        .map_err(|_| anyhow!("Invalid key length {}", value.len()))
+}
+
+/// Netlink request, a fixed header for its family followed by attributes
+struct Request {
+    kind: u16,
+    flags: u16,
+    header: Vec<u8>,
+    attributes: Attributes,
+}
+
+impl Request {
+    /// Generic netlink request of `command` to `family`
+    fn generic(family: u16, command: u8, version: u8, flags: u16) -> Self {
+        Self {
+            kind: family,
+            flags,
+            header: vec![command, version, 0, 0],
+            attributes: Default::default(),
+        }
+    }
+
+    /// Routing request about link `name`
+    fn link(kind: u16, flags: u16, name: &str) -> Self {
+        let mut request = Self {
+            kind,
+            flags,
+            // Struct ifinfomsg, all unspecified since links are picked by name
+            header: vec![0; IFINFOMSG_LEN],
+            attributes: Default::default(),
+        };
+        request.attributes.put_str(IFLA_IFNAME, name);
+        request
+    }
+
+    fn len(&self) -> usize {
+        NLMSG_HDRLEN + self.header.len() + self.attributes.len()
+    }
+
+    fn encode(&self, seq: u32) -> Zeroizing<Vec<u8>> {
+        let mut message = Zeroizing::new(Vec::with_capacity(self.len()));
+        message.extend_from_slice(&(self.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&self.kind.to_ne_bytes());
+        message.extend_from_slice(&self.flags.to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        // Port ID 0 addresses the kernel
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&self.header);
+        message.extend_from_slice(&self.attributes.0);
+        message
+    }
+}
+
+/// Netlink socket of the current network namespace or of a named one
+struct Socket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Socket {
+    fn open(protocol: libc::c_int, netns: Option<&Netns>) -> Result<Self> {
+        let fd = match netns {
+            None => Self::open_fd(protocol)?,
+            Some(netns) => {
+                let path = Path::new(NETNS_DIR).join(netns.name());
+                // Sockets stay in the namespace they were opened in. setns only moves the
+                // calling thread, one that exits right after so no other work runs in it.
+                std::thread::spawn(move || -> Result<OwnedFd> {
+                    let file = File::open(&path)
+                        .with_context(|| format!("Could not open network namespace {:?}", path))?;
+                    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+                        return Err(io::Error::last_os_error())
+                            .with_context(|| format!("Could not enter {:?}", path));
+                    }
+                    Self::open_fd(protocol)
+                })
+                .join()
+                .map_err(|_| anyhow!("Opening a netlink socket in {} panicked", netns))??
+            }
+        };
+        Ok(Self { fd, seq: 0 })
+    }
+
+    fn open_fd(protocol: libc::c_int) -> Result<OwnedFd> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Could not open netlink socket");
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    fn send(&mut self, request: &Request) -> Result<u32> {
+        self.seq = self.seq.wrapping_add(1);
+        let message = request.encode(self.seq);
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let sent = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                message.as_ptr().cast(),
+                message.len(),
+                0,
+                (&address as *const libc::sockaddr_nl).cast(),
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error()).context("Could not send netlink request");
+        }
+        Ok(self.seq)
+    }
+
+    fn receive(&self) -> Result<Vec<u8>> {
+        // Peeking with MSG_TRUNC gives the size of the next datagram without reading it
+        let len = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                std::ptr::null_mut(),
+                0,
+                libc::MSG_PEEK | libc::MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error()).context("Could not receive netlink response");
+        }
+        let mut buf = vec![0; len as usize];
+        let len = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error()).context("Could not receive netlink response");
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+
+    /// Sends `request` and returns the payloads of the response messages, without their
+    /// netlink header, until the kernel acknowledges the request or ends the dump
+    fn request(&mut self, request: &Request) -> Result<Vec<Vec<u8>>> {
+        let seq = self.send(request)?;
+        let mut payloads = vec![];
+        loop {
+            let datagram = self.receive()?;
+            let mut messages = datagram.as_slice();
+            while messages.len() >= NLMSG_HDRLEN {
+                let len = parse_u32(&messages[..4])? as usize;
+                let kind = parse_u16(&messages[4..6])?;
+                let message_seq = parse_u32(&messages[8..12])?;
+                if len < NLMSG_HDRLEN || len > messages.len() {
+                    return Err(anyhow!("Invalid netlink message length {}", len));
+                }
+                let payload = &messages[NLMSG_HDRLEN..len];
+                messages = &messages[align(len).min(messages.len())..];
+                if message_seq != seq {
+                    continue;
+                }
+                match kind {
+                    NLMSG_ERROR | NLMSG_DONE => {
+                        // Errors of acknowledgements and of dumps are negative errnos, 0 on success
+                        let error = match payload.get(..4) {
+                            Some(error) => i32::from_ne_bytes(error.try_into()?),
+                            None => 0,
+                        };
+                        if error < 0 {
+                            return Err(io::Error::from_raw_os_error(-error).into());
+                        }
+                        return Ok(payloads);
+                    }
+                    _ => payloads.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+
+    /// ID of generic netlink family `name`
+    fn resolve_family(&mut self, name: &str) -> Result<u16> {
+        let mut request = Request::generic(
+            GENL_ID_CTRL,
+            CTRL_CMD_GETFAMILY,
+            1,
+            NLM_F_REQUEST | NLM_F_ACK,
+        );
+        request.attributes.put_str(CTRL_ATTR_FAMILY_NAME, name);
+        let payloads = self
+            .request(&request)
+            .with_context(|| format!("Could not find generic netlink family {}", name))?;
+        for payload in payloads.iter() {
+            for (kind, value) in parse_attributes(payload.get(GENL_HDRLEN..).unwrap_or_default())? {
+                if kind == CTRL_ATTR_FAMILY_ID {
+                    return parse_u16(value);
+                }
+            }
+        }
+        Err(anyhow!("Generic netlink family {} has no ID", name))
+    }
+}
+
+fn encode_sockaddr(address: &SocketAddr) -> Vec<u8> {
+    let mut bytes = vec![];
+    match address {
+        SocketAddr::V4(address) => {
+            // Struct sockaddr_in
+            bytes.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            bytes.extend_from_slice(&address.port().to_be_bytes());
+            bytes.extend_from_slice(&address.ip().octets());
+            bytes.extend_from_slice(&[0; 8]);
+        }
+        SocketAddr::V6(address) => {
+            // Struct sockaddr_in6
+            bytes.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            bytes.extend_from_slice(&address.port().to_be_bytes());
+            bytes.extend_from_slice(&address.flowinfo().to_be_bytes());
+            bytes.extend_from_slice(&address.ip().octets());
+            bytes.extend_from_slice(&address.scope_id().to_ne_bytes());
+        }
+    }
+    bytes
+}
+
+fn parse_sockaddr(bytes: &[u8]) -> Result<SocketAddr> {
+    let family = parse_u16(bytes.get(..2).unwrap_or_default())?;
+    let port = u16::from_be_bytes(bytes.get(2..4).unwrap_or_default().try_into()?);
+    match (i32::from(family), bytes.len()) {
+        (libc::AF_INET, 16) => {
+            let ip: [u8; 4] = bytes[4..8].try_into()?;
+            Ok(SocketAddrV4::new(Ipv4Addr::from(ip), port).into())
+        }
+        (libc::AF_INET6, 28) => {
+            let ip: [u8; 16] = bytes[8..24].try_into()?;
+            Ok(SocketAddrV6::new(
+                Ipv6Addr::from(ip),
+                port,
+                u32::from_be_bytes(bytes[4..8].try_into()?),
+                u32::from_ne_bytes(bytes[24..28].try_into()?),
+            )
+            .into())
+        }
+        _ => Err(anyhow!("Unsupported endpoint address family {}", family)),
+    }
+}
+
+fn encode_allowed_ip(network: &IpNetwork) -> Attributes {
+    let mut attributes = Attributes::default();
+    match network.ip() {
+        IpAddr::V4(ip) => attributes
+            .put_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET as u16)
+            .put(WGALLOWEDIP_A_IPADDR, &ip.octets()),
+        IpAddr::V6(ip) => attributes
+            .put_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET6 as u16)
+            .put(WGALLOWEDIP_A_IPADDR, &ip.octets()),
+    };
+    attributes.put_u8(WGALLOWEDIP_A_CIDR_MASK, network.prefix());
+    attributes
+}
+
+fn parse_allowed_ip(bytes: &[u8]) -> Result<IpNetwork> {
+    let mut ip = None;
+    let mut prefix = None;
+    for (kind, value) in parse_attributes(bytes)? {
+        match kind {
+            WGALLOWEDIP_A_IPADDR => {
+                ip = Some(match value.len() {
+                    4 => IpAddr::from(TryInto::<[u8; 4]>::try_into(value)?),
+                    16 => IpAddr::from(TryInto::<[u8; 16]>::try_into(value)?),
+                    len => return Err(anyhow!("Invalid allowed IP length {}", len)),
+                })
+            }
+            WGALLOWEDIP_A_CIDR_MASK => prefix = value.first().copied(),
+            _ => (),
+        }
+    }
+    match (ip, prefix) {
+        (Some(ip), Some(prefix)) => Ok(IpNetwork::new(ip, prefix)?),
+        _ => Err(anyhow!("Allowed IP without address or mask")),
+    }
+}
+
+/// Change to a peer in a device update
+enum PeerSettings {
+    /// Sets every setting of `peer`, with its endpoint resolved to an address
+    Set(WireguardPeer, Option<SocketAddr>),
+    Remove(PublicKey),
+}
+
+impl PeerSettings {
+    fn encode(&self) -> Result<Attributes> {
+        let mut attributes = Attributes::default();
+        match self {
+            Self::Set(peer, endpoint) => {
+                let public_key: PublicKey = peer.public_key.parse()?;
+                // An all zero key removes the preshared key
+                let preshared_key = peer
+                    .preshared_key
+                    .as_ref()
+                    .map(|preshared_key| preshared_key.as_bytes())
+                    .unwrap_or(&[0; 32]);
+                let keepalive = peer
+                    .persistent_keepalive
+                    .map(|keepalive| keepalive.as_secs())
+                    .unwrap_or(0);
+                attributes
+                    .put(WGPEER_A_PUBLIC_KEY, public_key.as_bytes())
+                    .put(WGPEER_A_PRESHARED_KEY, preshared_key)
+                    .put_u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS)
+                    .put_u16(
+                        WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL,
+                        keepalive.try_into().unwrap_or(u16::MAX),
+                    );
+                if let Some(endpoint) = endpoint {
+                    attributes.put(WGPEER_A_ENDPOINT, &encode_sockaddr(endpoint));
+                }
+                let mut allowed_ips = Attributes::default();
+                for network in peer.allowed_ips.iter() {
+                    allowed_ips.put_nested(0, &encode_allowed_ip(network));
+                }
+                attributes.put_nested(WGPEER_A_ALLOWEDIPS, &allowed_ips);
+            }
+            Self::Remove(public_key) => {
+                attributes
+                    .put(WGPEER_A_PUBLIC_KEY, public_key.as_bytes())
+                    .put_u32(WGPEER_A_FLAGS, WGPEER_F_REMOVE_ME);
+            }
+        }
+        Ok(attributes)
+    }
+}
+
+/// WireGuard settings to set on a device, settings that are `None` are left as they are
+#[derive(Default)]
+struct DeviceUpdate {
+    private_key: Option<Zeroizing<[u8; 32]>>,
+    listen_port: Option<u16>,
+    fw_mark: Option<u32>,
+    /// Whether peers not in `peers` are removed
+    replace_peers: bool,
+    peers: Vec<PeerSettings>,
+}
+
+impl DeviceUpdate {
+    /// Requests making the update on device `name`. Peers are split over several requests
+    /// when there are many, only the first one replaces the peers of the device.
+    fn requests(&self, family: u16, name: &str) -> Result<Vec<Request>> {
+        let new_request = || {
+            let mut request = Request::generic(
+                family,
+                WG_CMD_SET_DEVICE,
+                WG_GENL_VERSION,
+                NLM_F_REQUEST | NLM_F_ACK,
+            );
+            request.attributes.put_str(WGDEVICE_A_IFNAME, name);
+            request
+        };
+
+        let mut request = new_request();
+        if let Some(private_key) = self.private_key.as_ref() {
+            request
+                .attributes
+                .put(WGDEVICE_A_PRIVATE_KEY, &**private_key);
+        }
+        if let Some(listen_port) = self.listen_port {
+            request
+                .attributes
+                .put_u16(WGDEVICE_A_LISTEN_PORT, listen_port);
+        }
+        if let Some(fw_mark) = self.fw_mark {
+            request.attributes.put_u32(WGDEVICE_A_FWMARK, fw_mark);
+        }
+        if self.replace_peers {
+            request
+                .attributes
+                .put_u32(WGDEVICE_A_FLAGS, WGDEVICE_F_REPLACE_PEERS);
+        }
+
+        let mut requests = vec![];
+        let mut peers = Attributes::default();
+        for settings in self.peers.iter() {
+            let peer = settings.encode()?;
+            let full = request.len() + 2 * NLA_HDRLEN + peers.len() + peer.len() > MAX_MESSAGE_LEN;
+            if full && peers.len() > 0 {
+                request
+                    .attributes
+                    .put_nested(WGDEVICE_A_PEERS, &std::mem::take(&mut peers));
+                requests.push(std::mem::replace(&mut request, new_request()));
+            }
+            peers.put_nested(0, &peer);
+        }
+        if peers.len() > 0 {
+            request.attributes.put_nested(WGDEVICE_A_PEERS, &peers);
+        }
+        requests.push(request);
+        Ok(requests)
+    }
+}
+
+fn parse_peer(bytes: &[u8]) -> Result<PeerStatus> {
+    let attributes = parse_attributes(bytes)?;
+    let public_key = attributes
+        .iter()
+        .find(|(kind, _)| *kind == WGPEER_A_PUBLIC_KEY)
+        .ok_or_else(|| anyhow!("Peer without public key"))?
+        .1;
+    let mut peer = PeerStatus::new(PublicKey::from(parse_key(public_key)?));
+    for (kind, value) in attributes {
+        match kind {
+            WGPEER_A_PRESHARED_KEY => {
+                peer.preshared_key =
+                    Some(PresharedKey::from_bytes(Zeroizing::new(parse_key(value)?)))
+                        .filter(|key| key.as_bytes().iter().any(|byte| *byte != 0))
+            }
+            WGPEER_A_ENDPOINT => peer.endpoint = Some(parse_sockaddr(value)?),
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => {
+                peer.persistent_keepalive = keepalive(parse_u16(value)?.into())
+            }
+            WGPEER_A_LAST_HANDSHAKE_TIME => {
+                // Struct __kernel_timespec
+                if value.len() != 16 {
+                    return Err(anyhow!("Invalid handshake time length {}", value.len()));
+                }
+                let secs = i64::from_ne_bytes(value[..8].try_into()?);
+                let nanos = i64::from_ne_bytes(value[8..].try_into()?);
+                peer.latest_handshake =
+                    handshake_time(secs.try_into().unwrap_or(0), nanos.try_into().unwrap_or(0));
+            }
+            WGPEER_A_RX_BYTES => peer.rx_bytes = parse_u64(value)?,
+            WGPEER_A_TX_BYTES => peer.tx_bytes = parse_u64(value)?,
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, allowed_ip) in parse_attributes(value)? {
+                    peer.allowed_ips.push(parse_allowed_ip(allowed_ip)?);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(peer)
+}
+
+/// Parses the messages of a device dump. A peer with many allowed IPs can be split over
+/// messages, its public key is repeated at the start of the next message.
+fn parse_device(payloads: &[Vec<u8>]) -> Result<InterfaceStatus> {
+    let mut status = InterfaceStatus::default();
+    for payload in payloads {
+        for (kind, value) in parse_attributes(payload.get(GENL_HDRLEN..).unwrap_or_default())? {
+            match kind {
+                WGDEVICE_A_PUBLIC_KEY => {
+                    status.public_key = Some(parse_key(value)?)
+                        .filter(|key| key.iter().any(|byte| *byte != 0))
+                        .map(PublicKey::from)
+                }
+                WGDEVICE_A_LISTEN_PORT => {
+                    status.listen_port = Some(parse_u16(value)?).filter(|port| *port != 0)
+                }
+                WGDEVICE_A_PEERS => {
+                    for (_, peer) in parse_attributes(value)? {
+                        let peer = parse_peer(peer)?;
+                        match status.peers.last_mut() {
+                            Some(last) if last.public_key == peer.public_key => {
+                                last.allowed_ips.extend(peer.allowed_ips)
+                            }
+                            _ => status.peers.push(peer),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// Whether `err` is the kernel reporting that there is no such interface
+fn is_no_device(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .and_then(io::Error::raw_os_error)
+        == Some(libc::ENODEV)
+}
+
+/// Backend configuring kernel WireGuard interfaces over netlink, the interface `wg` and
+/// `ip` use, without running them or writing configuration files
+#[derive(Debug, Default)]
+pub struct NetlinkBackend {
+    /// Namespace created interfaces are moved to
+    netns: Option<Netns>,
+    /// Scripts of created interfaces, run when destroying them
+    scripts: Mutex<HashMap<String, WireguardInterfaceScripts>>,
+}
+
+impl NetlinkBackend {
+    /// Moves created interfaces to `netns`, their UDP sockets stay in the current namespace
+    pub fn with_netns(self, netns: Option<Netns>) -> Self {
+        Self { netns, ..self }
+    }
+
+    /// Runs `f` with a netlink socket of `protocol` in `netns`, on a thread that can block
+    async fn with_socket<T, F>(netns: Option<Netns>, protocol: libc::c_int, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Socket) -> Result<T> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || f(&mut Socket::open(protocol, netns.as_ref())?)).await?
+    }
+
+    /// Makes `update` on interface `name` through the WireGuard generic netlink family
+    async fn set_device(&self, name: &str, update: DeviceUpdate) -> Result<()> {
+        let name = name.to_owned();
+        Self::with_socket(self.netns.clone(), libc::NETLINK_GENERIC, move |socket| {
+            let family = socket.resolve_family(WG_GENL_NAME)?;
+            for request in update.requests(family, &name)? {
+                socket
+                    .request(&request)
+                    .with_context(|| format!("Could not configure {}", name))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_link(&self, name: &str) -> Result<()> {
+        let name = name.to_owned();
+        // Created in the current namespace, where WireGuard then keeps the UDP socket
+        Self::with_socket(None, libc::NETLINK_ROUTE, move |socket| {
+            let mut request = Request::link(
+                RTM_NEWLINK,
+                NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+                &name,
+            );
+            let mut link_info = Attributes::default();
+            link_info.put_str(IFLA_INFO_KIND, WG_GENL_NAME);
+            request.attributes.put_nested(IFLA_LINKINFO, &link_info);
+            socket
+                .request(&request)
+                .with_context(|| format!("Could not create WireGuard interface {}", name))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deletes link `name`, returns whether there was one
+    async fn delete_link(&self, name: &str) -> Result<bool> {
+        let name = name.to_owned();
+        Self::with_socket(
+            self.netns.clone(),
+            libc::NETLINK_ROUTE,
+            move |socket| match socket.request(&Request::link(
+                RTM_DELLINK,
+                NLM_F_REQUEST | NLM_F_ACK,
+                &name,
+            )) {
+                Ok(_) => Ok(true),
+                Err(err) if is_no_device(&err) => Ok(false),
+                Err(err) => Err(err.context(format!("Could not delete interface {}", name))),
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl WireguardBackend for NetlinkBackend {
+    async fn create_interface(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        info!("Bringing {} up", name);
+        if !config.interface.interface.dns.is_empty() {
+            warn!(
+                "Not setting DNS servers of {}, not supported by the netlink backend",
+                name
+            );
+        }
+        let scripts = &config.interface.scripts;
+        let netns = self.netns.as_ref();
+        run_scripts(netns, name, &scripts.pre_up).await?;
+        if self.delete_link(name).await? {
+            debug!("Replaced existing interface {}", name);
+        }
+        self.create_link(name).await?;
+        if let Some(netns) = netns {
+            netns.move_interface(name).await?;
+        }
+        self.set_config(name, config).await?;
+        configure_link(netns, name, config).await?;
+        run_scripts(netns, name, &scripts.post_up).await?;
+        self.scripts
+            .lock()
+            .expect("Scripts lock is poisoned")
+            .insert(name.to_owned(), scripts.clone());
+        Ok(())
+    }
+
+    async fn set_config(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        debug!("Setting configuration of {}", name);
+        let mut peers = Vec::with_capacity(config.peers.len());
+        for peer in config.peers.iter() {
+            peers.push(PeerSettings::Set(
+                peer.clone(),
+                resolve_endpoint(peer).await?,
+            ));
+        }
+        let interface = &config.interface;
+        self.set_device(
+            name,
+            DeviceUpdate {
+                private_key: interface
+                    .private_key
+                    .as_ref()
+                    .map(|private_key| Zeroizing::new(*private_key.as_bytes())),
+                listen_port: interface.interface.listen_port,
+                fw_mark: interface.interface.fw_mark,
+                replace_peers: true,
+                peers,
+            },
+        )
+        .await
+    }
+
+    async fn add_peer(&self, name: &str, peer: &WireguardPeer) -> Result<()> {
+        debug!("Setting peer {} on {}", peer.public_key, name);
+        let settings = PeerSettings::Set(peer.clone(), resolve_endpoint(peer).await?);
+        self.set_device(
+            name,
+            DeviceUpdate {
+                peers: vec![settings],
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn remove_peer(&self, name: &str, public_key: &str) -> Result<()> {
+        debug!("Removing peer {} from {}", public_key, name);
+        self.set_device(
+            name,
+            DeviceUpdate {
+                peers: vec![PeerSettings::Remove(public_key.parse()?)],
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn status(&self, name: &str) -> Result<InterfaceStatus> {
+        let name = name.to_owned();
+        Self::with_socket(self.netns.clone(), libc::NETLINK_GENERIC, move |socket| {
+            let family = socket.resolve_family(WG_GENL_NAME)?;
+            let mut request = Request::generic(
+                family,
+                WG_CMD_GET_DEVICE,
+                WG_GENL_VERSION,
+                NLM_F_REQUEST | NLM_F_DUMP,
+            );
+            request.attributes.put_str(WGDEVICE_A_IFNAME, &name);
+            let payloads = socket
+                .request(&request)
+                .with_context(|| format!("Could not read status of {}", name))?;
+            parse_device(&payloads)
+        })
+        .await
+    }
+
+    async fn destroy_interface(&self, name: &str) -> Result<()> {
+        info!("Taking {} down", name);
+        let scripts = self
+            .scripts
+            .lock()
+            .expect("Scripts lock is poisoned")
+            .remove(name)
+            .unwrap_or_default();
+        let netns = self.netns.as_ref();
+        run_scripts(netns, name, &scripts.pre_down).await?;
+        if !self.delete_link(name).await? {
+            return Err(anyhow!("No interface {}", name));
+        }
+        run_scripts(netns, name, &scripts.post_down).await?;
+        Ok(())
+    }
+}
+
+/// Endpoint of `peer` resolved to an address, netlink only takes addresses
+async fn resolve_endpoint(peer: &WireguardPeer) -> Result<Option<SocketAddr>> {
+    match peer.endpoint.as_ref() {
+        None => Ok(None),
+        Some(endpoint) => tokio::net::lookup_host(endpoint)
+            .await?
+            .next()
+            .map(Some)
+            .ok_or_else(|| anyhow!("Endpoint {} has no addresses", endpoint)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FullWireguardInterface, WgKeyPair, WireguardInterface};
+    use std::time::Duration;
+
+    fn peer(index: u16) -> Result<WireguardPeer> {
+        Ok(WireguardPeer {
+            public_key: WgKeyPair::new().public_key.to_string(),
+            preshared_key: Some(base64::encode([(index % 255) as u8 + 1; 32]).parse()?),
+            allowed_ips: vec![
+                format!("10.{}.{}.0/24", index >> 8, index & 0xff).parse()?,
+                format!("fd00::{:x}/128", index).parse()?,
+            ],
+            endpoint: None,
+            persistent_keepalive: Some(Duration::from_secs(25)),
+        })
+    }
+
+    /// Peers read back from set requests, which use the same attributes as dumps
+    fn request_peers(requests: &[Request]) -> Result<Vec<PeerStatus>> {
+        let payloads: Vec<Vec<u8>> = requests
+            .iter()
+            .map(|request| request.encode(1)[NLMSG_HDRLEN..].to_vec())
+            .collect();
+        Ok(parse_device(&payloads)?.peers)
+    }
+
+    #[test]
+    fn test_device_requests() -> Result<()> {
+        let peers = (0..1000).map(peer).collect::<Result<Vec<_>>>()?;
+        let endpoint: SocketAddr = "[2001:db8::1]:51820".parse()?;
+        let update = DeviceUpdate {
+            listen_port: Some(51820),
+            replace_peers: true,
+            peers: peers
+                .iter()
+                .enumerate()
+                .map(|(index, peer)| {
+                    PeerSettings::Set(peer.clone(), Some(endpoint).filter(|_| index == 0))
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let requests = update.requests(0x20, "wg0")?;
+        assert!(requests.len() > 1);
+        for (index, request) in requests.iter().enumerate() {
+            assert!(request.len() <= MAX_MESSAGE_LEN);
+            let attributes = parse_attributes(&request.attributes.0)?;
+            let replaces_peers = attributes.iter().any(|(kind, value)| {
+                *kind == WGDEVICE_A_FLAGS && *value == WGDEVICE_F_REPLACE_PEERS.to_ne_bytes()
+            });
+            assert_eq!(replaces_peers, index == 0);
+        }
+
+        let parsed = request_peers(&requests)?;
+        assert_eq!(parsed.len(), peers.len());
+        for (parsed, peer) in parsed.iter().zip(peers.iter()) {
+            assert_eq!(parsed.public_key.to_string(), peer.public_key);
+            assert_eq!(parsed.allowed_ips, peer.allowed_ips);
+            assert_eq!(parsed.preshared_key, peer.preshared_key);
+            assert_eq!(parsed.persistent_keepalive, peer.persistent_keepalive);
+        }
+        assert_eq!(parsed[0].endpoint, Some(endpoint));
+        assert_eq!(parsed[1].endpoint, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_peer() -> Result<()> {
+        // A peer continued in the next message of a dump repeats its public key
+        let first = peer(1)?;
+        let continued = WireguardPeer {
+            preshared_key: None,
+            allowed_ips: vec!["192.168.0.0/16".parse()?],
+            persistent_keepalive: None,
+            ..first.clone()
+        };
+        let requests = [first.clone(), continued]
+            .iter()
+            .map(|peer| {
+                DeviceUpdate {
+                    peers: vec![PeerSettings::Set(peer.clone(), None)],
+                    ..Default::default()
+                }
+                .requests(0x20, "wg0")
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let parsed = request_peers(&requests)?;
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].allowed_ips.len(), 3);
+        assert_eq!(parsed[0].preshared_key, first.preshared_key);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sockaddr() -> Result<()> {
+        for address in ["203.0.113.7:51820", "[2001:db8::1%3]:443"] {
+            let address: SocketAddr = address.parse()?;
+            assert_eq!(parse_sockaddr(&encode_sockaddr(&address))?, address);
+        }
+        assert!(parse_sockaddr(&[0; 4]).is_err());
+        Ok(())
+    }
+
+    /// Reply to `WG_CMD_GET_DEVICE` for `wg0` on a little endian host, laid out the way the
+    /// kernel dumps devices: interface index and name, keys, port and firewall mark, then
+    /// the peers with their handshake time, counters and protocol version, which are never set
+    /// in requests. The second peer has an IPv6 endpoint and continues in the next message.
+    const DEVICE_DUMP: &[u8] = &[
+        0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x01, 0x00, 0x05, 0x00, 0x00, 0x00, 0x08, 0x00, 0x02,
+        0x00, 0x77, 0x67, 0x30, 0x00, 0x24, 0x00, 0x03, 0x00, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x24, 0x00, 0x04, 0x00,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x06, 0x00, 0x06, 0x00, 0x6c, 0xca, 0x00, 0x00, 0x08, 0x00, 0x07, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x9c, 0x01, 0x08, 0x80, 0xa8, 0x00, 0x00, 0x80, 0x24, 0x00, 0x01, 0x00,
+        0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33,
+        0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33,
+        0x33, 0x33, 0x24, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00,
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x0c, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x08, 0x00, 0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x20, 0x00, 0x09, 0x80, 0x1c, 0x00, 0x00,
+        0x80, 0x06, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x00, 0x02, 0x00, 0x0a, 0x00,
+        0x00, 0x02, 0x05, 0x00, 0x03, 0x00, 0x20, 0x00, 0x00, 0x00, 0xf0, 0x00, 0x01, 0x80, 0x24,
+        0x00, 0x01, 0x00, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44,
+        0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44,
+        0x44, 0x44, 0x44, 0x44, 0x44, 0x24, 0x00, 0x02, 0x00, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+        0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+        0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x14, 0x00, 0x06, 0x00,
+        0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x06, 0x00, 0x05, 0x00, 0x19, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x08, 0x00, 0xd2, 0x04,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x07, 0x00, 0x2e, 0x16, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x08, 0x00, 0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x20, 0x00, 0x04, 0x00,
+        0x0a, 0x00, 0xca, 0x6c, 0x00, 0x00, 0x00, 0x00, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x48, 0x00,
+        0x09, 0x80, 0x1c, 0x00, 0x00, 0x80, 0x06, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08,
+        0x00, 0x02, 0x00, 0x0a, 0x00, 0x00, 0x03, 0x05, 0x00, 0x03, 0x00, 0x20, 0x00, 0x00, 0x00,
+        0x28, 0x00, 0x01, 0x80, 0x06, 0x00, 0x01, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x14, 0x00, 0x02,
+        0x00, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x03, 0x05, 0x00, 0x03, 0x00, 0x80, 0x00, 0x00, 0x00,
+    ];
+
+    /// Rest of the allowed IPs of the second peer, after its public key
+    const DEVICE_DUMP_CONTINUED: &[u8] = &[
+        0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x01, 0x00, 0x05, 0x00, 0x00, 0x00, 0x08, 0x00, 0x02,
+        0x00, 0x77, 0x67, 0x30, 0x00, 0x4c, 0x00, 0x08, 0x80, 0x48, 0x00, 0x00, 0x80, 0x24, 0x00,
+        0x01, 0x00, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44,
+        0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44,
+        0x44, 0x44, 0x44, 0x44, 0x20, 0x00, 0x09, 0x80, 0x1c, 0x00, 0x00, 0x80, 0x06, 0x00, 0x01,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x00, 0x02, 0x00, 0xc0, 0xa8, 0x00, 0x00, 0x05, 0x00,
+        0x03, 0x00, 0x10, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_parse_device_dump() -> Result<()> {
+        let status = parse_device(&[DEVICE_DUMP.to_vec(), DEVICE_DUMP_CONTINUED.to_vec()])?;
+        assert_eq!(status.public_key, Some(PublicKey::from([0x22; 32])));
+        assert_eq!(status.listen_port, Some(51820));
+        assert_eq!(status.peers.len(), 2);
+
+        let first = &status.peers[0];
+        assert_eq!(first.public_key, PublicKey::from([0x33; 32]));
+        assert_eq!(first.preshared_key, None);
+        assert_eq!(first.endpoint, None);
+        assert_eq!(first.persistent_keepalive, None);
+        assert_eq!(first.latest_handshake, None);
+        assert_eq!((first.rx_bytes, first.tx_bytes), (0, 0));
+        assert_eq!(first.allowed_ips, vec!["10.0.0.2/32".parse()?]);
+
+        let second = &status.peers[1];
+        assert_eq!(second.public_key, PublicKey::from([0x44; 32]));
+        assert_eq!(
+            second.preshared_key.as_ref().map(|key| *key.as_bytes()),
+            Some([0x55; 32])
+        );
+        assert_eq!(second.endpoint, Some("[2001:db8::1]:51820".parse()?));
+        assert_eq!(second.persistent_keepalive, Some(Duration::from_secs(25)));
+        assert_eq!(
+            second.latest_handshake,
+            Some(std::time::UNIX_EPOCH + Duration::new(1_700_000_000, 500))
+        );
+        assert_eq!((second.rx_bytes, second.tx_bytes), (5678, 1234));
+        assert_eq!(
+            second.allowed_ips,
+            vec![
+                "10.0.0.3/32".parse()?,
+                "fd00::3/128".parse()?,
+                "192.168.0.0/16".parse()?
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_parse_truncated_dump() {
+        // Cutting the dump inside the peers leaves an attribute longer than the message
+        assert!(parse_device(&[DEVICE_DUMP[..200].to_vec()]).is_err());
+        assert!(parse_device(&[DEVICE_DUMP[..DEVICE_DUMP.len() - 4].to_vec()]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_family() -> Result<()> {
+        let mut socket = Socket::open(libc::NETLINK_GENERIC, None)?;
+        assert_eq!(socket.resolve_family("nlctrl")?, GENL_ID_CTRL);
+        let err = socket.resolve_family("no-such-family").unwrap_err();
+        assert_eq!(
+            err.root_cause()
+                .downcast_ref::<io::Error>()
+                .and_then(io::Error::raw_os_error),
+            Some(libc::ENOENT)
+        );
+        Ok(())
+    }
+
+    /// Needs kernel WireGuard and permission to create interfaces,
+    /// `scripts/userspace-test.sh` runs it in unprivileged namespaces
+    #[tokio::test]
+    #[ignore]
+    async fn test_netlink() -> Result<()> {
+        let backend = NetlinkBackend::default();
+        let key_pair = WgKeyPair::new();
+        let peer = WireguardPeer {
+            endpoint: Some("127.0.0.1:51821".to_owned()),
+            ..peer(2)?
+        };
+        let config = WireguardConfig::new(
+            FullWireguardInterface::new(
+                &key_pair,
+                WireguardInterface {
+                    address: vec!["10.99.0.1/24".parse()?],
+                    listen_port: Some(51820),
+                    ..Default::default()
+                },
+            ),
+            vec![peer.clone()],
+        );
+
+        backend.create_interface("cs-netlink", &config).await?;
+        let status = backend.status("cs-netlink").await?;
+        assert_eq!(status.public_key, Some(key_pair.public_key));
+        assert_eq!(status.listen_port, Some(51820));
+        assert_eq!(status.peers.len(), 1);
+        assert_eq!(status.peers[0].allowed_ips, peer.allowed_ips);
+        assert_eq!(status.peers[0].endpoint, Some("127.0.0.1:51821".parse()?));
+
+        backend.remove_peer("cs-netlink", &peer.public_key).await?;
+        assert!(backend.status("cs-netlink").await?.peers.is_empty());
+
+        backend.destroy_interface("cs-netlink").await?;
+        assert!(backend.status("cs-netlink").await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+#[cfg(target_family = "unix")]
+use crate::command::run_command;
+#[cfg(target_family = "unix")]
+use crate::WireguardConfig;
+use anyhow::{anyhow, Result};
+#[cfg(target_family = "unix")]
+use log::*;
+use std::fmt;
+use std::str::FromStr;
+use tokio::process::Command;
+
+/// Linux network namespace that interfaces are moved to once created.
+/// WireGuard keeps the UDP socket of an interface in the namespace it was created in,
+/// so encrypted traffic uses the host network while the tunnel traffic is isolated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Netns(String);
+
+impl Netns {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Moves interface `name` from the current namespace to this one
+    #[cfg(target_family = "unix")]
+    pub(crate) async fn move_interface(&self, name: &str) -> Result<()> {
+        debug!("Moving {} to network namespace {}", name, self);
+        run_command(Command::new("ip").args(["link", "set", "dev", name, "netns", &self.0]))
+            .await?;
+        Ok(())
+    }
+}
+
+impl FromStr for Netns {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(anyhow!("Network namespaces are only supported on Linux"));
+        }
+        // Named namespaces are files in /var/run/netns
+        if s.is_empty() || s == "." || s == ".." || s.contains('/') || s.contains('\0') {
+            return Err(anyhow!("Invalid network namespace name {:?}", s));
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Netns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Command running `program` in `netns`, or in the current namespace
+pub(crate) fn netns_command(netns: Option<&Netns>, program: &str) -> Command {
+    match netns {
+        None => Command::new(program),
+        Some(netns) => {
+            let mut command = Command::new("ip");
+            command.args(["netns", "exec", netns.name(), program]);
+            command
+        }
+    }
+}
+
+/// `ip` command acting on `netns`, or on the current namespace
+#[cfg(target_os = "linux")]
+fn ip_command(netns: Option<&Netns>) -> Command {
+    let mut command = Command::new("ip");
+    if let Some(netns) = netns {
+        command.args(["-n", netns.name()]);
+    }
+    command
+}
+
+/// Runs wg-quick style hook commands in `netns`, with `%i` replaced by the interface name
+#[cfg(target_family = "unix")]
+pub(crate) async fn run_scripts(
+    netns: Option<&Netns>,
+    name: &str,
+    commands: &[String],
+) -> Result<()> {
+    for command in commands {
+        run_command(
+            netns_command(netns, "sh")
+                .arg("-c")
+                .arg(command.replace("%i", name)),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Sets addresses, MTU and routes of interface `name` in `netns`, the parts of
+/// `config` that WireGuard itself does not cover, and brings it up
+#[cfg(target_os = "linux")]
+pub(crate) async fn configure_link(
+    netns: Option<&Netns>,
+    name: &str,
+    config: &WireguardConfig,
+) -> Result<()> {
+    let interface = &config.interface.interface;
+    for address in interface.address.iter() {
+        run_command(ip_command(netns).args([
+            "address",
+            "replace",
+            &address.to_string(),
+            "dev",
+            name,
+        ]))
+        .await?;
+    }
+    let mut link = ip_command(netns);
+    link.args(["link", "set", "dev", name, "up"]);
+    if let Some(mtu) = interface.mtu {
+        link.args(["mtu", &mtu.to_string()]);
+    }
+    run_command(&mut link).await?;
+
+    let table = interface.table.as_deref().unwrap_or("auto");
+    if table == "off" {
+        return Ok(());
+    }
+    for network in config.peers.iter().flat_map(|peer| peer.allowed_ips.iter()) {
+        let mut route = ip_command(netns);
+        route.args(["route", "replace", &network.to_string(), "dev", name]);
+        if table != "auto" {
+            route.args(["table", table]);
+        }
+        run_command(&mut route).await?;
+    }
+    Ok(())
+}
+
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+pub(crate) async fn configure_link(
+    _netns: Option<&Netns>,
+    name: &str,
+    _config: &WireguardConfig,
+) -> Result<()> {
+    warn!(
+        "Not configuring addresses and routes of {}, only supported on Linux",
+        name
+    );
+    Ok(())
+}
+
+/// Deletes interface `name` from `netns`
+#[cfg(target_os = "linux")]
+pub(crate) async fn delete_link(netns: Option<&Netns>, name: &str) -> Result<()> {
+    run_command(ip_command(netns).args(["link", "delete", "dev", name])).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!("vpn".parse::<Netns>()?.name(), "vpn");
+        assert!("".parse::<Netns>().is_err());
+        assert!("..".parse::<Netns>().is_err());
+        assert!("../vpn".parse::<Netns>().is_err());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_netns_command() -> Result<()> {
+        let netns: Netns = "vpn".parse()?;
+        let command = format!("{:?}", netns_command(Some(&netns), "wg"));
+        assert!(
+            command.contains(r#""ip" "netns" "exec" "vpn" "wg""#),
+            "{}",
+            command
+        );
+
+        let command = format!("{:?}", netns_command(None, "wg"));
+        assert!(!command.contains("netns"), "{}", command);
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    InterfaceStatus, PeerStatus, PublicKey, WireguardBackend, WireguardConfig, WireguardPeer,
+};
+use anyhow::Result;
+use ipnetwork::IpNetwork;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+/// Setting of a peer that differs from its desired value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerChange {
+    AllowedIps,
+    PresharedKey,
+    PersistentKeepalive,
+}
+
+impl fmt::Display for PeerChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::AllowedIps => "allowed IPs",
+            Self::PresharedKey => "preshared key",
+            Self::PersistentKeepalive => "persistent keepalive",
+        })
+    }
+}
+
+/// Peer on the interface whose settings differ from `peer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerUpdate {
+    pub peer: WireguardPeer,
+    pub changes: Vec<PeerChange>,
+}
+
+/// Peer changes bringing an interface to a desired configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerDiff {
+    /// Desired peers missing from the interface
+    pub added: Vec<WireguardPeer>,
+    pub updated: Vec<PeerUpdate>,
+    /// Peers on the interface that are not desired
+    pub removed: Vec<PublicKey>,
+}
+
+fn allowed_ips_set(allowed_ips: &[IpNetwork]) -> BTreeSet<IpNetwork> {
+    allowed_ips.iter().copied().collect()
+}
+
+/// Keepalive as WireGuard stores it, in whole seconds where 0 is off
+fn keepalive_seconds(keepalive: Option<Duration>) -> u64 {
+    keepalive.map(|keepalive| keepalive.as_secs()).unwrap_or(0)
+}
+
+fn peer_changes(desired: &WireguardPeer, actual: &PeerStatus) -> Vec<PeerChange> {
+    let mut changes = vec![];
+    if allowed_ips_set(&desired.allowed_ips) != allowed_ips_set(&actual.allowed_ips) {
+        changes.push(PeerChange::AllowedIps);
+    }
+    if desired.preshared_key != actual.preshared_key {
+        changes.push(PeerChange::PresharedKey);
+    }
+    if keepalive_seconds(desired.persistent_keepalive)
+        != keepalive_seconds(actual.persistent_keepalive)
+    {
+        changes.push(PeerChange::PersistentKeepalive);
+    }
+    changes
+}
+
+impl PeerDiff {
+    /// Compares the peers of `config` with the peers in `status`.
+    /// Endpoints are not compared, peers roam and desired endpoints can be hostnames.
+    pub fn new(config: &WireguardConfig, status: &InterfaceStatus) -> Result<Self> {
+        let actual: HashMap<&PublicKey, &PeerStatus> = status
+            .peers
+            .iter()
+            .map(|peer| (&peer.public_key, peer))
+            .collect();
+
+        let mut diff = Self::default();
+        let mut desired_keys = HashSet::with_capacity(config.peers.len());
+        for peer in config.peers.iter() {
+            let public_key: PublicKey = peer.public_key.parse()?;
+            match actual.get(&public_key) {
+                None => diff.added.push(peer.clone()),
+                Some(actual) => {
+                    let changes = peer_changes(peer, actual);
+                    if !changes.is_empty() {
+                        diff.updated.push(PeerUpdate {
+                            peer: peer.clone(),
+                            changes,
+                        });
+                    }
+                }
+            }
+            desired_keys.insert(public_key);
+        }
+        diff.removed = status
+            .peers
+            .iter()
+            .map(|peer| peer.public_key)
+            .filter(|public_key| !desired_keys.contains(public_key))
+            .collect();
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+
+    /// Makes the changes on interface `name`, removing peers first so addresses
+    /// they held are free for added ones
+    pub async fn apply(&self, backend: &dyn WireguardBackend, name: &str) -> Result<()> {
+        for public_key in self.removed.iter() {
+            backend.remove_peer(name, &public_key.to_string()).await?;
+        }
+        for peer in self
+            .added
+            .iter()
+            .chain(self.updated.iter().map(|update| &update.peer))
+        {
+            backend.add_peer(name, peer).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FullWireguardInterface, MockBackend, WgKeyPair, WireguardInterface};
+
+    fn peer(address: &str) -> Result<WireguardPeer> {
+        Ok(WireguardPeer {
+            public_key: WgKeyPair::new().public_key.to_string(),
+            preshared_key: None,
+            allowed_ips: vec![address.parse()?],
+            endpoint: None,
+            persistent_keepalive: None,
+        })
+    }
+
+    fn config(peers: Vec<WireguardPeer>) -> WireguardConfig {
+        WireguardConfig::new(
+            FullWireguardInterface::new(&WgKeyPair::new(), WireguardInterface::default()),
+            peers,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() -> Result<()> {
+        let backend = MockBackend::default();
+        let kept = peer("10.0.0.2/32")?;
+        let changed = peer("10.0.0.3/32")?;
+        let removed = peer("10.0.0.4/32")?;
+        backend
+            .create_interface(
+                "wg0",
+                &config(vec![kept.clone(), changed.clone(), removed.clone()]),
+            )
+            .await?;
+        let status = backend.status("wg0").await?;
+        assert!(PeerDiff::new(
+            &config(vec![kept.clone(), changed.clone(), removed.clone()]),
+            &status
+        )?
+        .is_empty());
+
+        let added = peer("10.0.0.5/32")?;
+        let changed = WireguardPeer {
+            allowed_ips: vec!["10.0.0.3/32".parse()?, "10.1.0.0/16".parse()?],
+            persistent_keepalive: Some(Duration::from_secs(25)),
+            ..changed
+        };
+        let desired = config(vec![kept, changed.clone(), added.clone()]);
+        let diff = PeerDiff::new(&desired, &status)?;
+        assert_eq!(diff.added, vec![added]);
+        assert_eq!(
+            diff.updated,
+            vec![PeerUpdate {
+                peer: changed,
+                changes: vec![PeerChange::AllowedIps, PeerChange::PersistentKeepalive],
+            }]
+        );
+        assert_eq!(diff.removed, vec![removed.public_key.parse()?]);
+
+        diff.apply(&backend, "wg0").await?;
+        assert!(PeerDiff::new(&desired, &backend.status("wg0").await?)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_ips_order() -> Result<()> {
+        let desired = WireguardPeer {
+            allowed_ips: vec!["10.0.0.2/32".parse()?, "fd00::2/128".parse()?],
+            ..peer("10.0.0.2/32")?
+        };
+        let actual = PeerStatus {
+            allowed_ips: vec!["fd00::2/128".parse()?, "10.0.0.2/32".parse()?],
+            persistent_keepalive: Some(Duration::from_millis(500)),
+            ..PeerStatus::new(desired.public_key.parse()?)
+        };
+        assert!(peer_changes(&desired, &actual).is_empty());
+        Ok(())
+    }
+}
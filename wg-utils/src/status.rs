@@ -0,0 +1,268 @@
+use crate::key_pair::decode_hex_key;
+use crate::{PresharedKey, PrivateKey, PublicKey};
+use anyhow::{anyhow, Result};
+use ipnetwork::IpNetwork;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// State of a WireGuard interface as reported by the kernel or userspace implementation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceStatus {
+    pub public_key: Option<PublicKey>,
+    pub listen_port: Option<u16>,
+    pub peers: Vec<PeerStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub public_key: PublicKey,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNetwork>,
+    pub preshared_key: Option<PresharedKey>,
+    pub persistent_keepalive: Option<Duration>,
+    /// When the last handshake completed, `None` if there was none yet
+    pub latest_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl PeerStatus {
+    pub fn new(public_key: PublicKey) -> Self {
+        Self {
+            public_key,
+            endpoint: None,
+            allowed_ips: vec![],
+            preshared_key: None,
+            persistent_keepalive: None,
+            latest_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+        }
+    }
+}
+
+/// Time of a handshake given in seconds since the epoch, where 0 means there was none
+pub(crate) fn handshake_time(secs: u64, nanos: u32) -> Option<SystemTime> {
+    match secs {
+        0 => None,
+        secs => Some(UNIX_EPOCH + Duration::new(secs, nanos)),
+    }
+}
+
+/// Keepalive given in seconds, where 0 means it is off
+pub(crate) fn keepalive(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|_| secs != 0)
+}
+
+/// Parses `value` unless `wg` printed it as missing
+fn parse_optional<T>(value: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    match value {
+        "(none)" | "off" | "" => Ok(None),
+        value => value.parse().map(Some).map_err(Into::into),
+    }
+}
+
+/// Parses the tab separated output of `wg show <interface> dump`
+pub fn parse_dump(dump: &str) -> Result<InterfaceStatus> {
+    let mut lines = dump.lines().filter(|line| !line.is_empty());
+    let interface: Vec<&str> = lines
+        .next()
+        .ok_or_else(|| anyhow!("Empty interface dump"))?
+        .split('\t')
+        .collect();
+    let (public_key, listen_port) = match interface.as_slice() {
+        [_private_key, public_key, listen_port, _fwmark] => (public_key, listen_port),
+        _ => {
+            return Err(anyhow!(
+                "Unexpected interface line in dump: {:?}",
+                interface
+            ))
+        }
+    };
+
+    let peers = lines
+        .map(|line| match line.split('\t').collect::<Vec<_>>().as_slice() {
+            [public_key, preshared_key, endpoint, allowed_ips, latest_handshake, rx_bytes, tx_bytes, persistent_keepalive] => {
+                Ok(PeerStatus {
+                    public_key: public_key.parse()?,
+                    endpoint: parse_optional(endpoint)?,
+                    allowed_ips: match *allowed_ips {
+                        "(none)" => vec![],
+                        allowed_ips => allowed_ips
+                            .split(',')
+                            .map(|network| network.parse())
+                            .collect::<Result<_, _>>()?,
+                    },
+                    preshared_key: parse_optional(preshared_key)?,
+                    persistent_keepalive: parse_optional(persistent_keepalive)?
+                        .and_then(keepalive),
+                    latest_handshake: handshake_time(latest_handshake.parse()?, 0),
+                    rx_bytes: rx_bytes.parse()?,
+                    tx_bytes: tx_bytes.parse()?,
+                })
+            }
+            fields => Err(anyhow!("Unexpected peer line in dump: {:?}", fields)),
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(InterfaceStatus {
+        public_key: parse_optional(public_key)?,
+        listen_port: parse_optional::<u16>(listen_port)?.filter(|port| *port != 0),
+        peers,
+    })
+}
+
+/// Parses the response to a UAPI `get=1` request, without the trailing `errno` line
+pub fn parse_uapi(response: &str) -> Result<InterfaceStatus> {
+    let mut status = InterfaceStatus::default();
+    // Handshake seconds and nanoseconds come on separate lines
+    let mut handshake_secs = 0;
+    for line in response.lines().filter(|line| !line.is_empty()) {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Unexpected UAPI line: {}", line))?;
+        match key {
+            "private_key" => {
+                let private_key = PrivateKey::from_bytes(*decode_hex_key(value)?);
+                status.public_key = Some(private_key.public_key());
+            }
+            "listen_port" => status.listen_port = Some(value.parse()?).filter(|port| *port != 0),
+            "public_key" => status
+                .peers
+                .push(PeerStatus::new(PublicKey::from(*decode_hex_key(value)?))),
+            "endpoint"
+            | "allowed_ip"
+            | "preshared_key"
+            | "persistent_keepalive_interval"
+            | "last_handshake_time_sec"
+            | "last_handshake_time_nsec"
+            | "rx_bytes"
+            | "tx_bytes" => {
+                let peer = status
+                    .peers
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("UAPI {} before any peer", key))?;
+                match key {
+                    "endpoint" => peer.endpoint = Some(value.parse()?),
+                    "allowed_ip" => peer.allowed_ips.push(value.parse()?),
+                    "preshared_key" => {
+                        peer.preshared_key = Some(PresharedKey::from_bytes(decode_hex_key(value)?))
+                            .filter(|key| key.as_bytes().iter().any(|byte| *byte != 0))
+                    }
+                    "persistent_keepalive_interval" => {
+                        peer.persistent_keepalive = keepalive(value.parse()?)
+                    }
+                    "last_handshake_time_sec" => {
+                        handshake_secs = value.parse()?;
+                        peer.latest_handshake = handshake_time(handshake_secs, 0);
+                    }
+                    "last_handshake_time_nsec" => {
+                        peer.latest_handshake = handshake_time(handshake_secs, value.parse()?);
+                    }
+                    "rx_bytes" => peer.rx_bytes = value.parse()?,
+                    _ => peer.tx_bytes = value.parse()?,
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Output of `wg show wg0 dump` with a connected peer and one that never completed a handshake
+    const DUMP: &str = "\
+cHJpdmF0ZWtleXByaXZhdGVrZXlwcml2YXRla2V5cHI=\tWmn0rCT9BhG2ZxmJAxl3cTg8sbLA2UDaixKKatBHeWg=\t51820\toff
+xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\t(none)\t203.0.113.7:40312\t172.25.0.2/32\t1624452003\t18760\t14532\toff
+TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=\t(none)\t(none)\t172.25.0.3/32,fd00::3/128\t0\t0\t0\t25
+";
+
+    #[test]
+    fn test_parse_dump() -> Result<()> {
+        let status = parse_dump(DUMP)?;
+        assert_eq!(
+            status.public_key,
+            Some("Wmn0rCT9BhG2ZxmJAxl3cTg8sbLA2UDaixKKatBHeWg=".parse()?)
+        );
+        assert_eq!(status.listen_port, Some(51820));
+        assert_eq!(status.peers.len(), 2);
+
+        let connected = &status.peers[0];
+        assert_eq!(connected.endpoint, Some("203.0.113.7:40312".parse()?));
+        assert_eq!(connected.allowed_ips, vec!["172.25.0.2/32".parse()?]);
+        assert_eq!(
+            connected.latest_handshake,
+            Some(UNIX_EPOCH + Duration::from_secs(1624452003))
+        );
+        assert_eq!((connected.rx_bytes, connected.tx_bytes), (18760, 14532));
+
+        let idle = &status.peers[1];
+        assert_eq!(idle.endpoint, None);
+        assert_eq!(idle.allowed_ips.len(), 2);
+        assert_eq!(idle.latest_handshake, None);
+        assert_eq!(connected.persistent_keepalive, None);
+        assert_eq!(idle.persistent_keepalive, Some(Duration::from_secs(25)));
+        assert!(connected.preshared_key.is_none());
+
+        assert!(parse_dump("").is_err());
+        assert!(parse_dump("a\tb\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_uapi() -> Result<()> {
+        // Response of wireguard-go to `get=1`, before the errno line
+        let status = parse_uapi(
+            "private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a
+listen_port=12912
+public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33
+preshared_key=188515093e952f5f22e865cef3012e72f8b5f0b598ac0309d5dacce3b70fcf52
+allowed_ip=192.168.4.4/32
+endpoint=[abcd:23::33]:51820
+last_handshake_time_sec=1624452003
+last_handshake_time_nsec=500
+tx_bytes=38333
+rx_bytes=2224
+protocol_version=1
+public_key=58402e695ba1772b1cc9309755f043251ea77fdcf10fbe63989ceb7e19321376
+allowed_ip=192.168.4.10/32
+allowed_ip=192.168.4.11/32
+preshared_key=0000000000000000000000000000000000000000000000000000000000000000
+persistent_keepalive_interval=25
+last_handshake_time_sec=0
+last_handshake_time_nsec=0
+tx_bytes=0
+rx_bytes=0
+",
+        )?;
+        assert!(status.public_key.is_some());
+        assert_eq!(status.listen_port, Some(12912));
+        assert_eq!(status.peers.len(), 2);
+        assert_eq!(
+            status.peers[0].latest_handshake,
+            Some(UNIX_EPOCH + Duration::new(1624452003, 500))
+        );
+        assert_eq!(
+            (status.peers[0].rx_bytes, status.peers[0].tx_bytes),
+            (2224, 38333)
+        );
+        assert_eq!(status.peers[1].allowed_ips.len(), 2);
+        assert_eq!(status.peers[1].latest_handshake, None);
+        assert!(status.peers[0].preshared_key.is_some());
+        assert!(status.peers[1].preshared_key.is_none());
+        assert_eq!(status.peers[0].persistent_keepalive, None);
+        assert_eq!(
+            status.peers[1].persistent_keepalive,
+            Some(Duration::from_secs(25))
+        );
+        Ok(())
+    }
+}
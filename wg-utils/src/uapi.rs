@@ -0,0 +1,319 @@
+use crate::command::run_command;
+use crate::key_pair::encode_hex;
+use crate::netns::{configure_link, run_scripts};
+use crate::status::parse_uapi;
+use crate::{
+    InterfaceStatus, Netns, PublicKey, WireguardBackend, WireguardConfig,
+    WireguardInterfaceScripts, WireguardPeer,
+};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use zeroize::Zeroizing;
+
+const DEFAULT_SOCKET_DIR: &str = "/var/run/wireguard";
+const DEFAULT_USERSPACE_COMMAND: &str = "wireguard-go";
+const BORINGTUN_COMMAND: &str = "boringtun-cli";
+
+/// How long a started userspace implementation gets to open its UAPI socket
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Backend configuring a userspace WireGuard implementation, such as wireguard-go or
+/// boringtun, through its UAPI socket instead of running `wg-quick`.
+/// Kernel interfaces have no UAPI socket, they are left to the wg-quick and netlink backends.
+#[derive(Debug)]
+pub struct UapiBackend {
+    socket_dir: PathBuf,
+    /// Started with the interface name when creating an interface without a socket
+    userspace_command: String,
+    /// Passed to `userspace_command` before the interface name
+    userspace_args: Vec<String>,
+    /// Namespace created interfaces are moved to
+    netns: Option<Netns>,
+    /// Scripts of created interfaces, run when destroying them
+    scripts: Mutex<HashMap<String, WireguardInterfaceScripts>>,
+}
+
+impl Default for UapiBackend {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_SOCKET_DIR.into(),
+            DEFAULT_USERSPACE_COMMAND.to_owned(),
+        )
+    }
+}
+
+/// Appends the UAPI lines setting `peer`, resolving its endpoint since UAPI only takes addresses
+async fn write_peer(request: &mut String, peer: &WireguardPeer) -> Result<()> {
+    let public_key: PublicKey = peer.public_key.parse()?;
+    request.push_str(&format!(
+        "public_key={}\n",
+        encode_hex(public_key.as_bytes()).as_str()
+    ));
+    // An all zero key removes the preshared key
+    let preshared_key = peer
+        .preshared_key
+        .as_ref()
+        .map(|preshared_key| preshared_key.as_bytes())
+        .unwrap_or(&[0; 32]);
+    request.push_str(&format!(
+        "preshared_key={}\n",
+        encode_hex(preshared_key).as_str()
+    ));
+    if let Some(endpoint) = peer.endpoint.as_ref() {
+        let address = tokio::net::lookup_host(endpoint)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Endpoint {} has no addresses", endpoint))?;
+        request.push_str(&format!("endpoint={}\n", address));
+    }
+    request.push_str(&format!(
+        "persistent_keepalive_interval={}\n",
+        peer.persistent_keepalive
+            .map(|keepalive| keepalive.as_secs())
+            .unwrap_or(0)
+    ));
+    request.push_str("replace_allowed_ips=true\n");
+    for allowed_ip in peer.allowed_ips.iter() {
+        request.push_str(&format!("allowed_ip={}\n", allowed_ip));
+    }
+    Ok(())
+}
+
+impl UapiBackend {
+    pub fn new(socket_dir: PathBuf, userspace_command: String) -> Self {
+        Self {
+            socket_dir,
+            userspace_command,
+            userspace_args: Vec::new(),
+            netns: None,
+            scripts: Default::default(),
+        }
+    }
+
+    /// Moves created interfaces to `netns`, the userspace implementation and its socket
+    /// stay in the current namespace
+    pub fn with_netns(self, netns: Option<Netns>) -> Self {
+        Self { netns, ..self }
+    }
+
+    /// Backend running boringtun on a TUN device, which needs neither kernel WireGuard
+    /// nor root outside of a user namespace
+    pub fn boringtun() -> Self {
+        Self {
+            // Privileges can't be dropped to a user that is not mapped in a user namespace
+            userspace_args: vec!["--disable-drop-privileges".to_owned()],
+            ..Self::new(DEFAULT_SOCKET_DIR.into(), BORINGTUN_COMMAND.to_owned())
+        }
+    }
+
+    fn socket_path(&self, name: &str) -> PathBuf {
+        self.socket_dir.join(format!("{}.sock", name))
+    }
+
+    /// Sends `request` and returns the response lines before `errno`
+    async fn request(&self, name: &str, request: &str) -> Result<String> {
+        let path = self.socket_path(name);
+        let mut stream = UnixStream::connect(&path)
+            .await
+            .with_context(|| format!("Could not connect to {:?}", path))?;
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut reader = BufReader::new(stream);
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || line == "\n" {
+                break;
+            }
+            match line.trim_end().strip_prefix("errno=") {
+                Some("0") => return Ok(response),
+                Some(errno) => {
+                    return Err(anyhow!("UAPI request to {} failed: errno {}", name, errno))
+                }
+                None => response.push_str(&line),
+            }
+        }
+        Err(anyhow!("UAPI response from {} has no errno", name))
+    }
+
+    async fn set(&self, name: &str, request: &str) -> Result<()> {
+        self.request(name, &format!("set=1\n{}\n", request)).await?;
+        Ok(())
+    }
+
+    /// Starts the userspace implementation for `name` unless it is running,
+    /// returns whether it was started
+    async fn start_userspace(&self, name: &str) -> Result<bool> {
+        if self.socket_path(name).exists() {
+            return Ok(false);
+        }
+        info!("Starting {} for {}", self.userspace_command, name);
+        run_command(
+            Command::new(&self.userspace_command)
+                .args(&self.userspace_args)
+                .arg(name),
+        )
+        .await?;
+
+        let started = Instant::now();
+        while !self.socket_path(name).exists() {
+            if started.elapsed() > SOCKET_TIMEOUT {
+                return Err(anyhow!(
+                    "{} did not open {:?}",
+                    self.userspace_command,
+                    self.socket_path(name)
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait::async_trait]
+impl WireguardBackend for UapiBackend {
+    async fn create_interface(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        info!("Bringing {} up", name);
+        if !config.interface.interface.dns.is_empty() {
+            warn!(
+                "Not setting DNS servers of {}, not supported by the UAPI backend",
+                name
+            );
+        }
+        let scripts = &config.interface.scripts;
+        let netns = self.netns.as_ref();
+        run_scripts(netns, name, &scripts.pre_up).await?;
+        if self.start_userspace(name).await? {
+            if let Some(netns) = netns {
+                netns.move_interface(name).await?;
+            }
+        }
+        self.set_config(name, config).await?;
+        configure_link(netns, name, config).await?;
+        run_scripts(netns, name, &scripts.post_up).await?;
+        self.scripts
+            .lock()
+            .expect("Scripts lock is poisoned")
+            .insert(name.to_owned(), scripts.clone());
+        Ok(())
+    }
+
+    async fn set_config(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        debug!("Setting configuration of {}", name);
+        let interface = &config.interface;
+        let mut request = Zeroizing::new(String::new());
+        if let Some(private_key) = interface.private_key.as_ref() {
+            request.push_str(&format!(
+                "private_key={}\n",
+                encode_hex(private_key.as_bytes()).as_str()
+            ));
+        }
+        request.push_str("replace_peers=true\n");
+        if let Some(listen_port) = interface.interface.listen_port {
+            request.push_str(&format!("listen_port={}\n", listen_port));
+        }
+        if let Some(fw_mark) = interface.interface.fw_mark {
+            request.push_str(&format!("fwmark={}\n", fw_mark));
+        }
+        for peer in config.peers.iter() {
+            write_peer(&mut request, peer).await?;
+        }
+        self.set(name, &request).await
+    }
+
+    async fn add_peer(&self, name: &str, peer: &WireguardPeer) -> Result<()> {
+        debug!("Setting peer {} on {}", peer.public_key, name);
+        let mut request = Zeroizing::new(String::new());
+        write_peer(&mut request, peer).await?;
+        self.set(name, &request).await
+    }
+
+    async fn remove_peer(&self, name: &str, public_key: &str) -> Result<()> {
+        debug!("Removing peer {} from {}", public_key, name);
+        let public_key: PublicKey = public_key.parse()?;
+        self.set(
+            name,
+            &format!(
+                "public_key={}\nremove=true\n",
+                encode_hex(public_key.as_bytes()).as_str()
+            ),
+        )
+        .await
+    }
+
+    async fn status(&self, name: &str) -> Result<InterfaceStatus> {
+        parse_uapi(&self.request(name, "get=1\n\n").await?)
+    }
+
+    async fn destroy_interface(&self, name: &str) -> Result<()> {
+        info!("Taking {} down", name);
+        let scripts = self
+            .scripts
+            .lock()
+            .expect("Scripts lock is poisoned")
+            .remove(name)
+            .unwrap_or_default();
+        let netns = self.netns.as_ref();
+        run_scripts(netns, name, &scripts.pre_down).await?;
+        #[cfg(target_os = "linux")]
+        crate::netns::delete_link(netns, name).await?;
+        // Userspace implementations exit when their socket is removed
+        #[cfg(not(target_os = "linux"))]
+        tokio::fs::remove_file(self.socket_path(name)).await?;
+        run_scripts(netns, name, &scripts.post_down).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FullWireguardInterface, WgKeyPair, WireguardInterface};
+
+    /// Needs boringtun-cli and permission to create interfaces,
+    /// `scripts/userspace-test.sh` runs it in unprivileged namespaces
+    #[tokio::test]
+    #[ignore]
+    async fn test_boringtun() -> Result<()> {
+        let backend = UapiBackend::boringtun();
+        let key_pair = WgKeyPair::new();
+        let peer = WireguardPeer {
+            public_key: WgKeyPair::new().public_key.to_string(),
+            preshared_key: None,
+            allowed_ips: vec!["10.99.0.2/32".parse()?],
+            endpoint: Some("127.0.0.1:51821".to_owned()),
+            persistent_keepalive: None,
+        };
+        let config = WireguardConfig::new(
+            FullWireguardInterface::new(
+                &key_pair,
+                WireguardInterface {
+                    address: vec!["10.99.0.1/24".parse()?],
+                    listen_port: Some(51820),
+                    ..Default::default()
+                },
+            ),
+            vec![peer.clone()],
+        );
+
+        backend.create_interface("cs-test", &config).await?;
+        let status = backend.status("cs-test").await?;
+        assert_eq!(status.public_key, Some(key_pair.public_key));
+        assert_eq!(status.listen_port, Some(51820));
+        assert_eq!(status.peers.len(), 1);
+        assert_eq!(status.peers[0].allowed_ips, peer.allowed_ips);
+
+        backend.remove_peer("cs-test", &peer.public_key).await?;
+        assert!(backend.status("cs-test").await?.peers.is_empty());
+
+        backend.destroy_interface("cs-test").await?;
+        Ok(())
+    }
+}
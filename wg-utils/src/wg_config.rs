@@ -1,46 +1,69 @@
-use crate::WgKeyPair;
+use crate::{PresharedKey, PrivateKey, WgKeyPair};
 use ipnetwork::IpNetwork;
-use log::*;
 use serde::{Deserialize, Serialize};
 use serde_with::rust::StringWithSeparator;
 use serde_with::skip_serializing_none;
 use serde_with::CommaSeparator;
 use std::net::IpAddr;
 use std::time::Duration;
+use zeroize::Zeroizing;
 
 #[skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct WireguardInterface {
-    pub address: IpNetwork,
-    pub dns: Option<IpAddr>,
+    /// Addresses of the interface, wg-quick adds none when empty
+    #[serde(default, with = "StringWithSeparator::<CommaSeparator>")]
+    pub address: Vec<IpNetwork>,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "StringWithSeparator::<CommaSeparator>"
+    )]
+    pub dns: Vec<IpAddr>,
+    /// DNS search domains, written on the DNS line after the servers
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "StringWithSeparator::<CommaSeparator>"
+    )]
+    pub dns_search: Vec<String>,
     pub mtu: Option<u16>,
     pub listen_port: Option<u16>,
+    /// Routing table for the routes of the interface, `off` to add no routes
+    pub table: Option<String>,
+    pub fw_mark: Option<u32>,
+    /// Whether wg-quick saves the running configuration to the file on shutdown
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub save_config: bool,
 }
 
-#[skip_serializing_none]
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Commands run by wg-quick around bringing the interface up and down, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct WireguardInterfaceScripts {
-    pub post_up: Option<String>,
-    pub post_down: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_up: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_up: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_down: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_down: Vec<String>,
 }
 
-#[skip_serializing_none]
-#[derive(Serialize)]
-#[serde(rename_all = "PascalCase")]
+#[derive(Debug, PartialEq, Eq)]
 pub struct FullWireguardInterface {
-    private_key: String,
-    #[serde(flatten)]
-    interface: WireguardInterface,
-    #[serde(flatten)]
-    scripts: WireguardInterfaceScripts,
+    /// `None` for configurations read back from files written without secrets
+    pub(crate) private_key: Option<PrivateKey>,
+    pub(crate) interface: WireguardInterface,
+    pub(crate) scripts: WireguardInterfaceScripts,
 }
 
 impl FullWireguardInterface {
     pub fn new(key_pair: &WgKeyPair, interface: WireguardInterface) -> Self {
         Self {
-            private_key: key_pair.private_key.clone(),
+            private_key: Some(key_pair.private_key.clone()),
             interface,
             scripts: Default::default(),
         }
@@ -52,57 +75,186 @@ impl FullWireguardInterface {
         scripts: WireguardInterfaceScripts,
     ) -> Self {
         Self {
-            private_key: key_pair.private_key.clone(),
+            private_key: Some(key_pair.private_key.clone()),
             interface,
             scripts,
         }
     }
 }
 
+/// Serializes a keepalive as whole seconds, like in wg-quick configuration files
+mod keepalive_seconds {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        keepalive: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        keepalive
+            .map(|keepalive| keepalive.as_secs())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+    }
+}
+
 #[skip_serializing_none]
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct WireguardPeer {
     pub public_key: String,
+    #[serde(default)]
+    pub preshared_key: Option<PresharedKey>,
     #[serde(with = "StringWithSeparator::<CommaSeparator>")]
     pub allowed_ips: Vec<IpNetwork>,
     pub endpoint: Option<String>,
+    #[serde(default, with = "keepalive_seconds")]
     pub persistent_keepalive: Option<Duration>,
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub struct WireguardConfig {
-    interface: FullWireguardInterface,
-    peers: Vec<WireguardPeer>,
+    pub(crate) interface: FullWireguardInterface,
+    pub(crate) peers: Vec<WireguardPeer>,
 }
 
 impl WireguardConfig {
     pub fn new(interface: FullWireguardInterface, peers: Vec<WireguardPeer>) -> Self {
         Self { interface, peers }
     }
-}
 
-impl std::fmt::Display for WireguardConfig {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    pub fn peers(&self) -> &[WireguardPeer] {
+        &self.peers
+    }
+
+    /// Configuration in the format of `wg setconf`, without the fields only wg-quick understands
+    pub fn to_setconf_string(&self) -> Zeroizing<String> {
+        let mut config = Zeroizing::new(String::new());
+        // Writing to a String can't fail
+        let _ = self.write_setconf(&mut *config);
+        config
+    }
+
+    fn write_setconf(&self, f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        let interface = &self.interface.interface;
         writeln!(f, "[Interface]")?;
-        writeln!(
-            f,
-            "{}",
-            serde_ini::to_string(&self.interface).map_err(|e| {
-                warn!("Ignored error while formatting WireguardConfig: {}", e);
-                std::fmt::Error
-            })?
-        )?;
+        if let Some(private_key) = self.interface.private_key.as_ref() {
+            writeln!(f, "PrivateKey = {}", private_key.to_base64().as_str())?;
+        }
+        if let Some(listen_port) = interface.listen_port {
+            writeln!(f, "ListenPort = {}", listen_port)?;
+        }
+        if let Some(fw_mark) = interface.fw_mark {
+            writeln!(f, "FwMark = {:#x}", fw_mark)?;
+        }
+        self.write_peers(f, true)
+    }
+
+    /// Configuration in the format of `wg-quick`, without the private key and preshared keys.
+    /// Safe to write to disk, the keys are set on the interface with [`Self::to_setconf_string`].
+    /// `SaveConfig` is left out as well, `wg-quick down` would write the keys back otherwise.
+    pub fn to_string_without_secrets(&self) -> String {
+        let mut config = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_wg_quick(&mut config, false);
+        config
+    }
+
+    fn write_peers(&self, f: &mut impl std::fmt::Write, secrets: bool) -> std::fmt::Result {
         for peer in self.peers.iter() {
+            writeln!(f)?;
             writeln!(f, "[Peer]")?;
-            writeln!(
-                f,
-                "{}",
-                serde_ini::to_string(&peer).map_err(|e| {
-                    warn!("Ignored error while formatting WireguardConfig: {}", e);
-                    std::fmt::Error
-                })?
-            )?;
+            writeln!(f, "PublicKey = {}", peer.public_key)?;
+            if let Some(preshared_key) = peer.preshared_key.as_ref().filter(|_| secrets) {
+                writeln!(f, "PresharedKey = {}", preshared_key.to_base64().as_str())?;
+            }
+            write_list(f, "AllowedIPs", &peer.allowed_ips)?;
+            if let Some(endpoint) = peer.endpoint.as_ref() {
+                writeln!(f, "Endpoint = {}", endpoint)?;
+            }
+            if let Some(persistent_keepalive) = peer.persistent_keepalive {
+                writeln!(
+                    f,
+                    "PersistentKeepalive = {}",
+                    persistent_keepalive.as_secs()
+                )?;
+            }
         }
         Ok(())
     }
 }
+
+/// Writes `key = values` with comma separated values, unless there are none
+fn write_list<T: std::fmt::Display>(
+    f: &mut impl std::fmt::Write,
+    key: &str,
+    values: impl IntoIterator<Item = T>,
+) -> std::fmt::Result {
+    let values: Vec<String> = values.into_iter().map(|value| value.to_string()).collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "{} = {}", key, values.join(", "))
+}
+
+impl WireguardConfig {
+    fn write_wg_quick(&self, f: &mut impl std::fmt::Write, secrets: bool) -> std::fmt::Result {
+        let FullWireguardInterface {
+            private_key,
+            interface,
+            scripts,
+        } = &self.interface;
+        writeln!(f, "[Interface]")?;
+        if let Some(private_key) = private_key.as_ref().filter(|_| secrets) {
+            writeln!(f, "PrivateKey = {}", private_key.to_base64().as_str())?;
+        }
+        if let Some(listen_port) = interface.listen_port {
+            writeln!(f, "ListenPort = {}", listen_port)?;
+        }
+        if let Some(fw_mark) = interface.fw_mark {
+            writeln!(f, "FwMark = {:#x}", fw_mark)?;
+        }
+        write_list(f, "Address", &interface.address)?;
+        write_list(
+            f,
+            "DNS",
+            interface
+                .dns
+                .iter()
+                .map(ToString::to_string)
+                .chain(interface.dns_search.iter().cloned()),
+        )?;
+        if let Some(mtu) = interface.mtu {
+            writeln!(f, "MTU = {}", mtu)?;
+        }
+        if let Some(table) = interface.table.as_ref() {
+            writeln!(f, "Table = {}", table)?;
+        }
+        for (key, commands) in [
+            ("PreUp", &scripts.pre_up),
+            ("PostUp", &scripts.post_up),
+            ("PreDown", &scripts.pre_down),
+            ("PostDown", &scripts.post_down),
+        ] {
+            for command in commands {
+                writeln!(f, "{} = {}", key, command)?;
+            }
+        }
+        if interface.save_config && secrets {
+            writeln!(f, "SaveConfig = true")?;
+        }
+
+        self.write_peers(f, secrets)
+    }
+}
+
+impl std::fmt::Display for WireguardConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_wg_quick(f, true)
+    }
+}
@@ -0,0 +1,477 @@
+use crate::{
+    FullWireguardInterface, PrivateKey, PublicKey, WireguardConfig, WireguardInterface,
+    WireguardInterfaceScripts, WireguardPeer,
+};
+use ipnetwork::IpNetwork;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Error in a wg-quick configuration file, with the 1-based line it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ConfigParseError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ConfigParseError {}
+
+type ParseResult<T> = Result<T, ConfigParseError>;
+
+/// Keys that may be repeated, each value being a comma separated list
+const MULTI_VALUE_KEYS: &[&str] = &["address", "dns", "allowedips"];
+
+/// Keys that may be repeated, each value taken whole
+const REPEATED_KEYS: &[&str] = &["preup", "postup", "predown", "postdown"];
+
+/// Keys and values of one `[Interface]` or `[Peer]` section, keys lowercased since
+/// wg-quick matches them case-insensitively
+struct Section {
+    line: usize,
+    values: HashMap<String, Vec<(usize, String)>>,
+}
+
+impl Section {
+    fn new(line: usize) -> Self {
+        Self {
+            line,
+            values: Default::default(),
+        }
+    }
+
+    fn insert(&mut self, line: usize, key: &str, value: &str) -> ParseResult<()> {
+        let key = key.to_ascii_lowercase();
+        let values = self.values.entry(key.clone()).or_default();
+        if MULTI_VALUE_KEYS.contains(&key.as_str()) {
+            values.extend(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (line, value.to_owned())),
+            );
+            return Ok(());
+        }
+        if REPEATED_KEYS.contains(&key.as_str()) {
+            values.push((line, value.to_owned()));
+            return Ok(());
+        }
+        if let Some((first_line, _)) = values.first() {
+            return Err(ConfigParseError::new(
+                line,
+                format!("{} was already set on line {}", key, first_line),
+            ));
+        }
+        values.push((line, value.to_owned()));
+        Ok(())
+    }
+
+    fn take<T>(&mut self, key: &str) -> ParseResult<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self
+            .values
+            .remove(key)
+            .and_then(|values| values.into_iter().next())
+        {
+            None => Ok(None),
+            Some((line, value)) => value.parse().map(Some).map_err(|err| {
+                ConfigParseError::new(line, format!("Invalid {} \"{}\": {}", key, value, err))
+            }),
+        }
+    }
+
+    fn take_required<T>(&mut self, key: &str) -> ParseResult<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let line = self.line;
+        self.take(key)?
+            .ok_or_else(|| ConfigParseError::new(line, format!("Missing {}", key)))
+    }
+
+    fn take_all<T>(&mut self, key: &str) -> ParseResult<Vec<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.values
+            .remove(key)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(line, value)| {
+                value.parse().map_err(|err| {
+                    ConfigParseError::new(line, format!("Invalid {} \"{}\": {}", key, value, err))
+                })
+            })
+            .collect()
+    }
+
+    /// Fails on keys not taken while building the section
+    fn finish(self) -> ParseResult<()> {
+        match self
+            .values
+            .into_iter()
+            .filter_map(|(key, values)| values.first().map(|(line, _)| (*line, key)))
+            .min()
+        {
+            None => Ok(()),
+            Some((line, key)) => Err(ConfigParseError::new(line, format!("Unknown key {}", key))),
+        }
+    }
+}
+
+/// Firewall mark in decimal or hexadecimal, or `off`
+struct FwMark(Option<u32>);
+
+impl FromStr for FwMark {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self(None)),
+            s => match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).map(|mark| Self(Some(mark))),
+                None => s.parse().map(|mark| Self(Some(mark))),
+            },
+        }
+    }
+}
+
+fn parse_interface(mut section: Section) -> ParseResult<FullWireguardInterface> {
+    let private_key: Option<PrivateKey> = section.take("privatekey")?;
+    let address: Vec<IpNetwork> = section.take_all("address")?;
+    // Like wg-quick, DNS values that are not addresses are search domains
+    let (dns, dns_search): (Vec<String>, Vec<String>) = section
+        .take_all::<String>("dns")?
+        .into_iter()
+        .partition(|value| value.parse::<IpAddr>().is_ok());
+    let interface = WireguardInterface {
+        address,
+        dns: dns.iter().filter_map(|value| value.parse().ok()).collect(),
+        dns_search,
+        mtu: section.take("mtu")?,
+        listen_port: section.take("listenport")?,
+        table: section.take("table")?,
+        fw_mark: section.take::<FwMark>("fwmark")?.and_then(|mark| mark.0),
+        save_config: section.take("saveconfig")?.unwrap_or(false),
+    };
+    let scripts = WireguardInterfaceScripts {
+        pre_up: section.take_all("preup")?,
+        post_up: section.take_all("postup")?,
+        pre_down: section.take_all("predown")?,
+        post_down: section.take_all("postdown")?,
+    };
+    section.finish()?;
+    Ok(FullWireguardInterface {
+        private_key,
+        interface,
+        scripts,
+    })
+}
+
+/// Persistent keepalive in seconds, or `off`
+struct Keepalive(Option<Duration>);
+
+impl FromStr for Keepalive {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self(None)),
+            s => Ok(Self(Some(Duration::from_secs(s.parse::<u16>()?.into())))),
+        }
+    }
+}
+
+fn parse_peer(mut section: Section) -> ParseResult<WireguardPeer> {
+    let public_key: PublicKey = section.take_required("publickey")?;
+    let peer = WireguardPeer {
+        public_key: public_key.to_string(),
+        preshared_key: section.take("presharedkey")?,
+        allowed_ips: section.take_all::<IpNetwork>("allowedips")?,
+        endpoint: section.take("endpoint")?,
+        persistent_keepalive: section
+            .take::<Keepalive>("persistentkeepalive")?
+            .and_then(|keepalive| keepalive.0),
+    };
+    section.finish()?;
+    Ok(peer)
+}
+
+impl FromStr for WireguardConfig {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> ParseResult<Self> {
+        let mut interface: Option<Section> = None;
+        let mut peers: Vec<Section> = vec![];
+        // Whether key lines currently belong to the last peer rather than the interface
+        let mut in_peer: Option<bool> = None;
+
+        for (index, raw_line) in s.lines().enumerate() {
+            let line = index + 1;
+            // Like wg-quick, everything after a # is a comment
+            let text = raw_line.split('#').next().unwrap_or_default().trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            if text.starts_with('[') && text.ends_with(']') {
+                let name = &text[1..text.len() - 1];
+                if name.eq_ignore_ascii_case("interface") {
+                    if let Some(interface) = interface.as_ref() {
+                        return Err(ConfigParseError::new(
+                            line,
+                            format!("Interface was already defined on line {}", interface.line),
+                        ));
+                    }
+                    interface = Some(Section::new(line));
+                    in_peer = Some(false);
+                } else if name.eq_ignore_ascii_case("peer") {
+                    peers.push(Section::new(line));
+                    in_peer = Some(true);
+                } else {
+                    return Err(ConfigParseError::new(
+                        line,
+                        format!("Unknown section [{}]", name),
+                    ));
+                }
+                continue;
+            }
+
+            let (key, value) = match text.find('=') {
+                Some(pos) => (text[..pos].trim(), text[pos + 1..].trim()),
+                None => {
+                    return Err(ConfigParseError::new(
+                        line,
+                        format!("Expected \"Key = Value\", got \"{}\"", text),
+                    ))
+                }
+            };
+            let section = match in_peer {
+                Some(true) => peers.last_mut(),
+                Some(false) => interface.as_mut(),
+                None => None,
+            };
+            match section {
+                Some(section) => section.insert(line, key, value)?,
+                None => {
+                    return Err(ConfigParseError::new(
+                        line,
+                        format!("{} is not in a section", key),
+                    ))
+                }
+            }
+        }
+
+        let interface = interface.ok_or_else(|| {
+            ConfigParseError::new(s.lines().count().max(1), "Missing [Interface]")
+        })?;
+        Ok(Self {
+            interface: parse_interface(interface)?,
+            peers: peers
+                .into_iter()
+                .map(parse_peer)
+                .collect::<ParseResult<_>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WgKeyPair;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn random_network(rng: &mut StdRng) -> IpNetwork {
+        if rng.gen() {
+            IpNetwork::new(
+                IpAddr::V4(Ipv4Addr::from(rng.gen::<u32>())),
+                rng.gen_range(0..=32),
+            )
+            .unwrap()
+        } else {
+            IpNetwork::new(
+                IpAddr::V6(Ipv6Addr::from(rng.gen::<u128>())),
+                rng.gen_range(0..=128),
+            )
+            .unwrap()
+        }
+    }
+
+    fn random_list<T>(rng: &mut StdRng, mut make: impl FnMut(&mut StdRng) -> T) -> Vec<T> {
+        (0..rng.gen_range(0..4)).map(|_| make(rng)).collect()
+    }
+
+    fn random_config(rng: &mut StdRng) -> WireguardConfig {
+        let interface = WireguardInterface {
+            address: std::iter::once(random_network(rng))
+                .chain(random_list(rng, random_network))
+                .collect(),
+            dns: random_list(rng, |rng| random_network(rng).ip()),
+            dns_search: random_list(rng, |rng| format!("corp{}.example.com", rng.gen::<u8>())),
+            mtu: rng.gen(),
+            listen_port: rng.gen(),
+            table: rng
+                .gen::<bool>()
+                .then(|| ["off", "auto", "1234"][rng.gen_range(0..3)].to_owned()),
+            fw_mark: rng.gen(),
+            save_config: rng.gen(),
+        };
+        let command = |rng: &mut StdRng| format!("ip rule add table {} || true", rng.gen::<u8>());
+        let scripts = WireguardInterfaceScripts {
+            pre_up: random_list(rng, command),
+            post_up: random_list(rng, command),
+            pre_down: random_list(rng, command),
+            post_down: random_list(rng, command),
+        };
+        let peers = random_list(rng, |rng| WireguardPeer {
+            public_key: WgKeyPair::new().public_key.to_string(),
+            preshared_key: rng
+                .gen::<bool>()
+                .then(|| base64::encode(rng.gen::<[u8; 32]>()).parse().unwrap()),
+            allowed_ips: random_list(rng, random_network),
+            endpoint: rng
+                .gen::<bool>()
+                .then(|| format!("vpn{}.example.com:{}", rng.gen::<u8>(), rng.gen::<u16>())),
+            persistent_keepalive: rng
+                .gen::<bool>()
+                .then(|| Duration::from_secs(rng.gen_range(1..=u16::MAX).into())),
+        });
+        WireguardConfig::new(
+            FullWireguardInterface::new_with_scripts(&WgKeyPair::new(), interface, scripts),
+            peers,
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..500 {
+            let config = random_config(&mut rng);
+            let written = config.to_string();
+            let parsed: WireguardConfig = written
+                .parse()
+                .unwrap_or_else(|err| panic!("Parsing failed at {}:\n{}", err, written));
+            assert_eq!(parsed, config, "Round trip changed:\n{}", written);
+            assert_eq!(parsed.to_string(), written);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_without_secrets() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let mut config = random_config(&mut rng);
+            let written = config.to_string_without_secrets();
+            let parsed: WireguardConfig = written
+                .parse()
+                .unwrap_or_else(|err| panic!("Parsing failed at {}:\n{}", err, written));
+            config.interface.private_key = None;
+            config.interface.interface.save_config = false;
+            for peer in config.peers.iter_mut() {
+                peer.preshared_key = None;
+            }
+            assert_eq!(parsed, config, "Round trip changed:\n{}", written);
+            assert_eq!(parsed.to_string(), written);
+        }
+    }
+
+    #[test]
+    fn test_parse_optional() {
+        let config: WireguardConfig = "[Interface]\nListenPort = 51820\n".parse().unwrap();
+        assert_eq!(config.interface.private_key, None);
+        assert!(config.interface.interface.address.is_empty());
+        assert_eq!(config.to_string(), "[Interface]\nListenPort = 51820\n");
+    }
+
+    #[test]
+    fn test_parse() {
+        let private_key = PrivateKey::generate();
+        let peer_key = WgKeyPair::new().public_key;
+        let config: WireguardConfig = format!(
+            "# Written by hand\n\
+             [interface]\n\
+             privatekey = {}\n\
+             Address = 10.0.0.2/32 # client address\n\
+             Address = fd00::2/128\n\
+             DNS = 10.0.0.1, corp.example.com\n\
+             FwMark = 0xca6c\n\
+             PostUp = echo up\n\
+             PostUp = echo still up\n\
+             \n\
+             [Peer]\n\
+             PublicKey = {}\n\
+             AllowedIPs = 10.0.0.0/24, 192.168.0.0/16\n\
+             AllowedIPs = fd00::/64\n\
+             PersistentKeepalive = off\n",
+            private_key.to_base64().as_str(),
+            peer_key
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.interface.private_key, Some(private_key));
+        let interface = &config.interface.interface;
+        assert_eq!(interface.address.len(), 2);
+        assert_eq!(interface.dns, vec![IpAddr::from([10, 0, 0, 1])]);
+        assert_eq!(interface.dns_search, vec!["corp.example.com".to_owned()]);
+        assert_eq!(interface.fw_mark, Some(0xca6c));
+        assert_eq!(config.interface.scripts.post_up.len(), 2);
+        assert_eq!(config.peers.len(), 1);
+        assert_eq!(config.peers[0].public_key, peer_key.to_string());
+        assert_eq!(config.peers[0].allowed_ips.len(), 3);
+        assert_eq!(config.peers[0].persistent_keepalive, None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let private_key = PrivateKey::generate();
+        let interface = format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.0.0.1/24\n",
+            private_key.to_base64().as_str()
+        );
+        let error_line = |config: &str| config.parse::<WireguardConfig>().unwrap_err().line;
+
+        assert_eq!(error_line(&format!("{}Color = blue\n", interface)), 4);
+        assert_eq!(error_line(&format!("{}MTU = huge\n", interface)), 4);
+        assert_eq!(
+            error_line(&format!(
+                "{}ListenPort = 51820\nListenPort = 51821\n",
+                interface
+            )),
+            5
+        );
+        assert_eq!(
+            error_line(&format!("{}\n[Peer]\nAllowedIPs = 10.0.0.0/8\n", interface)),
+            5
+        );
+        assert_eq!(error_line(&format!("{}[Peers]\n", interface)), 4);
+        assert_eq!(error_line(&format!("{}[Interface]\n", interface)), 4);
+        assert_eq!(error_line("Address = 10.0.0.1/24\n[Interface]\n"), 1);
+        assert_eq!(error_line("[Interface]\nAddress\n"), 2);
+        assert_eq!(error_line("[Interface]\nPrivateKey = short\n"), 2);
+    }
+}
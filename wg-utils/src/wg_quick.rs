@@ -1,91 +1,400 @@
-use crate::WireguardConfig;
-use anyhow::{anyhow, Result};
+use crate::command::{run_command, run_command_with_input};
+use crate::netns::netns_command;
+#[cfg(target_os = "linux")]
+use crate::netns::{configure_link, delete_link, run_scripts};
+use crate::status::parse_dump;
+#[cfg(target_os = "linux")]
+use crate::WireguardInterfaceScripts;
+use crate::{InterfaceStatus, Netns, WireguardBackend, WireguardConfig, WireguardPeer};
+use anyhow::Result;
 use log::*;
-use std::path::PathBuf;
-use tokio::fs::{create_dir_all, File};
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::sync::Mutex;
+use tokio::fs::{create_dir_all, remove_file, rename, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
 #[cfg(target_family = "windows")]
-fn config_dir() -> PathBuf {
+pub(crate) fn default_config_dir() -> PathBuf {
     PathBuf::from(r"C:\Program Files\WireGuard\Tunnels")
 }
 
 #[cfg(target_family = "unix")]
-fn config_dir() -> PathBuf {
+pub(crate) fn default_config_dir() -> PathBuf {
     PathBuf::from("/etc/wireguard")
 }
 
-async fn run_command(command: &mut Command) -> Result<()> {
-    debug!("Running: {:?}", command);
-    let output = command.output().await?;
-    if !output.status.success() {
-        let msg = format!(
-            "Running command failed:\nstdout: {}\nstderr: {}",
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
-        error!("Command failed: {}", msg);
-        return Err(anyhow!(msg));
-    }
-    debug!("Output: {}", String::from_utf8_lossy(&output.stdout));
-    Ok(())
+#[cfg(target_family = "windows")]
+const WG_COMMAND: &str = r"C:\Program Files\WireGuard\wg.exe";
+
+#[cfg(target_family = "unix")]
+const WG_COMMAND: &str = "wg";
+
+/// Path of the configuration file of interface `name`, named like wg-quick expects
+fn config_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.conf", name))
 }
 
-async fn write_config_file(name: &str, config: WireguardConfig) -> Result<String> {
-    let config_dir = config_dir();
-    create_dir_all(&config_dir).await?;
+/// Writes `contents` to `<name>.conf` in `dir`, only readable by its owner.
+/// The file is written next to its final path and renamed into place, so no one
+/// ever reads a partial configuration.
+async fn write_config_file(dir: &Path, name: &str, contents: &str) -> Result<PathBuf> {
+    create_dir_all(dir).await?;
 
-    let config_pathbuf = config_dir.join(format!("{}.conf", name));
-    let config_path = config_pathbuf
-        .to_str()
-        .expect("Could not format tunnel config path")
-        .to_string();
+    let config_path = config_path(dir, name);
+    let temp_path = dir.join(format!(".{}.conf.tmp", name));
+    debug!("Writing {:?}", config_path);
+    if let Err(err) = remove_file(&temp_path).await {
+        if err.kind() != ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
 
-    debug!("Writing {}", config_path);
-    let mut config_file = File::create(&config_pathbuf).await?;
-    let config_data = format!("{}", config);
-    config_file.write_all(config_data.as_bytes()).await?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(target_family = "unix")]
+    options.mode(0o600);
+    let mut config_file = options.open(&temp_path).await?;
+    config_file.write_all(contents.as_bytes()).await?;
+    config_file.sync_all().await?;
+    drop(config_file);
 
+    rename(&temp_path, &config_path).await?;
     Ok(config_path)
 }
 
+/// Overwrites `<name>.conf` in `dir` with zeros before removing it, if it exists
+async fn remove_config_file(dir: &Path, name: &str) -> Result<()> {
+    let config_path = config_path(dir, name);
+    let mut config_file = match OpenOptions::new().write(true).open(&config_path).await {
+        Ok(config_file) => config_file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    debug!("Removing {:?}", config_path);
+    let len = config_file.metadata().await?.len();
+    config_file.write_all(&vec![0; len.try_into()?]).await?;
+    config_file.sync_all().await?;
+    drop(config_file);
+    remove_file(&config_path).await?;
+    Ok(())
+}
+
+/// Brings `name` up with wg-quick, writing its configuration file to `config_dir`.
+/// The file only has the settings wg-quick needs for addresses, routes, DNS and scripts,
+/// the keys are then set through `wg syncconf` so they never touch the disk.
 #[cfg(target_family = "unix")]
-pub async fn wg_quick_up(name: &str, config: WireguardConfig) -> Result<()> {
-    if let Err(err) = wg_quick_down(name).await {
+pub async fn wg_quick_up(config_dir: &Path, name: &str, config: &WireguardConfig) -> Result<()> {
+    if let Err(err) = wg_quick_down(config_dir, name).await {
         debug!(
             "Error while running 'wg-quick down' before 'wg-quick up': {}",
             err
         );
     }
     info!("Bringing {} up", name);
-    let config_path = write_config_file(name, config).await?;
-    run_command(Command::new("wg-quick").args(["up", &config_path])).await
+    if config.interface.interface.save_config {
+        warn!(
+            "Ignoring SaveConfig of {}, it would write its keys to disk",
+            name
+        );
+    }
+    let config_path =
+        write_config_file(config_dir, name, &config.to_string_without_secrets()).await?;
+    run_command(Command::new("wg-quick").arg("up").arg(&config_path)).await?;
+    if let Err(err) = sync_config(None, name, config).await {
+        if let Err(err) = wg_quick_down(config_dir, name).await {
+            warn!("Error while taking {} down: {}", name, err);
+        }
+        return Err(err);
+    }
+    Ok(())
 }
 
 #[cfg(target_family = "unix")]
-pub async fn wg_quick_down(name: &str) -> Result<()> {
+pub async fn wg_quick_down(config_dir: &Path, name: &str) -> Result<()> {
     info!("Taking {} down", name);
-    run_command(Command::new("wg-quick").args(["down", name])).await
+    // A bare name would make wg-quick look in /etc/wireguard instead of `config_dir`
+    run_command(
+        Command::new("wg-quick")
+            .arg("down")
+            .arg(config_path(config_dir, name)),
+    )
+    .await?;
+    // Kept when taking the interface down failed, wg-quick needs it to try again
+    remove_config_file(config_dir, name).await
 }
 
+/// Brings `name` up as a tunnel service. The service reads its keys from the configuration
+/// file, so the file is kept until the tunnel is taken down.
 #[cfg(target_family = "windows")]
-pub async fn wg_quick_up(name: &str, config: WireguardConfig) -> Result<()> {
+pub async fn wg_quick_up(config_dir: &Path, name: &str, config: &WireguardConfig) -> Result<()> {
     info!("Bringing {} up", name);
-    let config_path = write_config_file(name, config).await?;
+    let config_path = write_config_file(config_dir, name, &config.to_string()).await?;
     run_command(
         Command::new(r"C:\Program Files\WireGuard\wireguard.exe")
-            .args(["/installtunnelservice", &config_path]),
+            .arg("/installtunnelservice")
+            .arg(&config_path),
     )
-    .await
+    .await?;
+    Ok(())
 }
 
 #[cfg(target_family = "windows")]
-pub async fn wg_quick_down(name: &str) -> Result<()> {
+pub async fn wg_quick_down(config_dir: &Path, name: &str) -> Result<()> {
     info!("Taking {} down", name);
     run_command(
         Command::new(r"C:\Program Files\WireGuard\wireguard.exe")
             .args(["/uninstalltunnelservice", name]),
     )
-    .await
+    .await?;
+    remove_config_file(config_dir, name).await
+}
+
+/// Sets the keys and peers of `name` in `netns`, passing them through standard input
+#[cfg(target_family = "unix")]
+async fn sync_config(netns: Option<&Netns>, name: &str, config: &WireguardConfig) -> Result<()> {
+    debug!("Syncing configuration of {}", name);
+    run_command_with_input(
+        netns_command(netns, WG_COMMAND).args(["syncconf", name, "/dev/stdin"]),
+        config.to_setconf_string().as_bytes(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Reads the state of interface `name` and its peers with `wg show`
+pub async fn interface_status(name: &str) -> Result<InterfaceStatus> {
+    show_dump(None, name).await
+}
+
+async fn show_dump(netns: Option<&Netns>, name: &str) -> Result<InterfaceStatus> {
+    let dump = run_command(netns_command(netns, WG_COMMAND).args(["show", name, "dump"])).await?;
+    parse_dump(&dump)
+}
+
+/// Backend running `wg-quick` to create interfaces and `wg` to change them
+#[derive(Debug)]
+pub struct WgQuickBackend {
+    config_dir: PathBuf,
+    /// Namespace created interfaces are moved to, their UDP socket stays in the current one
+    netns: Option<Netns>,
+    /// Scripts of interfaces created in `netns`, which have no wg-quick configuration file
+    #[cfg(target_os = "linux")]
+    netns_scripts: Mutex<HashMap<String, WireguardInterfaceScripts>>,
+}
+
+impl WgQuickBackend {
+    pub fn new(config_dir: PathBuf) -> Self {
+        Self {
+            config_dir,
+            netns: None,
+            #[cfg(target_os = "linux")]
+            netns_scripts: Default::default(),
+        }
+    }
+
+    /// Creates interfaces in `netns` instead of the current namespace
+    pub fn with_netns(self, netns: Option<Netns>) -> Self {
+        Self { netns, ..self }
+    }
+
+    /// Creates kernel interface `name` and moves it to `netns` before bringing it up.
+    /// wg-quick can't be used as it sets up interfaces in its own namespace.
+    #[cfg(target_os = "linux")]
+    async fn netns_up(&self, netns: &Netns, name: &str, config: &WireguardConfig) -> Result<()> {
+        if let Err(err) = self.netns_down(netns, name).await {
+            debug!(
+                "Error while taking {} down before bringing it up: {}",
+                name, err
+            );
+        }
+        info!("Bringing {} up in network namespace {}", name, netns);
+        if !config.interface.interface.dns.is_empty() {
+            warn!(
+                "Not setting DNS servers of {}, not supported in a network namespace",
+                name
+            );
+        }
+        let scripts = &config.interface.scripts;
+        run_scripts(Some(netns), name, &scripts.pre_up).await?;
+        run_command(Command::new("ip").args(["link", "add", "dev", name, "type", "wireguard"]))
+            .await?;
+        if let Err(err) = netns.move_interface(name).await {
+            if let Err(err) = delete_link(None, name).await {
+                warn!("Error while deleting {}: {}", name, err);
+            }
+            return Err(err);
+        }
+        self.netns_scripts
+            .lock()
+            .expect("Scripts lock is poisoned")
+            .insert(name.to_owned(), scripts.clone());
+        sync_config(Some(netns), name, config).await?;
+        configure_link(Some(netns), name, config).await?;
+        run_scripts(Some(netns), name, &scripts.post_up).await?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn netns_down(&self, netns: &Netns, name: &str) -> Result<()> {
+        info!("Taking {} down in network namespace {}", name, netns);
+        let scripts = self
+            .netns_scripts
+            .lock()
+            .expect("Scripts lock is poisoned")
+            .remove(name)
+            .unwrap_or_default();
+        run_scripts(Some(netns), name, &scripts.pre_down).await?;
+        delete_link(Some(netns), name).await?;
+        run_scripts(Some(netns), name, &scripts.post_down).await?;
+        Ok(())
+    }
+}
+
+impl Default for WgQuickBackend {
+    fn default() -> Self {
+        Self::new(default_config_dir())
+    }
+}
+
+#[async_trait::async_trait]
+impl WireguardBackend for WgQuickBackend {
+    async fn create_interface(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        match self.netns.as_ref() {
+            #[cfg(target_os = "linux")]
+            Some(netns) => self.netns_up(netns, name, config).await,
+            _ => wg_quick_up(&self.config_dir, name, config).await,
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    async fn set_config(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        sync_config(self.netns.as_ref(), name, config).await
+    }
+
+    #[cfg(target_family = "windows")]
+    async fn set_config(&self, name: &str, config: &WireguardConfig) -> Result<()> {
+        // The tunnel service has no way to sync a configuration in place
+        wg_quick_up(&self.config_dir, name, config).await
+    }
+
+    async fn add_peer(&self, name: &str, peer: &WireguardPeer) -> Result<()> {
+        debug!("Setting peer {} on {}", peer.public_key, name);
+        let allowed_ips: Vec<String> = peer.allowed_ips.iter().map(ToString::to_string).collect();
+        let mut command = netns_command(self.netns.as_ref(), WG_COMMAND);
+        command.args(["set", name, "peer", &peer.public_key]);
+        command.args(["allowed-ips", &allowed_ips.join(",")]);
+        if let Some(endpoint) = peer.endpoint.as_ref() {
+            command.args(["endpoint", endpoint]);
+        }
+        // Unset settings are cleared, so the peer ends up exactly as given
+        command.args([
+            "persistent-keepalive",
+            &peer
+                .persistent_keepalive
+                .map(|keepalive| keepalive.as_secs().to_string())
+                .unwrap_or_else(|| "off".to_owned()),
+        ]);
+        match peer.preshared_key.as_ref() {
+            None => {
+                command.args(["preshared-key", "/dev/null"]);
+                run_command(&mut command).await?
+            }
+            Some(preshared_key) => {
+                command.args(["preshared-key", "/dev/stdin"]);
+                run_command_with_input(&mut command, preshared_key.to_base64().as_bytes()).await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn remove_peer(&self, name: &str, public_key: &str) -> Result<()> {
+        debug!("Removing peer {} from {}", public_key, name);
+        run_command(
+            netns_command(self.netns.as_ref(), WG_COMMAND)
+                .args(["set", name, "peer", public_key, "remove"]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn status(&self, name: &str) -> Result<InterfaceStatus> {
+        show_dump(self.netns.as_ref(), name).await
+    }
+
+    async fn destroy_interface(&self, name: &str) -> Result<()> {
+        match self.netns.as_ref() {
+            #[cfg(target_os = "linux")]
+            Some(netns) => self.netns_down(netns, name).await,
+            _ => wg_quick_down(&self.config_dir, name).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FullWireguardInterface, PrivateKey, WireguardInterface};
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wg-utils-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_config_file() -> Result<()> {
+        let dir = test_dir("config-file");
+        let path = write_config_file(&dir, "wg0", "first").await?;
+        assert_eq!(path, dir.join("wg0.conf"));
+        let path = write_config_file(&dir, "wg0", "second").await?;
+        assert_eq!(tokio::fs::read_to_string(&path).await?, "second");
+        assert!(!dir.join(".wg0.conf.tmp").exists());
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = tokio::fs::metadata(&path).await?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        remove_config_file(&dir, "wg0").await?;
+        assert!(!path.exists());
+        remove_config_file(&dir, "wg0").await?;
+        tokio::fs::remove_dir(&dir).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_without_secrets() -> Result<()> {
+        let private_key = PrivateKey::generate();
+        let config = WireguardConfig::new(
+            FullWireguardInterface {
+                private_key: Some(private_key.clone()),
+                interface: WireguardInterface {
+                    address: vec!["10.0.0.2/32".parse()?],
+                    save_config: true,
+                    ..Default::default()
+                },
+                scripts: Default::default(),
+            },
+            vec![WireguardPeer {
+                public_key: PrivateKey::generate().public_key().to_string(),
+                preshared_key: Some(PrivateKey::generate().to_base64().parse()?),
+                allowed_ips: vec!["0.0.0.0/0".parse()?],
+                endpoint: Some("vpn.example.com:51820".to_owned()),
+                persistent_keepalive: None,
+            }],
+        );
+        let contents = config.to_string_without_secrets();
+        assert!(!contents.contains("PrivateKey"));
+        assert!(!contents.contains("PresharedKey"));
+        assert!(!contents.contains("SaveConfig"));
+        assert!(!contents.contains(private_key.to_base64().as_str()));
+        assert!(contents.contains("Address = 10.0.0.2/32"));
+        assert!(contents.contains("Endpoint = vpn.example.com:51820"));
+        Ok(())
+    }
 }